@@ -1,16 +1,16 @@
 use std::env;
+use std::error::Error;
 use std::fs;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use opl::{OPL, OPLSettings};
-use sdl2::audio::{self, AudioCVT, AudioFormat};
-use sdl2::mixer::{self};
+use opl::catalog::w3d::DIGI_SAMPLE_RATE;
+use opl::digi::DigiSound;
+use opl::imf::ImfFile;
+use opl::{Backend, ImfOptions, LoopMode, OPL, OPLSettings, SfxChannelMode};
 
-const ORIG_SAMPLE_RATE: i32 = 7042;
-
-fn main() -> Result<(), String> {
+fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         eprintln!("Usage: {} <track-filename> [<sound-filename>]", args[0]);
@@ -19,18 +19,27 @@ fn main() -> Result<(), String> {
 
     let track_file_name = &args[1];
     let track_file_data = fs::read(track_file_name).expect("Failed to read track file");
+    let track = ImfFile::from_bytes(&track_file_data)?;
+    if let Some(tags) = &track.tags {
+        println!("{} - {} ({})", tags.title, tags.composer, tags.program);
+    }
 
     let mut adl: Option<opl::chip::AdlSound> = None;
-    let mut digi_raw: Option<Vec<u8>> = None;
+    let mut pc: Option<opl::pcspeaker::PcSound> = None;
+    let mut digi: Option<DigiSound> = None;
     if args.len() >= 3 {
         let file = &args[2];
         let sound_file_data = fs::read(file).expect("Failed to read sound file");
         if file.ends_with(".adl") {
-            adl = Some(opl::chip::AdlSound::from_bytes(&sound_file_data));
+            adl = Some(opl::chip::AdlSound::from_bytes(&sound_file_data)?);
+        } else if file.ends_with(".pcs") {
+            pc = Some(
+                opl::pcspeaker::PcSound::from_bytes(&sound_file_data).map_err(|e| e.to_string())?,
+            );
         } else if file.ends_with(".digi") {
-            digi_raw = Some(sound_file_data)
+            digi = Some(DigiSound::new(DIGI_SAMPLE_RATE, sound_file_data))
         } else {
-            return Err(format!("unknown file type: {}", file));
+            return Err(format!("unknown file type: {}", file).into());
         }
     }
 
@@ -39,7 +48,12 @@ fn main() -> Result<(), String> {
         mixer_rate: 44100,
         imf_clock_rate: 560,
         adl_clock_rate: 140,
-    });
+        adl_channel: 0,
+        sfx_channel_mode: SfxChannelMode::Shared,
+        device_name: None,
+        buffer_size: 0,
+        channels: 0,
+    })?;
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -48,45 +62,13 @@ fn main() -> Result<(), String> {
     })
     .map_err(|e| e.to_string())?;
 
-    mixer::open_audio(44100, mixer::AUDIO_S16LSB, 2, 2048)?;
-    let (mix_freq, mix_format, mix_channels) = mixer::query_spec()?;
-    mixer::reserve_channels(2);
-    let group = mixer::Group(1);
-    group.add_channels_range(2, 8 - 1);
-
-    let mix_config = DigiMixConfig {
-        frequency: mix_freq,
-        format: map_audio_format(mix_format),
-        channels: mix_channels,
-        group,
-    };
-
-    let cvt = AudioCVT::new(
-        audio::AudioFormat::U8,
-        1,
-        ORIG_SAMPLE_RATE,
-        mix_config.format,
-        mix_config.channels as u8,
-        mix_config.frequency,
+    opl.play_imf_with_options(
+        track.data,
+        ImfOptions {
+            loop_mode: LoopMode::Once,
+            fade_out_ms: 0,
+        },
     )?;
-
-    let digi = if let Some(digi_raw_bytes) = digi_raw {
-        let converted_data = cvt.convert(digi_raw_bytes);
-        let chunk =
-            mixer::Chunk::from_raw_buffer(converted_data.into_boxed_slice()).expect("chunk");
-        Some(chunk)
-    } else {
-        None
-    };
-
-    let mixer_channel = mix_config
-        .group
-        .find_available()
-        .expect("get sdl mixer channel");
-
-    let mut digi_started = false;
-
-    opl.play_imf(track_file_data)?;
     println!("Playing track... Press Ctrl+C to stop");
     while running.load(Ordering::SeqCst) {
         if !opl.is_imf_playing()? {
@@ -97,13 +79,13 @@ fn main() -> Result<(), String> {
             let adl_play = adl.as_ref().expect("sound file").clone();
             opl.play_adl(adl_play)?;
         }
-
-        if let Some(digi_chunk) = &digi
-            && !digi_started
-        {
-            mixer_channel.halt();
-            mixer_channel.play(digi_chunk, -1)?;
-            digi_started = true;
+        if pc.is_some() && !opl.is_pc_playing()? {
+            let pc_play = pc.as_ref().expect("sound file").clone();
+            opl.play_pc(pc_play)?;
+        }
+        if digi.is_some() && !opl.is_digi_playing(0)? {
+            let digi_play = digi.as_ref().expect("sound file").clone();
+            opl.play_digi(0, digi_play)?;
         }
 
         std::thread::sleep(Duration::from_millis(50));
@@ -111,17 +93,3 @@ fn main() -> Result<(), String> {
 
     Ok(())
 }
-
-pub struct DigiMixConfig {
-    pub frequency: i32,
-    pub format: AudioFormat,
-    pub channels: i32,
-    pub group: mixer::Group,
-}
-
-fn map_audio_format(format: mixer::AudioFormat) -> AudioFormat {
-    match format {
-        mixer::AUDIO_S16LSB => AudioFormat::S16LSB,
-        _ => todo!("impl mapping"),
-    }
-}
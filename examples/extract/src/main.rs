@@ -1,5 +1,10 @@
 use clap::{CommandFactory, Parser};
-use opl::catalog::w3d;
+use opl::catalog::{self, w3d};
+use opl::chip::AdlSound;
+use opl::digi::DigiSound;
+use opl::pcspeaker::PcSound;
+use opl::render::{RenderSettings, RenderSource, render_wav_file};
+use std::error::Error;
 use std::fs::File;
 use std::path::Path;
 use std::{env, io::Write};
@@ -11,21 +16,33 @@ struct Cli {
     #[arg(short, long)]
     folder: Option<std::path::PathBuf>,
 
-    /// Track number to extract (either track_no or sound_no or digi_no have to be supplied)
+    /// Track number to extract (either track_no, sound_no, pc_sound_no or digi_no have to be supplied)
     #[arg(long)]
     track_no: Option<usize>,
 
-    /// Sound number to extract (either track_no or sound_no or digi_no have to be supplied)
+    /// Sound number to extract (either track_no, sound_no, pc_sound_no or digi_no have to be supplied)
     #[arg(long)]
     sound_no: Option<usize>,
 
-    /// Digital sound number to extract (either track_no or sound_no or digi_no have to be supplied)
+    /// PC speaker sound number to extract (either track_no, sound_no, pc_sound_no or digi_no have to be supplied)
+    #[arg(long)]
+    pc_sound_no: Option<usize>,
+
+    /// Digital sound number to extract (either track_no, sound_no, pc_sound_no or digi_no have to be supplied)
     #[arg(long)]
     digi_no: Option<usize>,
+
+    /// Render the track or sound into a WAV file instead of extracting the raw data
+    #[arg(long)]
+    wav: bool,
+
+    /// Render all tracks of the game into WAV files
+    #[arg(long)]
+    soundtrack: bool,
 }
 
 // TODO generalize to other formats
-pub fn main() -> Result<(), String> {
+pub fn main() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
 
     let folder_path = if let Some(path) = args.folder {
@@ -34,12 +51,16 @@ pub fn main() -> Result<(), String> {
         env::current_dir().map_err(|e| e.to_string())?
     };
 
-    if let Some(track_no) = args.track_no {
-        extract_track(&folder_path, track_no)?;
+    if args.soundtrack {
+        export_soundtrack(&folder_path)?;
+    } else if let Some(track_no) = args.track_no {
+        extract_track(&folder_path, track_no, args.wav)?;
     } else if let Some(sound_no) = args.sound_no {
-        extract_sound(&folder_path, sound_no)?;
+        extract_sound(&folder_path, sound_no, args.wav)?;
+    } else if let Some(pc_sound_no) = args.pc_sound_no {
+        extract_pc_sound(&folder_path, pc_sound_no, args.wav)?;
     } else if let Some(digi_no) = args.digi_no {
-        extract_digi(&folder_path, digi_no)?;
+        extract_digi(&folder_path, digi_no, args.wav)?;
     } else {
         let mut cmd = Cli::command();
         cmd.print_help().map_err(|e| e.to_string())?;
@@ -49,19 +70,29 @@ pub fn main() -> Result<(), String> {
     Ok(())
 }
 
-fn extract_sound(folder_path: &Path, sound_no: usize) -> Result<(), String> {
+fn extract_sound(folder_path: &Path, sound_no: usize, wav: bool) -> Result<(), Box<dyn Error>> {
     let sound_data = w3d::load_sound(&folder_path, sound_no)?;
 
-    let file_name = &format!("sound_{}.adl", sound_no);
-    let mut file = File::create(Path::new(file_name)).map_err(|e| e.to_string())?;
-    file.write_all(&sound_data).map_err(|e| e.to_string())?;
-    println!("file {} written", file_name);
-    Ok(())
+    if wav {
+        let source = RenderSource::Adl(AdlSound::from_bytes(&sound_data)?);
+        return write_wav(&format!("sound_{}.wav", sound_no), source, 0);
+    }
+    write_file(&format!("sound_{}.adl", sound_no), &sound_data)
+}
+
+fn extract_pc_sound(folder_path: &Path, sound_no: usize, wav: bool) -> Result<(), Box<dyn Error>> {
+    let sound_data = w3d::load_pc_sound(&folder_path, sound_no)?;
+
+    if wav {
+        let source = RenderSource::Pc(PcSound::from_bytes(&sound_data)?);
+        return write_wav(&format!("sound_{}_pc.wav", sound_no), source, 0);
+    }
+    write_file(&format!("sound_{}.pcs", sound_no), &sound_data)
 }
 
-fn extract_track(folder_path: &Path, track_no: usize) -> Result<(), String> {
+fn extract_track(folder_path: &Path, track_no: usize, wav: bool) -> Result<(), Box<dyn Error>> {
     if track_no >= w3d::GAME_MODULE.metadata.tracks.len() {
-        return Err(format!("track number {} is out of range", track_no));
+        return Err(format!("track number {} is out of range", track_no).into());
     }
 
     let track_meta = &w3d::GAME_MODULE.metadata.tracks[track_no];
@@ -72,19 +103,63 @@ fn extract_track(folder_path: &Path, track_no: usize) -> Result<(), String> {
 
     let track_data = w3d::load_track(&folder_path, track_no)?;
 
-    let file_name = &format!("track_{}.imf", track_no);
-    let mut file = File::create(Path::new(file_name)).map_err(|e| e.to_string())?;
-    file.write_all(&track_data).map_err(|e| e.to_string())?;
-    println!("file {} written", file_name);
-    Ok(())
+    if wav {
+        return write_wav(
+            &format!("track_{}.wav", track_no),
+            RenderSource::Imf(track_data),
+            w3d::GAME_MODULE.metadata.imf_clock_rate,
+        );
+    }
+    write_file(&format!("track_{}.imf", track_no), &track_data)
 }
 
-fn extract_digi(folder_path: &Path, digi_no: usize) -> Result<(), String> {
+fn extract_digi(folder_path: &Path, digi_no: usize, wav: bool) -> Result<(), Box<dyn Error>> {
     let digi_data = w3d::load_digi(&folder_path, digi_no)?;
 
-    let file_name = &format!("sound_{}.digi", digi_no);
+    if wav {
+        let source = RenderSource::Digi(DigiSound::new(w3d::DIGI_SAMPLE_RATE, digi_data));
+        return write_wav(&format!("sound_{}_digi.wav", digi_no), source, 0);
+    }
+    write_file(&format!("sound_{}.digi", digi_no), &digi_data)
+}
+
+fn export_soundtrack(folder_path: &Path) -> Result<(), Box<dyn Error>> {
+    let files = catalog::export_soundtrack(
+        &w3d::GAME_MODULE,
+        folder_path,
+        Path::new("."),
+        &render_settings(0),
+    )?;
+    for file in files {
+        println!("file {} written", file.display());
+    }
+    Ok(())
+}
+
+fn write_file(file_name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
     let mut file = File::create(Path::new(file_name)).map_err(|e| e.to_string())?;
-    file.write_all(&digi_data).map_err(|e| e.to_string())?;
+    file.write_all(data).map_err(|e| e.to_string())?;
+    println!("file {} written", file_name);
+    Ok(())
+}
+
+fn write_wav(
+    file_name: &str,
+    source: RenderSource,
+    imf_clock_rate: u32,
+) -> Result<(), Box<dyn Error>> {
+    render_wav_file(
+        source,
+        &render_settings(imf_clock_rate),
+        Path::new(file_name),
+    )?;
     println!("file {} written", file_name);
     Ok(())
 }
+
+fn render_settings(imf_clock_rate: u32) -> RenderSettings {
+    RenderSettings {
+        imf_clock_rate,
+        ..Default::default()
+    }
+}
@@ -1,40 +1,30 @@
-use std::cell::Cell;
-use std::rc::Rc;
 use wasm_bindgen::prelude::*;
-use web_sys::{AudioContext, AudioContextOptions};
 
-use opl::{OPL, OPLSettings, chip::AdlSound};
+use opl::{Backend, OPL, OPLSettings, SfxChannelMode, chip::AdlSound, digi::DigiSound};
 
-const SOURCE_SAMPLE_RATE: f32 = 7042.0;
-const TARGET_SAMPLE_RATE: f32 = 44100.0;
-const PLAYBACK_RATE: f32 = SOURCE_SAMPLE_RATE / TARGET_SAMPLE_RATE;
+const DIGI_SAMPLE_RATE: u32 = 7042;
 
 #[wasm_bindgen]
 pub struct WebPlayer {
     opl: OPL,
-    digi_context: AudioContext,
-
-    adl_playing: Rc<Cell<bool>>,
 }
 
 #[wasm_bindgen]
 pub async fn init_player() -> Result<WebPlayer, String> {
     console_error_panic_hook::set_once();
 
-    let mut opl = OPL::new().await?;
+    let mut opl = OPL::new().await.map_err(|e| e.to_string())?;
     opl.init(OPLSettings {
         imf_clock_rate: 560,
         adl_clock_rate: 140,
+        adl_channel: 0,
+        sfx_channel_mode: SfxChannelMode::Shared,
+        ..Default::default()
     })
-    .await?;
-
-    let digi_context = init_digi_sound_context()?;
+    .await
+    .map_err(|e| e.to_string())?;
 
-    Ok(WebPlayer {
-        opl,
-        digi_context,
-        adl_playing: Rc::new(Cell::new(false)),
-    })
+    Ok(WebPlayer { opl })
 }
 
 #[wasm_bindgen]
@@ -45,67 +35,17 @@ impl WebPlayer {
     }
 
     pub async fn play_adl(&mut self, sound_data: Vec<u8>) {
-        self.adl_playing.set(true);
-        let adl = AdlSound::from_bytes(&sound_data);
-        let adl_playing_clone = self.adl_playing.clone();
-        self.opl
-            .play_adl(adl, move || {
-                adl_playing_clone.set(false);
-            })
-            .expect("play adl");
+        let adl = AdlSound::from_bytes(&sound_data).expect("adl sound");
+        self.opl.play_adl(adl).expect("play adl");
     }
 
     pub async fn wait_for_adl_end(&self) {
-        while self.adl_playing.get() {
-            sleep(3).await;
-        }
+        self.opl.adl_finished().expect("adl finished").await
     }
 
     pub async fn play_digi(&mut self, digi_data: Vec<u8>) {
-        let converted: Vec<f32> = digi_data
-            .iter()
-            .map(|&s| (s as f32 - 128.0) / 128.0)
-            .collect();
-
-        let frames = converted.len() as u32;
-        let buffer = self
-            .digi_context
-            .create_buffer(1, frames, TARGET_SAMPLE_RATE)
-            .expect("buffer creation");
-        buffer
-            .copy_to_channel_with_start_in_channel(&converted, 0, 0)
-            .expect("copied data to channel");
-
-        let src = self
-            .digi_context
-            .create_buffer_source()
-            .expect("buffer source creation");
-        src.set_buffer(Some(&buffer));
-        src.playback_rate().set_value(PLAYBACK_RATE);
-        src.set_loop(true);
-
-        src.connect_with_audio_node(&self.digi_context.destination())
-            .expect("audio connect");
-
-        src.start().expect("sound start")
+        self.opl
+            .play_digi(0, DigiSound::new(DIGI_SAMPLE_RATE, digi_data))
+            .expect("play digi")
     }
 }
-
-fn init_digi_sound_context() -> Result<AudioContext, String> {
-    let opts = AudioContextOptions::new();
-    opts.set_sample_rate(TARGET_SAMPLE_RATE);
-
-    let ctx =
-        AudioContext::new_with_context_options(&opts).map_err(|_| "digi audio context init")?;
-    Ok(ctx)
-}
-
-async fn sleep(millis: u32) {
-    let mut cb = |resolve: js_sys::Function, _reject: js_sys::Function| {
-        let win = web_sys::window().expect("web_sys window");
-        win.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, millis as i32)
-            .expect("timeout set");
-    };
-    let p = js_sys::Promise::new(&mut cb);
-    wasm_bindgen_futures::JsFuture::from(p).await.unwrap();
-}
@@ -0,0 +1,86 @@
+#[cfg(all(test, feature = "headless"))]
+#[path = "./backend_test.rs"]
+mod backend_test;
+
+use core::time::Duration;
+
+use crate::Error;
+use crate::chip::AdlSound;
+use crate::digi::DigiSound;
+use crate::pcspeaker::PcSound;
+use crate::sequencer::ImfOptions;
+
+/// The playback control shared by all backends (`sdl::OPL`, `web::OPL`,
+/// `headless::OPL` and `rodio::OPLHandle`), for code that runs on any of them.
+/// The backends implement it instead of having the methods inherently. Setting
+/// up a backend, the end callbacks and futures and the timing of register writes
+/// are backend specific. Volumes are 1.0 for the original level (up to 4.0).
+pub trait Backend {
+    fn play_imf(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        self.play_imf_with_options(data, ImfOptions::default())
+    }
+
+    fn play_imf_with_options(&mut self, data: Vec<u8>, options: ImfOptions) -> Result<(), Error>;
+
+    fn stop_imf(&mut self) -> Result<(), Error>;
+
+    /// Pauses the music, the sound effects keep playing.
+    fn pause_imf(&mut self) -> Result<(), Error>;
+
+    fn resume_imf(&mut self) -> Result<(), Error>;
+
+    /// Whether a track is loaded (it stays loaded when stopped).
+    fn is_imf_playing(&self) -> Result<bool, Error>;
+
+    fn is_imf_paused(&self) -> Result<bool, Error>;
+
+    /// Length of one loop of the playing track, `None` if no track is loaded.
+    fn imf_duration(&self) -> Result<Option<Duration>, Error>;
+
+    /// Position in the current loop of the playing track, `None` if no track is loaded.
+    fn imf_position(&self) -> Result<Option<Duration>, Error>;
+
+    fn seek_imf(&mut self, position: Duration) -> Result<(), Error>;
+
+    /// Scales the speed of the music, 1.0 is the original tempo (0.1 to 8.0).
+    fn set_imf_tempo(&mut self, tempo: f32) -> Result<(), Error>;
+
+    /// Transposes the music by the given number of semitones.
+    fn set_imf_transpose(&mut self, semitones: i8) -> Result<(), Error>;
+
+    fn set_music_volume(&mut self, volume: f32) -> Result<(), Error>;
+
+    /// Volume of the ADL, PC speaker and digitized sounds.
+    fn set_sfx_volume(&mut self, volume: f32) -> Result<(), Error>;
+
+    fn set_master_volume(&mut self, volume: f32) -> Result<(), Error>;
+
+    /// Plays the sound effect unless an effect with a higher priority is
+    /// playing. Returns whether the sound was started.
+    fn play_adl(&mut self, sound: AdlSound) -> Result<bool, Error>;
+
+    fn stop_adl(&mut self) -> Result<(), Error>;
+
+    fn is_adl_playing(&self) -> Result<bool, Error>;
+
+    /// Plays the PC speaker sound with the same priority rules as `play_adl`.
+    /// Returns whether the sound was started.
+    fn play_pc(&mut self, sound: PcSound) -> Result<bool, Error>;
+
+    fn stop_pc(&mut self) -> Result<(), Error>;
+
+    fn is_pc_playing(&self) -> Result<bool, Error>;
+
+    /// Plays the digitized sound on the voice (0..`digi::DIGI_VOICES`), replacing
+    /// the sound playing on that voice.
+    fn play_digi(&mut self, voice: usize, sound: DigiSound) -> Result<(), Error>;
+
+    fn stop_digi(&mut self, voice: usize) -> Result<(), Error>;
+
+    fn is_digi_playing(&self, voice: usize) -> Result<bool, Error>;
+
+    /// Volume of the voice, kept when a new sound is played on it.
+    fn set_digi_volume(&mut self, voice: usize, volume: f32) -> Result<(), Error>;
+
+    fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), Error>;
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use crate::Backend;
+use crate::Error;
+use crate::chip::AdlSound;
+use crate::digi::{DIGI_VOICES, DigiSound};
+use crate::headless::{NullSink, OPL, OPLSettings};
+
+// controls the backend through the trait only
+fn start_sounds<B: Backend>(backend: &mut B) -> Result<(), Error> {
+    backend.set_master_volume(0.5)?;
+    backend.play_imf(vec![0xa0, 0x44, 0, 0, 0xb0, 0x32, 0x18, 0x01])?;
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    assert!(backend.play_adl(sound)?);
+    backend.play_digi(1, DigiSound::new(7000, vec![0xff; 7000]))?;
+    assert!(matches!(
+        backend.play_digi(DIGI_VOICES, DigiSound::new(7000, vec![0xff])),
+        Err(Error::OutOfRange { .. })
+    ));
+    Ok(())
+}
+
+fn stop_sounds<B: Backend>(backend: &mut B) -> Result<(), Error> {
+    backend.stop_adl()?;
+    backend.stop_digi(1)?;
+    backend.pause_imf()?;
+    Ok(())
+}
+
+// without a loaded track there is nothing to pause
+fn pause_without_track<B: Backend>(backend: &mut B) -> Result<(), Error> {
+    backend.pause_imf()?;
+    assert!(!backend.is_imf_paused()?);
+    Ok(())
+}
+
+#[test]
+fn test_headless_backend() {
+    let mut opl = OPL::new(NullSink::new());
+    opl.init(OPLSettings::default()).expect("init");
+    pause_without_track(&mut opl).expect("pause");
+    start_sounds(&mut opl).expect("start sounds");
+    opl.render(Duration::from_millis(10)).expect("render");
+    assert!(opl.is_imf_playing().expect("imf playing"));
+    assert!(opl.is_adl_playing().expect("adl playing"));
+    assert!(opl.is_digi_playing(1).expect("digi playing"));
+    assert!(opl.imf_duration().expect("duration").is_some());
+
+    stop_sounds(&mut opl).expect("stop sounds");
+    assert!(!opl.is_adl_playing().expect("adl playing"));
+    assert!(!opl.is_digi_playing(1).expect("digi playing"));
+    assert!(opl.is_imf_paused().expect("imf paused"));
+}
+
+#[cfg(feature = "rodio")]
+#[test]
+fn test_rodio_backend() {
+    let (mut source, mut handle) = crate::rodio::new(Default::default()).expect("rodio source");
+    pause_without_track(&mut handle).expect("pause");
+    start_sounds(&mut handle).expect("start sounds");
+    assert!(source.by_ref().take(1024).any(|s| s != 0.0));
+    assert!(handle.is_adl_playing().expect("adl playing"));
+
+    stop_sounds(&mut handle).expect("stop sounds");
+    assert!(!handle.is_adl_playing().expect("adl playing"));
+    assert!(handle.is_imf_paused().expect("imf paused"));
+}
@@ -1,6 +1,6 @@
 use clap::Parser;
-use opl::OPL;
 use opl::catalog::{CATALOGED_GAMES, GameModule, Track};
+use opl::{Backend as _, OPL};
 use ratatui::{
     crossterm::{
         ExecutableCommand,
@@ -12,14 +12,18 @@ use ratatui::{
     text::Span,
     widgets::{Block, List, ListState, Paragraph},
 };
+use std::error::Error;
 use std::io::stdout;
 use std::path::Path;
+use std::time::Duration;
 
 const FOCUS_SELECTED_STYLE: Style = Style::new()
     .bg(SLATE.c100)
     .fg(SLATE.c950)
     .add_modifier(Modifier::BOLD);
 const UNFOCUS_SELECTED_STYLE: Style = Style::new().bg(SLATE.c500).add_modifier(Modifier::BOLD);
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+const SEEK_STEP: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 struct Cli {
@@ -31,6 +35,8 @@ struct Cli {
 struct PlaybackState {
     game: &'static GameModule,
     track: &'static Track,
+    // (position, duration)
+    progress: Option<(Duration, Duration)>,
 }
 
 struct State {
@@ -45,7 +51,7 @@ struct App {
     state: State,
 }
 
-pub fn main() -> Result<(), String> {
+pub fn main() -> Result<(), Box<dyn Error>> {
     enable_raw_mode().map_err(|e| e.to_string())?;
     stdout()
         .execute(EnterAlternateScreen)
@@ -57,7 +63,12 @@ pub fn main() -> Result<(), String> {
         mixer_rate: 49716,
         imf_clock_rate: 0,
         adl_clock_rate: 0,
-    });
+        adl_channel: 0,
+        sfx_channel_mode: opl::SfxChannelMode::Shared,
+        device_name: None,
+        buffer_size: 0,
+        channels: 0,
+    })?;
     App::new(opl).run(terminal)?;
 
     disable_raw_mode().map_err(|e| e.to_string())?;
@@ -86,10 +97,14 @@ impl App {
         }
     }
 
-    fn run(&mut self, mut terminal: Terminal<impl Backend>) -> Result<(), String> {
+    fn run(&mut self, mut terminal: Terminal<impl Backend>) -> Result<(), Box<dyn Error>> {
         loop {
+            self.update_progress()?;
             self.draw(&mut terminal)?;
 
+            if !event::poll(REFRESH_INTERVAL).map_err(|e| e.to_string())? {
+                continue;
+            }
             if let Event::Key(key) = event::read().map_err(|e| e.to_string())? {
                 if key.kind == KeyEventKind::Press {
                     match key.code {
@@ -114,6 +129,18 @@ impl App {
                                 }
                             }
                         }
+                        KeyCode::Left => {
+                            if let Some(position) = self.state.opl.imf_position()? {
+                                self.state
+                                    .opl
+                                    .seek_imf(position.saturating_sub(SEEK_STEP))?;
+                            }
+                        }
+                        KeyCode::Right => {
+                            if let Some(position) = self.state.opl.imf_position()? {
+                                self.state.opl.seek_imf(position + SEEK_STEP)?;
+                            }
+                        }
                         KeyCode::Tab => {
                             if self.state.focus_state == Focused::GameList {
                                 self.state.focus_state = Focused::TrackList;
@@ -128,6 +155,7 @@ impl App {
                                     self.state.playback_state = Some(PlaybackState {
                                         track: &CATALOGED_GAMES[game_selected].metadata.tracks[0],
                                         game: CATALOGED_GAMES[game_selected],
+                                        progress: None,
                                     });
                                 }
                             } else {
@@ -138,6 +166,7 @@ impl App {
                                             track: &CATALOGED_GAMES[game_selected].metadata.tracks
                                                 [track_selected],
                                             game: CATALOGED_GAMES[game_selected],
+                                            progress: None,
                                         });
                                     }
                                 }
@@ -162,7 +191,16 @@ impl App {
         }
     }
 
-    fn draw(&mut self, terminal: &mut Terminal<impl Backend>) -> Result<(), String> {
+    fn update_progress(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(play_state) = &mut self.state.playback_state {
+            let position = self.state.opl.imf_position()?;
+            let duration = self.state.opl.imf_duration()?;
+            play_state.progress = position.zip(duration);
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self, terminal: &mut Terminal<impl Backend>) -> Result<(), Box<dyn Error>> {
         terminal
             .draw(|frame| frame.render_widget(self, frame.area()))
             .map_err(|e| e.to_string())?;
@@ -228,12 +266,24 @@ fn playback_text(state: &Option<PlaybackState>) -> Text<'_> {
                 .fg(Color::Yellow)
                 .add_modifier(Modifier::BOLD),
         )]));
+        if let Some((position, duration)) = state.progress {
+            playback_text.lines.push(Line::from(format!(
+                "{} / {}",
+                format_duration(position),
+                format_duration(duration)
+            )));
+        }
         playback_text
     } else {
         Text::from("Nothing selected")
     }
 }
 
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
 fn highlight_style(want_focus: Focused, has_focus: Focused) -> Style {
     if want_focus == has_focus {
         FOCUS_SELECTED_STYLE
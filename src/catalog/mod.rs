@@ -1,4 +1,10 @@
 use std::path::Path;
+#[cfg(feature = "headless")]
+use std::path::PathBuf;
+
+use crate::Error;
+#[cfg(feature = "headless")]
+use crate::render::{RenderSettings, RenderSource, render_wav_file};
 
 mod util;
 pub mod w3d;
@@ -12,17 +18,28 @@ pub struct Track {
 pub struct Metadata {
     pub name: &'static str,
     pub year: usize,
+    /// Music clock rate of the tracks in Hz.
+    pub imf_clock_rate: u32,
+    /// Sample rate of the digitized sounds in Hz.
+    pub digi_sample_rate: u32,
     pub tracks: &'static [Track],
 }
 
 type Inferrer = fn() -> bool;
-type TrackLoader = fn(game_path: &Path, track_no: usize) -> Result<Vec<u8>, String>;
+type TrackLoader = fn(game_path: &Path, track_no: usize) -> Result<Vec<u8>, Error>;
+type SoundLoader = fn(game_path: &Path, sound_no: usize) -> Result<Vec<u8>, Error>;
 
 pub struct GameModule {
     pub game: Game,
     pub metadata: &'static Metadata,
     pub inferrer: Inferrer,
     pub track_loader: TrackLoader,
+    /// Loads the AdLib sound effect chunk.
+    pub sound_loader: SoundLoader,
+    /// Loads the PC speaker sound effect chunk.
+    pub pc_sound_loader: SoundLoader,
+    /// Loads the digitized sound (8 bit unsigned mono PCM at `Metadata::digi_sample_rate`).
+    pub digi_loader: SoundLoader,
 }
 
 pub enum Game {
@@ -32,8 +49,39 @@ pub enum Game {
 
 pub static CATALOGED_GAMES: [&'static GameModule; 1] = [&w3d::GAME_MODULE];
 
+/// Renders all tracks of the game into WAV files in `out_dir` (named
+/// `<no>_<name>.wav`), played with the music clock rate of the game.
+/// Returns the written files.
+#[cfg(feature = "headless")]
+pub fn export_soundtrack(
+    module: &GameModule,
+    game_path: &Path,
+    out_dir: &Path,
+    settings: &RenderSettings,
+) -> Result<Vec<PathBuf>, Error> {
+    let settings = RenderSettings {
+        imf_clock_rate: module.metadata.imf_clock_rate,
+        ..settings.clone()
+    };
+    let mut files = Vec::with_capacity(module.metadata.tracks.len());
+    for track in module.metadata.tracks {
+        let data = (module.track_loader)(game_path, track.no)?;
+        let path = out_dir.join(format!("{:02}_{}.wav", track.no, file_name(track.name)));
+        render_wav_file(RenderSource::Imf(data), &settings, &path)?;
+        files.push(path);
+    }
+    Ok(files)
+}
+
+#[cfg(feature = "headless")]
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
 // inferGame() -> Game ?
 // each catalog mod contains functions:
 // - is_game() -> bool
-// - load_track() -> Result<Vec<u8>, String>
+// - load_track() -> Result<Vec<u8>, Error>
 // - get_metadata() -> &'static Metadata
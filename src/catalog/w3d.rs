@@ -3,17 +3,24 @@ use std::{fs::File, os::unix::fs::FileExt, path::Path};
 
 use super::util::DataReader;
 use super::{GameModule, Metadata, Track};
+use crate::Error;
+use crate::imf::{ImfFile, ImfType};
 
 pub static GAME_MODULE: GameModule = GameModule {
     game: super::Game::W3D,
     metadata: &METADATA,
     inferrer: is_w3d,
     track_loader: load_track,
+    sound_loader: load_sound,
+    pc_sound_loader: load_pc_sound,
+    digi_loader: load_digi,
 };
 
 static METADATA: Metadata = Metadata {
     name: "Wolfenstein 3D",
     year: 1992,
+    imf_clock_rate: 700,
+    digi_sample_rate: DIGI_SAMPLE_RATE,
     tracks: &[
         Track {
             no: 0,
@@ -157,15 +164,19 @@ pub static AUDIO_HEADER_FILE: &str = "AUDIOHED.WL6";
 pub static AUDIO_FILE: &str = "AUDIOT.WL6";
 pub static GAMEDATA_FILE: &str = "VSWAP.WL6";
 
+pub const START_PC_SOUND: usize = 0;
 pub const START_ADLIB_SOUND: usize = 87;
+pub const START_DIGI_SOUND: usize = 174;
 pub const START_MUSIC: usize = 261;
+/// Sample rate of the digitized sounds in VSWAP (8 bit unsigned mono PCM).
+pub const DIGI_SAMPLE_RATE: u32 = 7042;
 
 // Game Module interface
 fn is_w3d() -> bool {
     todo!("check w3d folder structure");
 }
 
-pub fn load_track(game_path: &Path, track_no: usize) -> Result<Vec<u8>, String> {
+pub fn load_track(game_path: &Path, track_no: usize) -> Result<Vec<u8>, Error> {
     let headers = read_w3d_audio_header(&game_path.join(AUDIO_HEADER_FILE))?;
     let track_chunk = load_audio_chunk(
         &headers,
@@ -173,13 +184,17 @@ pub fn load_track(game_path: &Path, track_no: usize) -> Result<Vec<u8>, String>
         START_MUSIC + track_no,
     )?;
 
-    let track_size = u16::from_le_bytes(track_chunk[0..2].try_into().unwrap()) as usize;
-    let mut result = vec![0; track_size];
-    result.copy_from_slice(&track_chunk[2..(track_size + 2)]);
-    Ok(result)
+    let imf = ImfFile::from_bytes_with_type(&track_chunk, ImfType::Type1)?;
+    Ok(imf.data)
 }
 
-pub fn load_sound(game_path: &Path, sound_no: usize) -> Result<Vec<u8>, String> {
+pub fn load_sound(game_path: &Path, sound_no: usize) -> Result<Vec<u8>, Error> {
+    if sound_no >= START_DIGI_SOUND - START_ADLIB_SOUND {
+        return Err(Error::OutOfRange {
+            kind: "sound",
+            index: sound_no,
+        });
+    }
     let headers = read_w3d_audio_header(&game_path.join(AUDIO_HEADER_FILE))?;
     load_audio_chunk(
         &headers,
@@ -187,17 +202,31 @@ pub fn load_sound(game_path: &Path, sound_no: usize) -> Result<Vec<u8>, String>
         START_ADLIB_SOUND + sound_no,
     )
 }
+
+/// Loads a PC speaker sound, they are stored in the chunks before the AdLib sounds.
+pub fn load_pc_sound(game_path: &Path, sound_no: usize) -> Result<Vec<u8>, Error> {
+    if sound_no >= START_ADLIB_SOUND - START_PC_SOUND {
+        return Err(Error::OutOfRange {
+            kind: "pc sound",
+            index: sound_no,
+        });
+    }
+    let headers = read_w3d_audio_header(&game_path.join(AUDIO_HEADER_FILE))?;
+    load_audio_chunk(
+        &headers,
+        &game_path.join(AUDIO_FILE),
+        START_PC_SOUND + sound_no,
+    )
+}
 // End Game Module interface
 
 // Extra interface
 
-pub fn load_digi(game_path: &Path, digi_no: usize) -> Result<Vec<u8>, String> {
+pub fn load_digi(game_path: &Path, digi_no: usize) -> Result<Vec<u8>, Error> {
     let gamedata_path = game_path.join(GAMEDATA_FILE);
-    let mut gamedata_file = File::open(&gamedata_path).map_err(|e| e.to_string())?;
+    let mut gamedata_file = File::open(&gamedata_path)?;
     let mut gamedata_bytes = Vec::new();
-    gamedata_file
-        .read_to_end(&mut gamedata_bytes)
-        .map_err(|e| e.to_string())?;
+    gamedata_file.read_to_end(&mut gamedata_bytes)?;
 
     let headers = read_w3d_gamedata_header(&gamedata_bytes)?;
     let mut gamedata_cursor = Cursor::new(gamedata_bytes);
@@ -207,6 +236,12 @@ pub fn load_digi(game_path: &Path, digi_no: usize) -> Result<Vec<u8>, String> {
         (headers.num_chunks - 1) as usize,
     )?;
 
+    if sound_info_page.len() < digi_no * 4 + 4 {
+        return Err(Error::OutOfRange {
+            kind: "digi sound",
+            index: digi_no,
+        });
+    }
     let start_page = u16::from_le_bytes(
         sound_info_page[(digi_no * 4)..(digi_no * 4 + 2)]
             .try_into()
@@ -240,14 +275,17 @@ fn load_digi_page<M: Read + Seek>(
     headers: &GamedataHeaders,
     start_page: usize,
     length: usize,
-) -> Result<Vec<u8>, String> {
-    let header = &headers.headers[headers.sound_start as usize + start_page];
-    data.seek(SeekFrom::Start(header.offset as u64))
-        .map_err(|e| e.to_string())?;
+) -> Result<Vec<u8>, Error> {
+    let page = headers.sound_start as usize + start_page;
+    let header = headers.headers.get(page).ok_or(Error::OutOfRange {
+        kind: "page",
+        index: page,
+    })?;
+    data.seek(SeekFrom::Start(header.offset as u64))?;
     let mut buffer: Vec<u8> = vec![0; length as usize];
-    let n = data.read(&mut buffer).map_err(|e| e.to_string())?;
+    let n = data.read(&mut buffer)?;
     if n != length as usize {
-        return Err("not enough bytes in page".to_string());
+        return Err(Error::Format("not enough bytes in page".into()));
     }
     Ok(buffer)
 }
@@ -257,14 +295,21 @@ pub fn load_audio_chunk(
     headers: &Vec<u32>,
     audiot_file: &Path,
     chunk_no: usize,
-) -> Result<Vec<u8>, String> {
-    let file = File::open(audiot_file).map_err(|e| e.to_string())?;
+) -> Result<Vec<u8>, Error> {
+    let file = File::open(audiot_file)?;
+    if chunk_no + 1 >= headers.len() {
+        return Err(Error::OutOfRange {
+            kind: "audio chunk",
+            index: chunk_no,
+        });
+    }
     let offset = headers[chunk_no];
-    let size = (headers[chunk_no + 1] - offset) as usize;
+    let size = headers[chunk_no + 1]
+        .checked_sub(offset)
+        .ok_or(Error::Format("audio header offsets not ascending".into()))? as usize;
 
     let mut data_buf = vec![0; size];
-    file.read_exact_at(&mut data_buf, offset as u64)
-        .map_err(|e| e.to_string())?;
+    file.read_exact_at(&mut data_buf, offset as u64)?;
     Ok(data_buf)
 }
 
@@ -272,22 +317,24 @@ fn load_page<M: Read + Seek>(
     data: &mut M,
     headers: &GamedataHeaders,
     page: usize,
-) -> Result<Vec<u8>, String> {
-    let header = &headers.headers[page];
-    data.seek(SeekFrom::Start(header.offset as u64))
-        .map_err(|e| e.to_string())?;
+) -> Result<Vec<u8>, Error> {
+    let header = headers.headers.get(page).ok_or(Error::OutOfRange {
+        kind: "page",
+        index: page,
+    })?;
+    data.seek(SeekFrom::Start(header.offset as u64))?;
     let mut buffer: Vec<u8> = vec![0; header.length as usize];
-    let n = data.read(&mut buffer).map_err(|e| e.to_string())?;
+    let n = data.read(&mut buffer)?;
     if n != header.length as usize {
-        return Err("not enough bytes in page".to_string());
+        return Err(Error::Format("not enough bytes in page".into()));
     }
     Ok(buffer)
 }
 
-pub fn read_w3d_audio_header(header_file: &Path) -> Result<Vec<u32>, String> {
-    let mut file = File::open(header_file).map_err(|e| e.to_string())?;
+pub fn read_w3d_audio_header(header_file: &Path) -> Result<Vec<u32>, Error> {
+    let mut file = File::open(header_file)?;
     let mut buf = Vec::new();
-    let size = file.read_to_end(&mut buf).map_err(|e| e.to_string())?;
+    let size = file.read_to_end(&mut buf)?;
 
     let num_headers = size / 4;
     let mut headers = Vec::with_capacity(num_headers);
@@ -299,9 +346,15 @@ pub fn read_w3d_audio_header(header_file: &Path) -> Result<Vec<u32>, String> {
     Ok(headers)
 }
 
-pub fn read_w3d_gamedata_header(gamedata_bytes: &[u8]) -> Result<GamedataHeaders, String> {
+pub fn read_w3d_gamedata_header(gamedata_bytes: &[u8]) -> Result<GamedataHeaders, Error> {
+    if gamedata_bytes.len() < 6 {
+        return Err(Error::Format("gamedata header truncated".into()));
+    }
     let mut reader = DataReader::new(&gamedata_bytes);
     let num_chunks = reader.read_u16();
+    if gamedata_bytes.len() < 6 + num_chunks as usize * 6 {
+        return Err(Error::Format("gamedata header truncated".into()));
+    }
     let sprite_start = reader.read_u16();
     let sound_start = reader.read_u16();
 
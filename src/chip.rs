@@ -5,7 +5,6 @@ mod chip_test;
 extern crate alloc;
 
 use alloc::string::{String, ToString};
-use alloc::vec;
 use alloc::vec::Vec;
 use core::array::from_fn;
 use core::f64::consts::PI;
@@ -20,6 +19,8 @@ const NUM_CHANNELS: usize = 18;
 const WAVE_BITS: u32 = 10;
 const WAVE_SH: u32 = 32 - WAVE_BITS;
 
+const WAVE_MASK: u32 = (1 << WAVE_SH) - 1;
+
 const LFO_SH: u32 = WAVE_SH - 10;
 const LFO_MAX: u32 = 256 << LFO_SH;
 
@@ -75,6 +76,9 @@ const AL_FEED_CON: u32 = 0xc0;
 pub const AL_FREQ_L: u32 = 0xa0;
 pub const AL_FREQ_H: u32 = 0xb0;
 
+//register offset of the modulator operator of the 9 melodic channels, the carrier is +3
+pub(crate) static CHANNEL_MODULATOR_OFFSET: [u32; 9] = [0, 1, 2, 8, 9, 10, 16, 17, 18];
+
 static VOLUME_HANDLER_TABLE: [VolumeHandler; 5] = [
     template_volume_off,
     template_volume_release,
@@ -83,7 +87,7 @@ static VOLUME_HANDLER_TABLE: [VolumeHandler; 5] = [
     template_volume_attack,
 ];
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Instrument {
     pub m_char: u8,
     pub c_char: u8,
@@ -100,16 +104,19 @@ pub struct Instrument {
     pub mode: u8,
 }
 
-#[derive(Clone, Debug)]
+/// An AdLib sound effect. The length of the sound is the length of `data`,
+/// one byte is played per sound tick.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct AdlSound {
-    orginal_length: usize, //size of the original byte array to be able to reconstruct it
-    pub length: u32,
     pub priority: u16,
     pub instrument: Instrument,
     pub block: u8,
     pub data: Vec<u8>,
     pub terminator: u8,
     pub name: String,
+    /// Bytes after the terminator of the name (the chunks in AUDIOT are padded),
+    /// written back by `to_vec`.
+    pub trailer: Vec<u8>,
 }
 
 pub struct Chip {
@@ -119,6 +126,11 @@ pub struct Chip {
     lfo_counter: u32,
     lfo_add: u32,
 
+    //the noise generator of the hi-hat, snare drum and top cymbal
+    noise_counter: u32,
+    noise_add: u32,
+    noise_value: u32,
+
     reg_104: u8,
     reg_08: u8,
     reg_bd: u8,
@@ -569,6 +581,9 @@ impl Chip {
             channels,
             lfo_counter: 0,
             lfo_add: (0.5 + scale * (1 << LFO_SH) as f64) as u32,
+            noise_counter: 0,
+            noise_add: (0.5 + scale * (1 << LFO_SH) as f64) as u32,
+            noise_value: 1,
             reg_104: 0,
             reg_08: 0,
             reg_bd: 0,
@@ -623,6 +638,40 @@ impl Chip {
         self.write_reg(1, 0x20);
     }
 
+    // The frequency registers (0xA0, 0xB0) of the melodic channel as the chip holds them.
+    #[cfg(test)]
+    pub(crate) fn freq_regs(&self, channel: usize) -> (u8, u8) {
+        let offset = self.tables.chan_offset_table[channel].expect("melodic channel");
+        let channel = &self.channels[offset];
+        (
+            channel.chan_data as u8,
+            ((channel.chan_data >> 8) & 0x1f) as u8 | (channel.reg_b0 & 0x20),
+        )
+    }
+
+    // The registers 0x20, 0x40, 0x60, 0x80 and 0xE0 of the operator at the register offset.
+    #[cfg(test)]
+    pub(crate) fn op_regs(&self, offset: u32) -> [u8; 5] {
+        let ix = ((offset >> 3) & 0x20) | (offset & 0x1f);
+        let offset = self.tables.op_offset_table[ix as usize]
+            .as_ref()
+            .expect("operator");
+        let op = &self.channels[offset.chan].operator[offset.op];
+        [op.reg_20, op.reg_40, op.reg_60, op.reg_80, op.reg_e0]
+    }
+
+    // The feedback/connection register (0xC0) of the melodic channel.
+    #[cfg(test)]
+    pub(crate) fn feed_con_reg(&self, channel: usize) -> u8 {
+        let offset = self.tables.chan_offset_table[channel].expect("melodic channel");
+        self.channels[offset].reg_c0
+    }
+
+    #[cfg(test)]
+    pub(crate) fn rhythm_reg(&self) -> u8 {
+        self.reg_bd
+    }
+
     pub fn write_reg(&mut self, reg: u32, val: u8) {
         match reg & 0xf0 {
             0x00 => {
@@ -669,6 +718,18 @@ impl Chip {
         }
     }
 
+    fn forward_noise(&mut self) -> u32 {
+        self.noise_counter += self.noise_add;
+        let count = self.noise_counter >> LFO_SH;
+        self.noise_counter &= WAVE_MASK;
+        for _ in 0..count {
+            //noise calculation from mame
+            self.noise_value ^= 0x800302 & 0u32.wrapping_sub(self.noise_value & 1);
+            self.noise_value >>= 1;
+        }
+        self.noise_value
+    }
+
     fn write_bd(&mut self, val: u8) {
         let change = self.reg_bd ^ val;
         if change == 0 {
@@ -881,7 +942,21 @@ impl Chip {
     }
 
     pub fn generate_block_2(&mut self, total_in: usize, mix_buffer: &mut Vec<i32>) {
+        self.generate_block_split(total_in, mix_buffer, None);
+    }
+
+    /// Like `generate_block_2`, but the given channel is rendered into its own
+    /// buffer (to mix a sound effect sharing the chip with the music at a different level).
+    pub fn generate_block_split(
+        &mut self,
+        total_in: usize,
+        mix_buffer: &mut [i32],
+        mut split: Option<(usize, &mut [i32])>,
+    ) {
         mix_buffer.fill(0);
+        if let Some((_, split_buffer)) = split.as_mut() {
+            split_buffer.fill(0);
+        }
 
         let mut mix_offset = 0;
         let mut total = total_in;
@@ -890,8 +965,13 @@ impl Chip {
             let mut chan_ptr = 0;
             while chan_ptr < 9 {
                 let chan = &mut self.channels[chan_ptr];
-                let ch_shift =
-                    (chan.synth_handler)(self, chan_ptr, samples, &mut mix_buffer[mix_offset..]);
+                let out = match split.as_mut() {
+                    Some((channel, split_buffer)) if *channel == chan_ptr => {
+                        &mut split_buffer[mix_offset..]
+                    }
+                    _ => &mut mix_buffer[mix_offset..],
+                };
+                let ch_shift = (chan.synth_handler)(self, chan_ptr, samples, out);
                 chan_ptr += ch_shift;
             }
             total -= samples;
@@ -1231,6 +1311,7 @@ fn channel_block_template(
                 return 1;
             }
         }
+        SynthMode::SM2Percussion | SynthMode::SM3Percussion => {}
         _ => todo!("block template {:?}", mode),
     }
 
@@ -1238,13 +1319,14 @@ fn channel_block_template(
     operator_prepare(chip, channel_ix, 0);
     operator_prepare(chip, channel_ix, 1);
 
+    //the operators 2-5 are the ones of the next two channels
     if mode > SynthMode::SM4Start {
-        operator_prepare(chip, channel_ix, 2);
-        operator_prepare(chip, channel_ix, 3);
+        operator_prepare(chip, channel_ix + 1, 0);
+        operator_prepare(chip, channel_ix + 1, 1);
     }
     if mode > SynthMode::SM6Start {
-        operator_prepare(chip, channel_ix, 4);
-        operator_prepare(chip, channel_ix, 5);
+        operator_prepare(chip, channel_ix + 2, 0);
+        operator_prepare(chip, channel_ix + 2, 1);
     }
 
     for i in 0..samples {
@@ -1314,13 +1396,70 @@ fn channel_block_template(
     }
 }
 
+// channel_ix is the bass drum channel, the hi-hat and snare drum are the operators
+// of the next channel, the tom-tom and top cymbal the ones of the channel after it
 fn channel_generate_percussion(
-    _chip: &mut Chip,
-    _channel_ix: usize,
-    _output: &mut [i32],
-    _opl3_mode: bool,
+    chip: &mut Chip,
+    channel_ix: usize,
+    output: &mut [i32],
+    opl3_mode: bool,
 ) {
-    todo!("channel_generate_percussion");
+    //bass drum
+    let channel = &mut chip.channels[channel_ix];
+    let modulation = ((channel.old[0] + channel.old[1]) as u32 >> channel.feedback) as i32;
+    channel.old[0] = channel.old[1];
+    channel.old[1] = operator_get_sample(&mut channel.operator[0], &chip.tables, modulation);
+
+    //when bass drum is in AM mode first operator is ignored
+    let modulation = if (channel.reg_c0 & 1) != 0 {
+        0
+    } else {
+        channel.old[0]
+    };
+    let mut sample = operator_get_sample(&mut channel.operator[1], &chip.tables, modulation);
+
+    //precalculate stuff used by other outputs
+    let noise_bit = chip.forward_noise() & 0x1;
+    let c2 = operator_forward_wave(&mut chip.channels[channel_ix + 1].operator[0]);
+    let c5 = operator_forward_wave(&mut chip.channels[channel_ix + 2].operator[1]);
+    let phase_bit = if (((c2 & 0x88) ^ ((c2 << 5) & 0x80)) | ((c5 ^ (c5 << 2)) & 0x20)) != 0 {
+        0x02
+    } else {
+        0x00
+    };
+
+    //hi-hat
+    let op = &mut chip.channels[channel_ix + 1].operator[0];
+    let hh_vol = operator_forward_volume(op);
+    if !env_silent(hh_vol) {
+        let hh_index = (phase_bit << 8) | (0x34 << (phase_bit ^ (noise_bit << 1)));
+        sample += operator_get_wave(op, &chip.tables, hh_index as i32, hh_vol);
+    }
+    //snare drum
+    let op = &mut chip.channels[channel_ix + 1].operator[1];
+    let sd_vol = operator_forward_volume(op);
+    if !env_silent(sd_vol) {
+        let sd_index = (0x100 + (c2 & 0x100)) ^ (noise_bit << 8);
+        sample += operator_get_wave(op, &chip.tables, sd_index as i32, sd_vol);
+    }
+    //tom-tom
+    let op = &mut chip.channels[channel_ix + 2].operator[0];
+    sample += operator_get_sample(op, &chip.tables, 0);
+
+    //top cymbal
+    let op = &mut chip.channels[channel_ix + 2].operator[1];
+    let tc_vol = operator_forward_volume(op);
+    if !env_silent(tc_vol) {
+        let tc_index = (1 + phase_bit) << 8;
+        sample += operator_get_wave(op, &chip.tables, tc_index as i32, tc_vol);
+    }
+    sample <<= 1;
+    if opl3_mode {
+        output[0] += sample;
+        output[1] += sample;
+    } else {
+        output[0] += sample;
+    }
 }
 
 // Volume Templates
@@ -1402,25 +1541,160 @@ fn env_silent(x: i32) -> bool {
 
 // backend impl helper functions
 
-pub fn adl_set_fx_inst(chip: &mut Chip, inst: &Instrument) {
-    let c = 3;
-    chip.write_reg(AL_CHAR, inst.m_char);
-    chip.write_reg(AL_SCALE, inst.m_scale);
-    chip.write_reg(AL_ATTACK, inst.m_attack);
-    chip.write_reg(AL_SUS, inst.m_sus);
-    chip.write_reg(AL_WAVE, inst.m_wave);
+/// The voice a sound effect is played on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdlVoice {
+    /// A melodic channel (0-8), keyed on in its 0xB0 register.
+    Melodic(u8),
+    /// A percussion voice of the rhythm mode, keyed on by `bit` in 0xBD. The
+    /// frequency is set on `channel` (6-8).
+    Rhythm { channel: u8, bit: u8 },
+}
+
+impl AdlVoice {
+    /// The channel whose frequency registers play the voice.
+    pub fn channel(self) -> u8 {
+        match self {
+            AdlVoice::Melodic(channel) => channel,
+            AdlVoice::Rhythm { channel, .. } => channel,
+        }
+    }
+}
+
+// register offset of the single operator, channel and 0xBD bit of the snare,
+// tom, cymbal and hi-hat (`voice` 1-4 of a percussive instrument)
+static RHYTHM_VOICES: [(u32, u8, u8); 4] = [
+    (0x14, 7, 0x08),
+    (0x12, 8, 0x04),
+    (0x15, 8, 0x02),
+    (0x11, 7, 0x01),
+];
+
+const MODE_PERCUSSIVE: u8 = 1;
+const VOICE_BASS_DRUM: u8 = 0;
+
+/// Programs the instrument of a sound effect and returns the voice to play it on.
+/// A percussive instrument (`mode` 1) is programmed on the rhythm voice selected
+/// by `voice`: 0 bass drum (both operators of channel 6), 1 snare, 2 tom,
+/// 3 cymbal and 4 hi-hat (the modulator settings on the single operator of the
+/// voice). Any other instrument is played on the melodic `channel` (0-8), id's
+/// tools leave garbage in `voice` and `mode`.
+pub fn adl_set_fx_inst(chip: &mut Chip, channel: u8, inst: &Instrument) -> AdlVoice {
+    let (channel, voice) = match (inst.mode, inst.voice) {
+        (MODE_PERCUSSIVE, VOICE_BASS_DRUM) => (
+            6,
+            AdlVoice::Rhythm {
+                channel: 6,
+                bit: 0x10,
+            },
+        ),
+        (MODE_PERCUSSIVE, 1..=4) => {
+            let (op, channel, bit) = RHYTHM_VOICES[inst.voice as usize - 1];
+            chip.write_reg(op + AL_CHAR, inst.m_char);
+            chip.write_reg(op + AL_SCALE, inst.m_scale);
+            chip.write_reg(op + AL_ATTACK, inst.m_attack);
+            chip.write_reg(op + AL_SUS, inst.m_sus);
+            chip.write_reg(op + AL_WAVE, inst.m_wave);
+            return AdlVoice::Rhythm { channel, bit };
+        }
+        _ => (channel, AdlVoice::Melodic(channel)),
+    };
+
+    let m = CHANNEL_MODULATOR_OFFSET[channel as usize];
+    let c = m + 3;
+    chip.write_reg(m + AL_CHAR, inst.m_char);
+    chip.write_reg(m + AL_SCALE, inst.m_scale);
+    chip.write_reg(m + AL_ATTACK, inst.m_attack);
+    chip.write_reg(m + AL_SUS, inst.m_sus);
+    chip.write_reg(m + AL_WAVE, inst.m_wave);
     chip.write_reg(c + AL_CHAR, inst.c_char);
     chip.write_reg(c + AL_SCALE, inst.c_scale);
     chip.write_reg(c + AL_ATTACK, inst.c_attack);
     chip.write_reg(c + AL_SUS, inst.c_sus);
     chip.write_reg(c + AL_WAVE, inst.c_wave);
 
-    chip.write_reg(AL_FEED_CON, 0);
+    chip.write_reg(channel as u32 + AL_FEED_CON, inst.n_conn);
+    voice
+}
+
+const ADL_HEADER_LEN: usize = 23;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdlError {
+    /// Not even the header is there.
+    TooShort { len: usize },
+    /// The data is shorter than the length in the header says.
+    LengthMismatch { declared: u32, available: usize },
+    /// The terminator byte after the data or the null byte after the name is missing.
+    MissingTerminator,
+    /// The name contains control characters.
+    InvalidName,
+}
+
+impl core::fmt::Display for AdlError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AdlError::TooShort { len } => write!(f, "adl sound too short: {} bytes", len),
+            AdlError::LengthMismatch {
+                declared,
+                available,
+            } => write!(
+                f,
+                "adl sound length mismatch: {} bytes declared, {} available",
+                declared, available
+            ),
+            AdlError::MissingTerminator => write!(f, "adl sound terminator missing"),
+            AdlError::InvalidName => write!(f, "adl sound name invalid"),
+        }
+    }
+}
+
+impl core::error::Error for AdlError {}
+
+impl TryFrom<&[u8]> for AdlSound {
+    type Error = AdlError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        AdlSound::from_bytes(data)
+    }
 }
 
 impl AdlSound {
-    pub fn from_bytes(data: &[u8]) -> AdlSound {
+    /// Creates a sound with an empty name and a zero terminator.
+    pub fn new(priority: u16, instrument: Instrument, block: u8, data: Vec<u8>) -> AdlSound {
+        AdlSound {
+            priority,
+            instrument,
+            block,
+            data,
+            terminator: 0,
+            name: String::new(),
+            trailer: Vec::new(),
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> AdlSound {
+        self.name = name.into();
+        self
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<AdlSound, AdlError> {
+        if data.len() < ADL_HEADER_LEN {
+            return Err(AdlError::TooShort { len: data.len() });
+        }
         let length = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        // the length is untrusted, a corrupted one must not overflow on 32 bit targets
+        let data_end = ADL_HEADER_LEN
+            .checked_add(length as usize)
+            .filter(|end| *end <= data.len())
+            .ok_or(AdlError::LengthMismatch {
+                declared: length,
+                available: data.len() - ADL_HEADER_LEN,
+            })?;
+        if data.len() == data_end {
+            return Err(AdlError::MissingTerminator);
+        }
+
         let instrument = Instrument {
             m_char: data[6],
             c_char: data[7],
@@ -1437,55 +1711,80 @@ impl AdlSound {
             mode: data[18],
             // data[19..22] are padding and omitted
         };
-        AdlSound {
-            orginal_length: data.len(),
-            length,
+        let (name, trailer) = parse_name(&data[(data_end + 1)..])?;
+        Ok(AdlSound {
             priority: u16::from_le_bytes(data[4..6].try_into().unwrap()),
             instrument,
             block: data[22],
-            data: data[23..(23 + length as usize)].to_vec(),
-            terminator: data[23 + length as usize],
-            name: str::from_utf8(&data[(23 + length as usize) + 1..data.len() - 1])
-                .expect("sound name")
-                .to_string(),
-        }
+            data: data[ADL_HEADER_LEN..data_end].to_vec(),
+            terminator: data[data_end],
+            name,
+            trailer: trailer.to_vec(),
+        })
     }
 
+    /// Serializes the sound to the chunk format. All sizes are derived from the
+    /// fields, the header padding is written as zeros and the trailer as is.
     pub fn to_vec(&self) -> Vec<u8> {
-        let len = self.length as usize;
-
-        let mut data = vec![0; self.orginal_length];
-        let len_bytes = self.length.to_le_bytes();
-        data[0] = len_bytes[0];
-        data[1] = len_bytes[1];
-        data[2] = len_bytes[2];
-        data[3] = len_bytes[3];
-        let prio = self.priority.to_le_bytes();
-        data[4] = prio[0];
-        data[5] = prio[1];
-
-        data[6] = self.instrument.m_char;
-        data[7] = self.instrument.c_char;
-        data[8] = self.instrument.m_scale;
-        data[9] = self.instrument.c_scale;
-        data[10] = self.instrument.m_attack;
-        data[11] = self.instrument.c_attack;
-        data[12] = self.instrument.m_sus;
-        data[13] = self.instrument.c_sus;
-        data[14] = self.instrument.m_wave;
-        data[15] = self.instrument.c_wave;
-        data[16] = self.instrument.n_conn;
-        data[17] = self.instrument.voice;
-        data[18] = self.instrument.mode;
-
-        data[22] = self.block;
-        data[23..(23 + len)].copy_from_slice(&self.data);
-        data[23 + len] = self.terminator;
-
-        let name_bytes = self.name.as_bytes();
-        let str_offset = 23 + len + 1;
-        data[str_offset..(str_offset + name_bytes.len())].copy_from_slice(name_bytes);
-
-        data
+        let name_bytes = encode_name(&self.name);
+        let mut out = Vec::with_capacity(
+            ADL_HEADER_LEN + self.data.len() + name_bytes.len() + 2 + self.trailer.len(),
+        );
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.priority.to_le_bytes());
+        out.extend_from_slice(&[
+            self.instrument.m_char,
+            self.instrument.c_char,
+            self.instrument.m_scale,
+            self.instrument.c_scale,
+            self.instrument.m_attack,
+            self.instrument.c_attack,
+            self.instrument.m_sus,
+            self.instrument.c_sus,
+            self.instrument.m_wave,
+            self.instrument.c_wave,
+            self.instrument.n_conn,
+            self.instrument.voice,
+            self.instrument.mode,
+            0,
+            0,
+            0,
+        ]);
+        out.push(self.block);
+        out.extend_from_slice(&self.data);
+        out.push(self.terminator);
+        out.extend_from_slice(&name_bytes);
+        out.push(0);
+        out.extend_from_slice(&self.trailer);
+        out
+    }
+}
+
+// Names are null terminated. They are decoded as UTF-8 if possible and as Latin-1 otherwise.
+// Returns the name and the bytes after its terminator.
+fn parse_name(bytes: &[u8]) -> Result<(String, &[u8]), AdlError> {
+    if bytes.is_empty() {
+        return Ok((String::new(), bytes));
+    }
+    let end = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(AdlError::MissingTerminator)?;
+    let name = match str::from_utf8(&bytes[..end]) {
+        Ok(name) => name.to_string(),
+        Err(_) => bytes[..end].iter().map(|b| *b as char).collect(),
+    };
+    if name.chars().any(|c| c.is_control()) {
+        return Err(AdlError::InvalidName);
+    }
+    Ok((name, &bytes[end + 1..]))
+}
+
+// Latin-1 if the name fits in it (what the DOS tools wrote), UTF-8 otherwise.
+fn encode_name(name: &str) -> Vec<u8> {
+    if name.chars().all(|c| (c as u32) <= 0xff) {
+        name.chars().map(|c| c as u8).collect()
+    } else {
+        name.as_bytes().to_vec()
     }
 }
@@ -1,4 +1,4 @@
-use crate::chip::{AdlSound, Chip, OpOffset};
+use crate::chip::{AdlError, AdlSound, AdlVoice, Chip, Instrument, OpOffset, adl_set_fx_inst};
 
 const TEST_RATE: u32 = 49716;
 
@@ -4926,7 +4926,7 @@ fn test_ksl_table() {
 fn test_adl_sound_to_vec() {
     let test_adl_bytes = include_bytes!("../testdata/test.adl");
 
-    let sound = AdlSound::from_bytes(test_adl_bytes);
+    let sound = AdlSound::from_bytes(test_adl_bytes).expect("parse adl");
     let back_to_vec = sound.to_vec();
 
     // padding bytes contain garbage in the reference file. null them out so that it compares.
@@ -4937,3 +4937,156 @@ fn test_adl_sound_to_vec() {
 
     assert_eq!(back_to_vec, ref_bytes, "not same bytes in conversion back")
 }
+
+#[test]
+fn test_adl_sound_new_round_trip() {
+    let instrument = Instrument {
+        m_char: 0x21,
+        c_char: 0x31,
+        m_attack: 0xf0,
+        c_attack: 0xf2,
+        n_conn: 0x0e,
+        ..Default::default()
+    };
+    let mut sound = AdlSound::new(10, instrument, 4, vec![0x40, 0x50, 0x60]).with_name("Bläh");
+
+    let bytes = sound.to_vec();
+    assert_eq!(bytes.len(), 23 + 3 + 1 + 4 + 1);
+    assert_eq!(&bytes[0..4], &[3, 0, 0, 0]);
+    assert_eq!(AdlSound::from_bytes(&bytes).expect("parse adl"), sound);
+
+    // changing the data length keeps the chunk valid
+    sound.data.extend_from_slice(&[0x70, 0x80]);
+    let bytes = sound.to_vec();
+    assert_eq!(&bytes[0..4], &[5, 0, 0, 0]);
+    assert_eq!(AdlSound::from_bytes(&bytes).expect("parse adl"), sound);
+}
+
+#[test]
+fn test_adl_sound_parse_errors() {
+    let test_adl_bytes = include_bytes!("../testdata/test.adl");
+
+    assert_eq!(
+        AdlSound::try_from(&test_adl_bytes[..10]).unwrap_err(),
+        AdlError::TooShort { len: 10 }
+    );
+    assert_eq!(
+        AdlSound::try_from(&test_adl_bytes[..27]).unwrap_err(),
+        AdlError::LengthMismatch {
+            declared: 8,
+            available: 4
+        }
+    );
+    assert_eq!(
+        AdlSound::try_from(&test_adl_bytes[..31]).unwrap_err(),
+        AdlError::MissingTerminator
+    );
+    assert_eq!(
+        AdlSound::try_from(&test_adl_bytes[..35]).unwrap_err(),
+        AdlError::MissingTerminator
+    );
+
+    let mut control_name = test_adl_bytes.to_vec();
+    control_name[33] = 0x07;
+    assert_eq!(
+        AdlSound::try_from(&control_name[..]).unwrap_err(),
+        AdlError::InvalidName
+    );
+}
+
+#[test]
+fn test_adl_sound_length_overflow() {
+    let mut test_adl_bytes = include_bytes!("../testdata/test.adl").to_vec();
+    test_adl_bytes[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        AdlSound::try_from(&test_adl_bytes[..]).unwrap_err(),
+        AdlError::LengthMismatch {
+            declared: u32::MAX,
+            available: test_adl_bytes.len() - 23
+        }
+    );
+}
+
+#[test]
+fn test_adl_sound_latin1_name() {
+    let mut test_adl_bytes = include_bytes!("../testdata/test.adl").to_vec();
+    test_adl_bytes[33] = 0xd6; // Latin-1 O-umlaut
+
+    let sound = AdlSound::try_from(&test_adl_bytes[..]).expect("parse adl");
+    assert_eq!(sound.name, "NÖTBIG");
+    assert_eq!(sound.to_vec()[33], 0xd6);
+}
+
+fn fx_instrument(voice: u8, mode: u8) -> Instrument {
+    Instrument {
+        m_char: 0x21,
+        c_char: 0x31,
+        m_scale: 0x4f,
+        c_scale: 0x02,
+        m_attack: 0xf2,
+        c_attack: 0xd3,
+        m_sus: 0x57,
+        c_sus: 0x68,
+        m_wave: 0x01,
+        c_wave: 0x02,
+        n_conn: 0x0b,
+        voice,
+        mode,
+    }
+}
+
+#[test]
+fn test_adl_set_fx_inst_melodic() {
+    let mut chip = Chip::new(TEST_RATE);
+    chip.setup();
+    // id's tools leave garbage in voice and mode
+    let voice = adl_set_fx_inst(&mut chip, 4, &fx_instrument(0x32, 0xd5));
+
+    assert_eq!(voice, AdlVoice::Melodic(4));
+    assert_eq!(chip.op_regs(0x09), [0x21, 0x4f, 0xf2, 0x57, 0x01]);
+    assert_eq!(chip.op_regs(0x0c), [0x31, 0x02, 0xd3, 0x68, 0x02]);
+    assert_eq!(chip.feed_con_reg(4), 0x0b);
+}
+
+#[test]
+fn test_adl_set_fx_inst_rhythm() {
+    let mut chip = Chip::new(TEST_RATE);
+    chip.setup();
+    let voice = adl_set_fx_inst(&mut chip, 4, &fx_instrument(0, 1));
+
+    assert_eq!(
+        voice,
+        AdlVoice::Rhythm {
+            channel: 6,
+            bit: 0x10
+        }
+    );
+    assert_eq!(chip.op_regs(0x10), [0x21, 0x4f, 0xf2, 0x57, 0x01]);
+    assert_eq!(chip.op_regs(0x13), [0x31, 0x02, 0xd3, 0x68, 0x02]);
+    assert_eq!(chip.feed_con_reg(6), 0x0b);
+    assert_eq!(
+        chip.op_regs(0x09),
+        [0; 5],
+        "the melodic channel is left alone"
+    );
+    assert_eq!(chip.feed_con_reg(4), 0);
+
+    let mut chip = Chip::new(TEST_RATE);
+    chip.setup();
+    let voice = adl_set_fx_inst(&mut chip, 4, &fx_instrument(4, 1));
+
+    assert_eq!(
+        voice,
+        AdlVoice::Rhythm {
+            channel: 7,
+            bit: 0x01
+        }
+    );
+    assert_eq!(chip.op_regs(0x11), [0x21, 0x4f, 0xf2, 0x57, 0x01]);
+    assert_eq!(
+        chip.op_regs(0x14),
+        [0; 5],
+        "the snare operator is left alone"
+    );
+    assert_eq!(chip.feed_con_reg(7), 0);
+}
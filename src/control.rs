@@ -0,0 +1,724 @@
+#[cfg(test)]
+#[path = "./control_test.rs"]
+mod control_test;
+
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering, fence};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::Error;
+use crate::chip::AdlSound;
+use crate::digi::{DIGI_VOICES, DigiSound};
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{ImfOptions, Released, Sample, Sequencer};
+use crate::spsc::{self, Consumer, Producer};
+
+// Control of a sequencer that is rendered on an audio thread. The control side
+// (`Controller`) sends commands through a lock-free queue, the audio side
+// (`Processor`) applies them before each block and publishes the playback
+// status through atomics. Neither side ever waits for the other. The tracks,
+// sounds and callbacks the audio side lets go of are returned through a second
+// queue and freed on the control side.
+
+/// Default number of commands that can be in flight between two audio blocks.
+pub(crate) const COMMAND_CAPACITY: usize = 4096;
+
+// status value of a sound that is not playing / a track that is not loaded
+const NO_PRIORITY: u32 = u32::MAX;
+const NO_DURATION: u64 = u64::MAX;
+
+pub(crate) enum Command {
+    PlayImf(Vec<u8>, ImfOptions),
+    StopImf,
+    PauseImf,
+    ResumeImf,
+    SeekImf(Duration),
+    SetImfTempo(f32),
+    SetImfTranspose(i8),
+    SetMusicVolume(f32),
+    SetSfxVolume(f32),
+    SetMasterVolume(f32),
+    PlayAdl(AdlSound),
+    StopAdl,
+    PlayPc(PcSound),
+    StopPc,
+    PlayDigi(usize, DigiSound),
+    StopDigi(usize),
+    SetDigiVolume(usize, f32),
+    WriteReg(u32, u8),
+    WriteRegAt(u64, u32, u8),
+    OnImfEnd(Box<dyn FnMut() + Send>),
+}
+
+// Memory the audio side no longer uses, dropped by the control side (the
+// payloads are never read).
+#[allow(dead_code)]
+pub(crate) enum Returned {
+    Released(Released),
+    OnImfEnd(Box<dyn FnMut() + Send>),
+}
+
+// Written by the audio side after each block (except `closed`).
+struct Status {
+    // number of commands applied before the status was published
+    applied: AtomicU64,
+    imf_playing: AtomicBool,
+    imf_paused: AtomicBool,
+    adl_priority: AtomicU32,
+    pc_priority: AtomicU32,
+    digi_playing: [AtomicBool; DIGI_VOICES],
+    imf_duration: AtomicU64,
+    imf_position: AtomicU64,
+    sample_clock: AtomicU64,
+    // host time (nanoseconds since the epoch) and sample clock at the start of
+    // the last block, guarded by a sequence lock (odd while written, 0 before the first block)
+    block_version: AtomicU64,
+    block_nanos: AtomicU64,
+    block_clock: AtomicU64,
+    // number of times a sound effect / a track ended or was stopped
+    adl_ends: AtomicU64,
+    imf_ends: AtomicU64,
+    // a track is playing and not stopped
+    imf_active: AtomicBool,
+    // futures waiting for an end, the audio side only try-locks them
+    waiting: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    // set by the control side
+    #[cfg(any(feature = "rodio", test))]
+    closed: AtomicBool,
+}
+
+#[derive(Clone, Copy)]
+enum End {
+    Adl,
+    Imf,
+}
+
+impl Status {
+    fn new() -> Status {
+        Status {
+            applied: AtomicU64::new(0),
+            imf_playing: AtomicBool::new(false),
+            imf_paused: AtomicBool::new(false),
+            adl_priority: AtomicU32::new(NO_PRIORITY),
+            pc_priority: AtomicU32::new(NO_PRIORITY),
+            digi_playing: Default::default(),
+            imf_duration: AtomicU64::new(NO_DURATION),
+            imf_position: AtomicU64::new(NO_DURATION),
+            sample_clock: AtomicU64::new(0),
+            block_version: AtomicU64::new(0),
+            block_nanos: AtomicU64::new(0),
+            block_clock: AtomicU64::new(0),
+            adl_ends: AtomicU64::new(0),
+            imf_ends: AtomicU64::new(0),
+            imf_active: AtomicBool::new(false),
+            waiting: AtomicBool::new(false),
+            wakers: Mutex::new(Vec::new()),
+            #[cfg(any(feature = "rodio", test))]
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn ends(&self, end: End) -> &AtomicU64 {
+        match end {
+            End::Adl => &self.adl_ends,
+            End::Imf => &self.imf_ends,
+        }
+    }
+
+    // an end was counted since `ends`, or nothing plays after command `after` was applied
+    fn has_ended(&self, end: End, ends: u64, after: u64) -> bool {
+        if self.ends(end).load(Ordering::SeqCst) > ends {
+            return true;
+        }
+        if self.applied.load(Ordering::Acquire) < after {
+            return false;
+        }
+        match end {
+            End::Adl => self.adl_priority.load(Ordering::Relaxed) == NO_PRIORITY,
+            End::Imf => !self.imf_active.load(Ordering::Relaxed),
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+        self.waiting.store(true, Ordering::SeqCst);
+        drop(wakers);
+        // pairs with the fence in `wake_all`: either the audio side sees the
+        // waker or the caller sees the end counted before it
+        fence(Ordering::SeqCst);
+    }
+
+    // False if the wakers were locked by the control side, to be retried after the next block.
+    // The wakers are swapped into the empty `woken`, both vectors keep their capacity.
+    fn wake_all(&self, woken: &mut Vec<Waker>) -> bool {
+        fence(Ordering::SeqCst);
+        if !self.waiting.load(Ordering::SeqCst) {
+            return true;
+        }
+        let Ok(mut locked) = self.wakers.try_lock() else {
+            return false;
+        };
+        self.waiting.store(false, Ordering::SeqCst);
+        std::mem::swap(&mut *locked, woken);
+        drop(locked);
+        for waker in woken.drain(..) {
+            waker.wake();
+        }
+        true
+    }
+
+    fn publish_block(&self, nanos: u64, clock: u64) {
+        let version = self.block_version.load(Ordering::Relaxed);
+        self.block_version.store(version + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.block_nanos.store(nanos, Ordering::Relaxed);
+        self.block_clock.store(clock, Ordering::Relaxed);
+        self.block_version.store(version + 2, Ordering::Release);
+    }
+
+    #[cfg(any(feature = "sdl", test))]
+    fn last_block(&self) -> Option<(u64, u64)> {
+        loop {
+            let version = self.block_version.load(Ordering::Acquire);
+            if version == 0 {
+                return None;
+            }
+            if version % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let nanos = self.block_nanos.load(Ordering::Relaxed);
+            let clock = self.block_clock.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.block_version.load(Ordering::Relaxed) == version {
+                return Some((nanos, clock));
+            }
+        }
+    }
+}
+
+/// Creates the two sides for the sequencer, which is moved to the audio side.
+// the host time mapping (`mixer_rate`, `buffer_frames`) is only used by SDL
+#[cfg_attr(not(any(feature = "sdl", test)), allow(unused_variables))]
+pub(crate) fn channel(
+    mut sequencer: Sequencer,
+    mixer_rate: u32,
+    channels: usize,
+    buffer_frames: u64,
+    capacity: usize,
+) -> (Controller, Processor) {
+    let (commands, queue) = spsc::ring(capacity);
+    // Every returned payload was sent in a command or held by the sequencer (a
+    // track, a callback and the sounds), the control side empties the queue before
+    // each command. It does not fill up unless the control side stops sending.
+    let (returns, returned) = spsc::ring(capacity + DIGI_VOICES + 4);
+    sequencer.set_keep_released(true);
+    let status = Arc::new(Status::new());
+    let epoch = Instant::now();
+    let controller = Controller {
+        commands,
+        returned,
+        status: status.clone(),
+        #[cfg(any(feature = "sdl", test))]
+        epoch,
+        #[cfg(any(feature = "sdl", test))]
+        mixer_rate,
+        #[cfg(any(feature = "sdl", test))]
+        buffer_frames,
+        sent: 0,
+        imf_playing: None,
+        imf_paused: None,
+        adl_priority: None,
+        pc_priority: None,
+        digi_playing: [None; DIGI_VOICES],
+    };
+    let processor = Processor {
+        sequencer,
+        commands: queue,
+        returns,
+        woken: Vec::new(),
+        status,
+        epoch,
+        channels,
+        applied: 0,
+        imf_stopped: false,
+        wake_pending: false,
+        on_imf_end: None,
+    };
+    (controller, processor)
+}
+
+// A state change the control side sent but the audio side may not have applied
+// yet. It overrides the published status until command `seq` was applied.
+#[derive(Clone, Copy)]
+struct Expected<T> {
+    seq: u64,
+    value: T,
+}
+
+pub(crate) struct Controller {
+    commands: Producer<Command>,
+    returned: Consumer<Returned>,
+    status: Arc<Status>,
+    #[cfg(any(feature = "sdl", test))]
+    epoch: Instant,
+    #[cfg(any(feature = "sdl", test))]
+    mixer_rate: u32,
+    #[cfg(any(feature = "sdl", test))]
+    buffer_frames: u64,
+    sent: u64,
+    imf_playing: Option<Expected<bool>>,
+    imf_paused: Option<Expected<bool>>,
+    adl_priority: Option<Expected<Option<u16>>>,
+    pc_priority: Option<Expected<Option<u16>>>,
+    digi_playing: [Option<Expected<bool>>; DIGI_VOICES],
+}
+
+impl Controller {
+    fn send(&mut self, command: Command) -> Result<u64, Error> {
+        // frees what the audio side returned
+        while self.returned.pop().is_some() {}
+        if self.commands.push(command).is_err() {
+            return Err(Error::QueueFull);
+        }
+        self.sent += 1;
+        Ok(self.sent)
+    }
+
+    fn current<T: Copy>(&self, expected: Option<Expected<T>>, published: impl FnOnce() -> T) -> T {
+        match expected {
+            Some(expected) if expected.seq > self.status.applied.load(Ordering::Acquire) => {
+                expected.value
+            }
+            _ => published(),
+        }
+    }
+
+    /// Ends the playback, the audio side renders nothing after its current block.
+    #[cfg(any(feature = "rodio", test))]
+    pub(crate) fn close(&self) {
+        self.status.closed.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn play_imf(&mut self, data: Vec<u8>, options: ImfOptions) -> Result<(), Error> {
+        let seq = self.send(Command::PlayImf(data, options))?;
+        self.imf_playing = Some(Expected { seq, value: true });
+        self.imf_paused = Some(Expected { seq, value: false });
+        Ok(())
+    }
+
+    pub(crate) fn on_imf_end(&mut self, on_end: Box<dyn FnMut() + Send>) -> Result<(), Error> {
+        self.send(Command::OnImfEnd(on_end))?;
+        Ok(())
+    }
+
+    pub(crate) fn stop_imf(&mut self) -> Result<(), Error> {
+        self.send(Command::StopImf)?;
+        Ok(())
+    }
+
+    pub(crate) fn pause_imf(&mut self) -> Result<(), Error> {
+        let loaded = self.is_imf_playing();
+        let seq = self.send(Command::PauseImf)?;
+        self.imf_paused = Some(Expected { seq, value: loaded });
+        Ok(())
+    }
+
+    pub(crate) fn resume_imf(&mut self) -> Result<(), Error> {
+        let seq = self.send(Command::ResumeImf)?;
+        self.imf_paused = Some(Expected { seq, value: false });
+        Ok(())
+    }
+
+    pub(crate) fn is_imf_paused(&self) -> bool {
+        self.current(self.imf_paused, || {
+            self.status.imf_paused.load(Ordering::Relaxed)
+        })
+    }
+
+    pub(crate) fn is_imf_playing(&self) -> bool {
+        self.current(self.imf_playing, || {
+            self.status.imf_playing.load(Ordering::Relaxed)
+        })
+    }
+
+    /// Resolves once the track ended or was stopped, also if it was replaced
+    /// by one that is still playing. Commands sent before are taken into account.
+    pub(crate) fn imf_finished(&self) -> impl Future<Output = ()> + Send + use<> {
+        self.finished(End::Imf)
+    }
+
+    /// Resolves once no sound effect is playing (after the commands sent before)
+    /// or the one playing ended or was stopped.
+    pub(crate) fn adl_finished(&self) -> impl Future<Output = ()> + Send + use<> {
+        self.finished(End::Adl)
+    }
+
+    fn finished(&self, end: End) -> impl Future<Output = ()> + Send + use<> {
+        let status = self.status.clone();
+        let ends = status.ends(end).load(Ordering::SeqCst);
+        let after = self.sent;
+        poll_fn(move |cx| {
+            if status.has_ended(end, ends, after) {
+                return Poll::Ready(());
+            }
+            status.register(cx.waker());
+            if status.has_ended(end, ends, after) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+
+    pub(crate) fn imf_duration(&self) -> Option<Duration> {
+        published_duration(&self.status.imf_duration)
+    }
+
+    pub(crate) fn imf_position(&self) -> Option<Duration> {
+        published_duration(&self.status.imf_position)
+    }
+
+    pub(crate) fn seek_imf(&mut self, position: Duration) -> Result<(), Error> {
+        self.send(Command::SeekImf(position))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_imf_tempo(&mut self, tempo: f32) -> Result<(), Error> {
+        self.send(Command::SetImfTempo(tempo))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_imf_transpose(&mut self, semitones: i8) -> Result<(), Error> {
+        self.send(Command::SetImfTranspose(semitones))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_music_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.send(Command::SetMusicVolume(volume))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_sfx_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.send(Command::SetSfxVolume(volume))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_master_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.send(Command::SetMasterVolume(volume))?;
+        Ok(())
+    }
+
+    /// Decides the priority against the last known state of the audio side.
+    pub(crate) fn play_adl(&mut self, sound: AdlSound) -> Result<bool, Error> {
+        if let Some(playing) = self.adl_priority()
+            && sound.priority < playing
+        {
+            return Ok(false);
+        }
+        let priority = sound.priority;
+        let seq = self.send(Command::PlayAdl(sound))?;
+        self.adl_priority = Some(Expected {
+            seq,
+            value: Some(priority),
+        });
+        Ok(true)
+    }
+
+    pub(crate) fn stop_adl(&mut self) -> Result<(), Error> {
+        let seq = self.send(Command::StopAdl)?;
+        self.adl_priority = Some(Expected { seq, value: None });
+        Ok(())
+    }
+
+    pub(crate) fn is_adl_playing(&self) -> bool {
+        self.adl_priority().is_some()
+    }
+
+    fn adl_priority(&self) -> Option<u16> {
+        self.current(self.adl_priority, || {
+            published_priority(&self.status.adl_priority)
+        })
+    }
+
+    pub(crate) fn play_pc(&mut self, sound: PcSound) -> Result<bool, Error> {
+        if let Some(playing) = self.pc_priority()
+            && sound.priority < playing
+        {
+            return Ok(false);
+        }
+        let priority = sound.priority;
+        let seq = self.send(Command::PlayPc(sound))?;
+        self.pc_priority = Some(Expected {
+            seq,
+            value: Some(priority),
+        });
+        Ok(true)
+    }
+
+    pub(crate) fn stop_pc(&mut self) -> Result<(), Error> {
+        let seq = self.send(Command::StopPc)?;
+        self.pc_priority = Some(Expected { seq, value: None });
+        Ok(())
+    }
+
+    pub(crate) fn is_pc_playing(&self) -> bool {
+        self.pc_priority().is_some()
+    }
+
+    fn pc_priority(&self) -> Option<u16> {
+        self.current(self.pc_priority, || {
+            published_priority(&self.status.pc_priority)
+        })
+    }
+
+    pub(crate) fn play_digi(&mut self, voice: usize, sound: DigiSound) -> Result<(), Error> {
+        if voice >= DIGI_VOICES {
+            return Err(Error::OutOfRange {
+                kind: "digi voice",
+                index: voice,
+            });
+        }
+        let seq = self.send(Command::PlayDigi(voice, sound))?;
+        self.digi_playing[voice] = Some(Expected { seq, value: true });
+        Ok(())
+    }
+
+    pub(crate) fn stop_digi(&mut self, voice: usize) -> Result<(), Error> {
+        let seq = self.send(Command::StopDigi(voice))?;
+        if let Some(expected) = self.digi_playing.get_mut(voice) {
+            *expected = Some(Expected { seq, value: false });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_digi_playing(&self, voice: usize) -> bool {
+        let Some(&expected) = self.digi_playing.get(voice) else {
+            return false;
+        };
+        self.current(expected, || {
+            self.status.digi_playing[voice].load(Ordering::Relaxed)
+        })
+    }
+
+    pub(crate) fn set_digi_volume(&mut self, voice: usize, volume: f32) -> Result<(), Error> {
+        self.send(Command::SetDigiVolume(voice, volume))?;
+        Ok(())
+    }
+
+    pub(crate) fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), Error> {
+        self.send(Command::WriteReg(reg, val))?;
+        Ok(())
+    }
+
+    /// Sample clock at the end of the last rendered block.
+    pub(crate) fn sample_clock(&self) -> u64 {
+        self.status.sample_clock.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn write_reg_at(&mut self, reg: u32, val: u8, frame: u64) -> Result<(), Error> {
+        self.send(Command::WriteRegAt(frame, reg, val))?;
+        Ok(())
+    }
+
+    #[cfg(any(feature = "sdl", test))]
+    pub(crate) fn write_reg_at_instant(
+        &mut self,
+        reg: u32,
+        val: u8,
+        at: Instant,
+    ) -> Result<(), Error> {
+        let frame = self.frame_at(at);
+        self.write_reg_at(reg, val, frame)
+    }
+
+    // The frame that is rendered one buffer after the host time `at`, measured
+    // from the start of the last block.
+    #[cfg(any(feature = "sdl", test))]
+    fn frame_at(&self, at: Instant) -> u64 {
+        let Some((nanos, clock)) = self.status.last_block() else {
+            return self.sample_clock();
+        };
+        let instant = self.epoch + Duration::from_nanos(nanos);
+        let base = clock + self.buffer_frames;
+        if at >= instant {
+            base + duration_frames(at - instant, self.mixer_rate)
+        } else {
+            base.saturating_sub(duration_frames(instant - at, self.mixer_rate))
+        }
+    }
+}
+
+fn published_priority(priority: &AtomicU32) -> Option<u16> {
+    match priority.load(Ordering::Relaxed) {
+        NO_PRIORITY => None,
+        priority => Some(priority as u16),
+    }
+}
+
+fn published_duration(duration: &AtomicU64) -> Option<Duration> {
+    match duration.load(Ordering::Relaxed) {
+        NO_DURATION => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+#[cfg(any(feature = "sdl", test))]
+fn duration_frames(duration: Duration, mixer_rate: u32) -> u64 {
+    ((duration.as_nanos() * mixer_rate as u128) / 1_000_000_000) as u64
+}
+
+pub(crate) struct Processor {
+    sequencer: Sequencer,
+    commands: Consumer<Command>,
+    returns: Producer<Returned>,
+    // the wakers being woken, swapped with the registered ones
+    woken: Vec<Waker>,
+    status: Arc<Status>,
+    epoch: Instant,
+    channels: usize,
+    applied: u64,
+    imf_stopped: bool,
+    // the wakers were locked when an end was counted
+    wake_pending: bool,
+    on_imf_end: Option<Box<dyn FnMut() + Send>>,
+}
+
+impl Processor {
+    #[cfg(any(feature = "rodio", test))]
+    pub(crate) fn is_closed(&self) -> bool {
+        self.status.closed.load(Ordering::Acquire)
+    }
+
+    /// Applies the pending commands and renders the next block.
+    pub(crate) fn render<S: Sample>(&mut self, out: &mut [S]) {
+        let nanos = self.epoch.elapsed().as_nanos() as u64;
+        self.apply_commands();
+        self.status
+            .publish_block(nanos, self.sequencer.sample_clock());
+
+        let imf_playing = self.sequencer.is_imf_playing();
+        self.sequencer.generate_channels(out, self.channels);
+        self.return_released();
+        if imf_playing
+            && !self.sequencer.is_imf_playing()
+            && let Some(on_end) = self.on_imf_end.as_mut()
+        {
+            on_end();
+        }
+        self.publish_status();
+    }
+
+    fn return_released(&mut self) {
+        while let Some(released) = self.sequencer.take_released() {
+            self.return_to_control(Returned::Released(released));
+        }
+    }
+
+    fn return_to_control(&mut self, returned: Returned) {
+        // only dropped here if the control side stopped sending commands
+        let _ = self.returns.push(returned);
+    }
+
+    fn apply_commands(&mut self) {
+        while let Some(command) = self.commands.pop() {
+            let sequencer = &mut self.sequencer;
+            match command {
+                Command::PlayImf(data, options) => {
+                    if sequencer.is_imf_playing() && !self.imf_stopped {
+                        // the replaced track counts as ended
+                        self.status.imf_ends.fetch_add(1, Ordering::SeqCst);
+                        self.wake_pending = true;
+                    }
+                    sequencer.play_imf(data, options);
+                    self.imf_stopped = false;
+                }
+                Command::StopImf => {
+                    sequencer.stop_imf();
+                    self.imf_stopped = true;
+                }
+                Command::PauseImf => sequencer.pause_imf(),
+                Command::ResumeImf => sequencer.resume_imf(),
+                Command::SeekImf(position) => sequencer.seek_imf(position),
+                Command::SetImfTempo(tempo) => sequencer.set_imf_tempo(tempo),
+                Command::SetImfTranspose(semitones) => sequencer.set_imf_transpose(semitones),
+                Command::SetMusicVolume(volume) => sequencer.set_music_volume(volume),
+                Command::SetSfxVolume(volume) => sequencer.set_sfx_volume(volume),
+                Command::SetMasterVolume(volume) => sequencer.set_master_volume(volume),
+                Command::PlayAdl(sound) => {
+                    sequencer.play_adl(sound);
+                }
+                Command::StopAdl => sequencer.stop_adl(),
+                Command::PlayPc(sound) => {
+                    sequencer.play_pc(sound);
+                }
+                Command::StopPc => sequencer.stop_pc(),
+                Command::PlayDigi(voice, sound) => {
+                    sequencer.play_digi(voice, sound);
+                }
+                Command::StopDigi(voice) => sequencer.stop_digi(voice),
+                Command::SetDigiVolume(voice, volume) => sequencer.set_digi_volume(voice, volume),
+                Command::WriteReg(reg, val) => sequencer.write_reg(reg, val),
+                Command::WriteRegAt(frame, reg, val) => sequencer.queue_write(frame, reg, val),
+                Command::OnImfEnd(on_end) => {
+                    if let Some(replaced) = self.on_imf_end.replace(on_end) {
+                        self.return_to_control(Returned::OnImfEnd(replaced));
+                    }
+                }
+            }
+            self.return_released();
+            self.applied += 1;
+        }
+    }
+
+    fn publish_status(&mut self) {
+        let status = &*self.status;
+        let sequencer = &self.sequencer;
+        let relaxed = Ordering::Relaxed;
+        let adl_playing = sequencer.is_adl_playing();
+        let imf_active = sequencer.is_imf_playing() && !self.imf_stopped;
+        let adl_ended = status.adl_priority.load(relaxed) != NO_PRIORITY && !adl_playing;
+        let imf_ended = status.imf_active.load(relaxed) && !imf_active;
+        status.imf_active.store(imf_active, relaxed);
+        status
+            .imf_playing
+            .store(sequencer.is_imf_playing(), relaxed);
+        status.imf_paused.store(sequencer.is_imf_paused(), relaxed);
+        let priority = |priority: Option<u16>| priority.map_or(NO_PRIORITY, u32::from);
+        status
+            .adl_priority
+            .store(priority(sequencer.adl_priority()), relaxed);
+        status
+            .pc_priority
+            .store(priority(sequencer.pc_priority()), relaxed);
+        for (voice, playing) in status.digi_playing.iter().enumerate() {
+            playing.store(sequencer.is_digi_playing(voice), relaxed);
+        }
+        let nanos = |duration: Option<Duration>| {
+            duration.map_or(NO_DURATION, |duration| duration.as_nanos() as u64)
+        };
+        status
+            .imf_duration
+            .store(nanos(sequencer.imf_duration()), relaxed);
+        status
+            .imf_position
+            .store(nanos(sequencer.imf_position()), relaxed);
+        status.sample_clock.store(sequencer.sample_clock(), relaxed);
+        status.applied.store(self.applied, Ordering::Release);
+        if adl_ended {
+            status.adl_ends.fetch_add(1, Ordering::SeqCst);
+        }
+        if imf_ended {
+            status.imf_ends.fetch_add(1, Ordering::SeqCst);
+        }
+        if adl_ended || imf_ended || self.wake_pending {
+            self.wake_pending = !status.wake_all(&mut self.woken);
+        }
+    }
+}
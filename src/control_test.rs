@@ -0,0 +1,346 @@
+use std::iter;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Error;
+use crate::chip::AdlSound;
+use crate::control::{self, Controller, Processor, Returned};
+use crate::digi::{DIGI_VOICES, DigiSound};
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{ImfOptions, LoopMode, Released, Sequencer};
+
+const TEST_RATE: u32 = 44100;
+const TEST_FRAMES: usize = 441;
+
+fn test_channel(capacity: usize) -> (Controller, Processor) {
+    control::channel(
+        Sequencer::new(TEST_RATE, 100, 25).unwrap(),
+        TEST_RATE,
+        1,
+        TEST_FRAMES as u64,
+        capacity,
+    )
+}
+
+fn render_blocks(processor: &mut Processor, blocks: usize) {
+    let mut buf = [0i16; TEST_FRAMES];
+    for _ in 0..blocks {
+        processor.render(&mut buf);
+    }
+}
+
+fn test_sound(priority: u16) -> AdlSound {
+    let mut sound =
+        AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    sound.priority = priority;
+    sound
+}
+
+#[test]
+fn test_status_before_and_after_render() {
+    let (mut controller, mut processor) = test_channel(16);
+    assert!(!controller.is_adl_playing());
+
+    // the state is expected before the audio side applied the command
+    assert!(controller.play_adl(test_sound(10)).expect("play adl"));
+    assert!(controller.is_adl_playing());
+    assert!(!controller.play_adl(test_sound(9)).expect("play adl"));
+    render_blocks(&mut processor, 1);
+    assert!(controller.is_adl_playing());
+    assert_eq!(controller.sample_clock(), TEST_FRAMES as u64);
+
+    controller.stop_adl().expect("stop adl");
+    assert!(!controller.is_adl_playing());
+    assert!(controller.play_adl(test_sound(0)).expect("play adl"));
+    render_blocks(&mut processor, 1);
+    assert!(controller.is_adl_playing());
+
+    // ended on the audio side
+    render_blocks(&mut processor, 300);
+    assert!(!controller.is_adl_playing());
+
+    assert!(
+        controller
+            .play_pc(PcSound::new(5, vec![10; 5]))
+            .expect("play pc")
+    );
+    assert!(controller.is_pc_playing());
+    render_blocks(&mut processor, 100);
+    assert!(!controller.is_pc_playing());
+
+    let sound = DigiSound::new(TEST_RATE, vec![0xff; 441]);
+    controller.play_digi(2, sound.clone()).expect("play digi");
+    assert!(controller.is_digi_playing(2));
+    assert!(matches!(
+        controller.play_digi(DIGI_VOICES, sound),
+        Err(Error::OutOfRange { .. })
+    ));
+    render_blocks(&mut processor, 2);
+    assert!(!controller.is_digi_playing(2));
+}
+
+#[test]
+fn test_imf_status_and_end() {
+    let (mut controller, mut processor) = test_channel(16);
+    let ended = Arc::new(AtomicUsize::new(0));
+    let counter = ended.clone();
+    controller
+        .on_imf_end(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }))
+        .expect("on imf end");
+
+    // key on and off a note, 1 second in total
+    let track = vec![
+        0xa0, 0x44, 0, 0, 0xb0, 0x32, 0x32, 0, 0xb0, 0x12, 0x32, 0, 0, 0, 0, 0,
+    ];
+    let options = ImfOptions {
+        loop_mode: LoopMode::Once,
+        fade_out_ms: 0,
+    };
+    controller.play_imf(track, options).expect("play imf");
+    assert!(controller.is_imf_playing());
+    assert_eq!(controller.imf_duration(), None);
+    controller.pause_imf().expect("pause imf");
+    assert!(controller.is_imf_paused());
+    render_blocks(&mut processor, 1);
+    assert!(controller.is_imf_paused());
+    assert!(controller.imf_duration().is_some());
+
+    controller.resume_imf().expect("resume imf");
+    assert!(!controller.is_imf_paused());
+    render_blocks(&mut processor, 200);
+    assert!(!controller.is_imf_playing());
+    assert_eq!(ended.load(Ordering::SeqCst), 1);
+}
+
+// takes what the audio side returned, the control side frees it before the next command
+fn take_returned(controller: &mut Controller) -> Vec<Returned> {
+    iter::from_fn(|| controller.returned.pop()).collect()
+}
+
+#[test]
+fn test_payloads_returned() {
+    let (mut controller, mut processor) = test_channel(16);
+    let track = vec![0xa0, 0x44, 0x10, 0x00];
+    let digi = DigiSound::new(TEST_RATE, vec![0xff; 4410]);
+    controller
+        .play_imf(track.clone(), ImfOptions::default())
+        .expect("play imf");
+    assert!(controller.play_adl(test_sound(1)).expect("play adl"));
+    controller.play_digi(0, digi.clone()).expect("play digi");
+    controller.on_imf_end(Box::new(|| {})).expect("on imf end");
+    render_blocks(&mut processor, 1);
+    assert!(take_returned(&mut controller).is_empty());
+
+    // the replaced payloads come back in the order of the commands
+    controller
+        .play_imf(
+            vec![0xa0, 0x22, 0x10, 0x00],
+            ImfOptions {
+                loop_mode: LoopMode::Once,
+                fade_out_ms: 0,
+            },
+        )
+        .expect("play imf");
+    assert!(controller.play_adl(test_sound(2)).expect("play adl"));
+    controller
+        .play_digi(0, DigiSound::new(TEST_RATE, vec![0x80; 4410]))
+        .expect("play digi");
+    controller.on_imf_end(Box::new(|| {})).expect("on imf end");
+    render_blocks(&mut processor, 1);
+    let returned = take_returned(&mut controller);
+    assert_eq!(returned.len(), 4);
+    assert!(matches!(&returned[0], Returned::Released(Released::Imf(data)) if *data == track));
+    assert!(
+        matches!(&returned[1], Returned::Released(Released::Adl(sound)) if sound.priority == 1)
+    );
+    assert!(matches!(&returned[2], Returned::Released(Released::Digi(sound)) if *sound == digi));
+    assert!(matches!(&returned[3], Returned::OnImfEnd(_)));
+
+    // and those played to the end
+    render_blocks(&mut processor, 50);
+    assert!(!controller.is_imf_playing());
+    assert!(!controller.is_adl_playing());
+    assert!(!controller.is_digi_playing(0));
+    let returned = take_returned(&mut controller);
+    assert_eq!(returned.len(), 3);
+    for kind in [
+        |r: &Returned| matches!(r, Returned::Released(Released::Imf(_))),
+        |r: &Returned| matches!(r, Returned::Released(Released::Adl(_))),
+        |r: &Returned| matches!(r, Returned::Released(Released::Digi(_))),
+    ] {
+        assert!(returned.iter().any(kind));
+    }
+}
+
+#[test]
+fn test_queue_full() {
+    let (mut controller, mut processor) = test_channel(4);
+    for reg in 0..4 {
+        controller.write_reg(0x20 + reg, 1).expect("write reg");
+    }
+    assert!(matches!(
+        controller.write_reg(0x24, 1),
+        Err(Error::QueueFull)
+    ));
+    render_blocks(&mut processor, 1);
+    controller.write_reg(0x24, 1).expect("write reg");
+}
+
+#[test]
+fn test_write_reg_at_instant() {
+    let (mut controller, mut processor) = test_channel(16);
+    let start = Instant::now();
+    render_blocks(&mut processor, 1);
+
+    // one buffer of latency after the start of the last block
+    let frame = controller.frame_at(start + Duration::from_millis(100));
+    let expected = TEST_FRAMES as u64 + 4410;
+    assert!(
+        (expected - 441..=expected).contains(&frame),
+        "frame {frame}"
+    );
+    controller
+        .write_reg_at_instant(0x20, 1, Instant::now())
+        .expect("write reg at instant");
+}
+
+#[test]
+fn test_render_on_other_thread() {
+    let (mut controller, mut processor) = test_channel(1024);
+    let audio = thread::spawn(move || {
+        let mut buf = [0i16; TEST_FRAMES];
+        while !processor.is_closed() {
+            processor.render(&mut buf);
+            thread::yield_now();
+        }
+    });
+
+    for i in 0..10_000u32 {
+        while let Err(Error::QueueFull) = controller.write_reg(0x20 + i % 16, i as u8) {
+            thread::yield_now();
+        }
+    }
+    assert!(controller.play_adl(test_sound(1)).expect("play adl"));
+    assert!(controller.is_adl_playing());
+    controller.close();
+    audio.join().expect("audio thread");
+}
+
+// counts the wake-ups of a task
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_adl_finished() {
+    let (mut controller, mut processor) = test_channel(16);
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(counter.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // nothing playing
+    assert_eq!(
+        pin!(controller.adl_finished()).poll(&mut cx),
+        Poll::Ready(())
+    );
+
+    controller.play_adl(test_sound(1)).expect("play adl");
+    let mut finished = pin!(controller.adl_finished());
+    assert_eq!(finished.as_mut().poll(&mut cx), Poll::Pending);
+    render_blocks(&mut processor, 1);
+    assert_eq!(finished.as_mut().poll(&mut cx), Poll::Pending);
+    assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+    render_blocks(&mut processor, 300);
+    assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+    assert_eq!(finished.as_mut().poll(&mut cx), Poll::Ready(()));
+
+    // stopped, with a new sound started before the future is polled
+    controller.play_adl(test_sound(1)).expect("play adl");
+    render_blocks(&mut processor, 1);
+    let mut stopped = pin!(controller.adl_finished());
+    assert_eq!(stopped.as_mut().poll(&mut cx), Poll::Pending);
+    controller.stop_adl().expect("stop adl");
+    render_blocks(&mut processor, 1);
+    controller.play_adl(test_sound(1)).expect("play adl");
+    render_blocks(&mut processor, 1);
+    assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+    assert_eq!(stopped.as_mut().poll(&mut cx), Poll::Ready(()));
+}
+
+#[test]
+fn test_imf_finished() {
+    let (mut controller, mut processor) = test_channel(16);
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(counter.clone());
+    let mut cx = Context::from_waker(&waker);
+    let track = || vec![0xa0, 0x44, 0, 0, 0xb0, 0x32, 0x32, 0, 0, 0, 0, 0];
+
+    controller
+        .play_imf(track(), ImfOptions::default())
+        .expect("play imf");
+    let mut stopped = pin!(controller.imf_finished());
+    render_blocks(&mut processor, 10);
+    assert_eq!(stopped.as_mut().poll(&mut cx), Poll::Pending);
+    controller.stop_imf().expect("stop imf");
+    render_blocks(&mut processor, 1);
+    assert_eq!(stopped.as_mut().poll(&mut cx), Poll::Ready(()));
+    // the stopped track stays loaded but counts as finished
+    assert_eq!(
+        pin!(controller.imf_finished()).poll(&mut cx),
+        Poll::Ready(())
+    );
+
+    controller
+        .play_imf(track(), ImfOptions::default())
+        .expect("play imf");
+    let mut replaced = pin!(controller.imf_finished());
+    render_blocks(&mut processor, 1);
+    assert_eq!(replaced.as_mut().poll(&mut cx), Poll::Pending);
+    controller
+        .play_imf(track(), ImfOptions::default())
+        .expect("play imf");
+    render_blocks(&mut processor, 1);
+    assert_eq!(replaced.as_mut().poll(&mut cx), Poll::Ready(()));
+    assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_finished_on_other_thread() {
+    let (mut controller, mut processor) = test_channel(16);
+    controller.play_adl(test_sound(1)).expect("play adl");
+    let finished = controller.adl_finished();
+    let audio = thread::spawn(move || {
+        let mut buf = [0i16; TEST_FRAMES];
+        while !processor.is_closed() {
+            processor.render(&mut buf);
+        }
+    });
+
+    // a minimal executor that parks the thread until it is woken
+    struct Unpark(thread::Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut finished = pin!(finished);
+    while finished.as_mut().poll(&mut cx).is_pending() {
+        thread::park();
+    }
+    assert!(!controller.is_adl_playing());
+    controller.close();
+    audio.join().expect("audio thread");
+}
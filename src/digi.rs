@@ -0,0 +1,109 @@
+#[cfg(test)]
+#[path = "./digi_test.rs"]
+mod digi_test;
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// Number of digitized sounds that can play at the same time.
+pub const DIGI_VOICES: usize = 4;
+
+const VOLUME_ONE: i32 = 256;
+const MAX_VOLUME: f32 = 4.0;
+const POS_ONE: u64 = 1 << 16;
+// scales a signed 8 bit sample to the level of the chip output
+const SAMPLE_SCALE: i32 = 64;
+
+/// A digitized sound, 8 bit unsigned mono PCM (as stored in the VSWAP
+/// file of Wolfenstein 3D with a rate of 7042 Hz).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigiSound {
+    pub rate: u32,
+    pub data: Vec<u8>,
+}
+
+impl DigiSound {
+    pub fn new(rate: u32, data: Vec<u8>) -> DigiSound {
+        DigiSound { rate, data }
+    }
+}
+
+/// Plays a `DigiSound` at the mixer rate, resampled with linear interpolation.
+pub struct DigiVoice {
+    sound: Option<DigiSound>,
+    // played to the end, kept for `take_ended`
+    ended: Option<DigiSound>,
+    // position in the source data and step per output sample, 16.16 fixed point
+    pos: u64,
+    step: u64,
+    volume: i32,
+}
+
+impl Default for DigiVoice {
+    fn default() -> Self {
+        DigiVoice::new()
+    }
+}
+
+impl DigiVoice {
+    pub fn new() -> DigiVoice {
+        DigiVoice {
+            sound: None,
+            ended: None,
+            pos: 0,
+            step: POS_ONE,
+            volume: VOLUME_ONE,
+        }
+    }
+
+    /// Plays the sound (an empty sound stops the voice) and returns the sound that was playing.
+    pub fn play(&mut self, sound: DigiSound, mixer_rate: u32) -> Option<DigiSound> {
+        self.step = ((sound.rate as u64 * POS_ONE) / mixer_rate as u64).max(1);
+        self.pos = 0;
+        let playing = self.sound.take();
+        if !sound.data.is_empty() {
+            self.sound = Some(sound);
+        }
+        playing
+    }
+
+    /// Stops the voice and returns the sound that was playing.
+    pub fn stop(&mut self) -> Option<DigiSound> {
+        self.sound.take()
+    }
+
+    /// The sound that was played to the end since the last call.
+    pub fn take_ended(&mut self) -> Option<DigiSound> {
+        self.ended.take()
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.sound.is_some()
+    }
+
+    /// Sets the volume of the voice, 1.0 is the original level (up to 4.0). The
+    /// volume is kept when a new sound is played on the voice.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = (volume.clamp(0.0, MAX_VOLUME) * VOLUME_ONE as f32) as i32;
+    }
+
+    pub fn next_sample(&mut self) -> i32 {
+        let Some(sound) = &self.sound else {
+            return 0;
+        };
+
+        let ix = (self.pos >> 16) as usize;
+        if ix >= sound.data.len() {
+            self.ended = self.sound.take();
+            return 0;
+        }
+        let frac = (self.pos & (POS_ONE - 1)) as i32;
+        let s0 = sound.data[ix] as i32 - 128;
+        let s1 = sound.data.get(ix + 1).map_or(s0, |s| *s as i32 - 128);
+        let sample = s0 + (((s1 - s0) * frac) >> 16);
+        self.pos += self.step;
+
+        (sample * SAMPLE_SCALE * self.volume) >> 8
+    }
+}
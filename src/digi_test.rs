@@ -0,0 +1,18 @@
+use crate::digi::{DigiSound, DigiVoice};
+
+#[test]
+fn test_digi_volume_clamped() {
+    let sound = DigiSound::new(8000, vec![255, 0]);
+    let mut voice = DigiVoice::new();
+    voice.set_volume(1.0);
+    voice.play(sound.clone(), 8000);
+    let full = voice.next_sample();
+
+    voice.set_volume(1.0e9);
+    voice.play(sound.clone(), 8000);
+    assert_eq!(voice.next_sample(), full * 4);
+
+    voice.set_volume(-1.0);
+    voice.play(sound, 8000);
+    assert_eq!(voice.next_sample(), 0);
+}
@@ -0,0 +1,87 @@
+extern crate alloc;
+
+use alloc::string::String;
+
+#[cfg(feature = "chip")]
+use crate::chip::AdlError;
+#[cfg(feature = "chip")]
+use crate::pcspeaker::PcSoundError;
+
+/// Error of all public APIs of the crate.
+#[derive(Debug)]
+pub enum Error {
+    /// The audio device (SDL or the web audio worklet) failed.
+    Device(String),
+    /// The backend was used before `init()` was called.
+    NotInitialised,
+    /// Malformed music or game data.
+    Format(String),
+    #[cfg(feature = "chip")]
+    Adl(AdlError),
+    #[cfg(feature = "chip")]
+    PcSound(PcSoundError),
+    #[cfg(not(feature = "web-worklet"))]
+    Io(std::io::Error),
+    /// A chunk, sound, track or voice number that does not exist.
+    OutOfRange { kind: &'static str, index: usize },
+    /// Too many commands are waiting for the audio thread (is the device running?).
+    QueueFull,
+    /// A clock rate of 0 or one faster than the clock it is ticked from.
+    ClockRate { kind: &'static str, rate: u32 },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Device(msg) => write!(f, "audio device error: {}", msg),
+            Error::NotInitialised => write!(f, "OPL not initialised, did you call init()?"),
+            Error::Format(msg) => write!(f, "invalid data: {}", msg),
+            #[cfg(feature = "chip")]
+            Error::Adl(e) => write!(f, "{}", e),
+            #[cfg(feature = "chip")]
+            Error::PcSound(e) => write!(f, "{}", e),
+            #[cfg(not(feature = "web-worklet"))]
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::OutOfRange { kind, index } => write!(f, "{} {} out of range", kind, index),
+            Error::QueueFull => write!(f, "audio command queue full"),
+            Error::ClockRate { kind, rate } => {
+                write!(f, "{} clock rate {} out of range", kind, rate)
+            }
+        }
+    }
+}
+
+impl core::error::Error for Error {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "chip")]
+            Error::Adl(e) => Some(e),
+            #[cfg(feature = "chip")]
+            Error::PcSound(e) => Some(e),
+            #[cfg(not(feature = "web-worklet"))]
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "chip")]
+impl From<AdlError> for Error {
+    fn from(e: AdlError) -> Self {
+        Error::Adl(e)
+    }
+}
+
+#[cfg(feature = "chip")]
+impl From<PcSoundError> for Error {
+    fn from(e: PcSoundError) -> Self {
+        Error::PcSound(e)
+    }
+}
+
+#[cfg(not(feature = "web-worklet"))]
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,512 @@
+#[cfg(test)]
+#[path = "./headless_test.rs"]
+mod headless_test;
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::chip::AdlSound;
+use crate::digi::DigiSound;
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{ImfOptions, Sequencer, SfxChannelMode};
+use crate::{Backend, Error};
+
+const WAV_HEADER_LEN: u32 = 44;
+
+/// Receives the rendered audio of a headless `OPL`.
+pub trait AudioSink {
+    /// Called by `OPL::init` with the output format, before the first `write`.
+    fn open(&mut self, _mixer_rate: u32, _channels: u16) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Receives the next block of interleaved 16 bit samples.
+    fn write(&mut self, samples: &[i16]) -> Result<(), Error>;
+
+    /// Completes the output, called by `OPL::finish`.
+    fn finish(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Discards the audio, counts the rendered frames.
+#[derive(Default)]
+pub struct NullSink {
+    channels: u16,
+    frames: u64,
+}
+
+impl NullSink {
+    pub fn new() -> NullSink {
+        NullSink::default()
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl AudioSink for NullSink {
+    fn open(&mut self, _mixer_rate: u32, channels: u16) -> Result<(), Error> {
+        self.channels = channels;
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        self.frames += (samples.len() / self.channels.max(1) as usize) as u64;
+        Ok(())
+    }
+}
+
+/// Keeps the audio in memory.
+#[derive(Default)]
+pub struct MemorySink {
+    mixer_rate: u32,
+    channels: u16,
+    samples: Vec<i16>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    pub fn mixer_rate(&self) -> u32 {
+        self.mixer_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// The interleaved samples rendered so far.
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn into_samples(self) -> Vec<i16> {
+        self.samples
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl AudioSink for MemorySink {
+    fn open(&mut self, mixer_rate: u32, channels: u16) -> Result<(), Error> {
+        self.mixer_rate = mixer_rate;
+        self.channels = channels;
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        self.samples.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Writes the audio as raw signed 16 bit little endian PCM.
+pub struct RawSink<W: Write> {
+    writer: W,
+}
+
+impl RawSink<BufWriter<File>> {
+    pub fn create(path: &Path) -> Result<RawSink<BufWriter<File>>, Error> {
+        Ok(RawSink::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> RawSink<W> {
+    pub fn new(writer: W) -> RawSink<W> {
+        RawSink { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> AudioSink for RawSink<W> {
+    fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        write_samples(&mut self.writer, samples)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes the audio as a 16 bit PCM WAV file. The sizes in the header are
+/// filled in by `finish`.
+pub struct WavSink<W: Write + Seek> {
+    writer: W,
+    data_len: u32,
+}
+
+impl WavSink<BufWriter<File>> {
+    pub fn create(path: &Path) -> Result<WavSink<BufWriter<File>>, Error> {
+        Ok(WavSink::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(writer: W) -> WavSink<W> {
+        WavSink {
+            writer,
+            data_len: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn open(&mut self, mixer_rate: u32, channels: u16) -> Result<(), Error> {
+        let block_align = channels * 2;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(WAV_HEADER_LEN - 8).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&channels.to_le_bytes())?;
+        w.write_all(&mixer_rate.to_le_bytes())?;
+        w.write_all(&(mixer_rate * block_align as u32).to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?; // bits per sample
+        w.write_all(b"data")?;
+        w.write_all(&0u32.to_le_bytes())?;
+        self.data_len = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        write_samples(&mut self.writer, samples)?;
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        let w = &mut self.writer;
+        w.seek(SeekFrom::Start(4))?;
+        w.write_all(&(WAV_HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        w.seek(SeekFrom::Start(WAV_HEADER_LEN as u64 - 4))?;
+        w.write_all(&self.data_len.to_le_bytes())?;
+        w.seek(SeekFrom::End(0))?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+fn write_samples<W: Write>(writer: &mut W, samples: &[i16]) -> Result<(), Error> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+pub struct OPLSettings {
+    pub mixer_rate: u32,
+    pub imf_clock_rate: u32,
+    pub adl_clock_rate: u32,
+    /// Melodic channel (0-8) the ADL sound effects are played on.
+    pub adl_channel: u8,
+    /// How the music is kept off the effect channel.
+    pub sfx_channel_mode: SfxChannelMode,
+    /// Number of frames handed to the sink at once, 0 for 1024.
+    pub buffer_size: u16,
+    /// Number of output channels (all get the same signal), 0 for stereo.
+    pub channels: u8,
+}
+
+impl Default for OPLSettings {
+    fn default() -> Self {
+        OPLSettings {
+            mixer_rate: 44100,
+            imf_clock_rate: 0,
+            adl_clock_rate: 0,
+            adl_channel: 0,
+            sfx_channel_mode: SfxChannelMode::Shared,
+            buffer_size: 0,
+            channels: 0,
+        }
+    }
+}
+
+impl OPLSettings {
+    pub(crate) fn output_channels(&self) -> usize {
+        if self.channels != 0 {
+            self.channels as usize
+        } else {
+            2
+        }
+    }
+
+    pub(crate) fn block_frames(&self) -> usize {
+        if self.buffer_size != 0 {
+            self.buffer_size as usize
+        } else {
+            1024
+        }
+    }
+
+    pub(crate) fn new_sequencer(&self) -> Result<Sequencer, Error> {
+        let imf_clock_rate = if self.imf_clock_rate != 0 {
+            self.imf_clock_rate
+        } else {
+            560
+        };
+        let adl_clock_rate = if self.adl_clock_rate != 0 {
+            self.adl_clock_rate
+        } else {
+            140
+        };
+
+        let mut sequencer = Sequencer::new(self.mixer_rate, imf_clock_rate, adl_clock_rate)?;
+        sequencer.set_adl_channel(self.adl_channel);
+        sequencer.set_sfx_channel_mode(self.sfx_channel_mode);
+        Ok(sequencer)
+    }
+}
+
+struct Player {
+    sequencer: Sequencer,
+    mixer_rate: u32,
+    channels: usize,
+    buffer: Vec<i16>,
+    on_imf_end: Option<Box<dyn FnMut()>>,
+}
+
+/// An `OPL` without an audio device. It is controlled through `Backend` like the SDL backend,
+/// but the time only advances with `render`, which hands the audio to an `AudioSink`.
+pub struct OPL<S: AudioSink> {
+    sink: S,
+    player: Option<Player>,
+}
+
+impl<S: AudioSink> OPL<S> {
+    pub fn new(sink: S) -> OPL<S> {
+        OPL { sink, player: None }
+    }
+
+    pub fn init(&mut self, settings: OPLSettings) -> Result<(), Error> {
+        let channels = settings.output_channels();
+        let sequencer = settings.new_sequencer()?;
+        self.sink.open(settings.mixer_rate, channels as u16)?;
+        self.player = Some(Player {
+            sequencer,
+            mixer_rate: settings.mixer_rate,
+            channels,
+            buffer: vec![0; settings.block_frames() * channels],
+            on_imf_end: None,
+        });
+        Ok(())
+    }
+
+    /// Renders the given time of audio into the sink.
+    pub fn render(&mut self, duration: Duration) -> Result<(), Error> {
+        let mixer_rate = self.player()?.mixer_rate;
+        let frames = (duration.as_nanos() * mixer_rate as u128) / 1_000_000_000;
+        self.render_frames(frames as u64)
+    }
+
+    /// Renders the given number of frames into the sink, in blocks of `buffer_size`.
+    pub fn render_frames(&mut self, frames: u64) -> Result<(), Error> {
+        let player = self.player.as_mut().ok_or(Error::NotInitialised)?;
+        let mut left = frames;
+        while left > 0 {
+            let block_frames = left.min((player.buffer.len() / player.channels) as u64);
+            let block = &mut player.buffer[..block_frames as usize * player.channels];
+
+            let imf_playing = player.sequencer.is_imf_playing();
+            player.sequencer.generate_channels(block, player.channels);
+            self.sink.write(block)?;
+            if imf_playing
+                && !player.sequencer.is_imf_playing()
+                && let Some(on_end) = player.on_imf_end.as_mut()
+            {
+                on_end();
+            }
+            left -= block_frames;
+        }
+        Ok(())
+    }
+
+    /// Completes the output of the sink (e.g. writes the sizes of a WAV file).
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.sink.finish()
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Registers a function that is called each time an IMF track ended (which
+    /// never happens with `LoopMode::Forever`). It is called from `render`.
+    pub fn on_imf_end<F>(&mut self, on_end: F) -> Result<(), Error>
+    where
+        F: FnMut() + 'static,
+    {
+        self.player_mut()?.on_imf_end = Some(Box::new(on_end));
+        Ok(())
+    }
+
+    /// Number of frames rendered so far, the time base of `write_reg_at`.
+    pub fn sample_clock(&self) -> Result<u64, Error> {
+        Ok(self.player()?.sequencer.sample_clock())
+    }
+
+    /// Applies the register write right before the frame `frame` is rendered.
+    pub fn write_reg_at(&mut self, reg: u32, val: u8, frame: u64) -> Result<(), Error> {
+        self.sequencer()?.queue_write(frame, reg, val);
+        Ok(())
+    }
+
+    fn player(&self) -> Result<&Player, Error> {
+        self.player.as_ref().ok_or(Error::NotInitialised)
+    }
+
+    fn player_mut(&mut self) -> Result<&mut Player, Error> {
+        self.player.as_mut().ok_or(Error::NotInitialised)
+    }
+
+    fn sequencer(&mut self) -> Result<&mut Sequencer, Error> {
+        Ok(&mut self.player_mut()?.sequencer)
+    }
+}
+
+impl<S: AudioSink> Backend for OPL<S> {
+    fn play_imf_with_options(&mut self, data: Vec<u8>, options: ImfOptions) -> Result<(), Error> {
+        self.sequencer()?.play_imf(data, options);
+        Ok(())
+    }
+
+    fn stop_imf(&mut self) -> Result<(), Error> {
+        self.sequencer()?.stop_imf();
+        Ok(())
+    }
+
+    fn pause_imf(&mut self) -> Result<(), Error> {
+        self.sequencer()?.pause_imf();
+        Ok(())
+    }
+
+    fn resume_imf(&mut self) -> Result<(), Error> {
+        self.sequencer()?.resume_imf();
+        Ok(())
+    }
+
+    fn is_imf_paused(&self) -> Result<bool, Error> {
+        Ok(self.player()?.sequencer.is_imf_paused())
+    }
+
+    fn imf_duration(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.player()?.sequencer.imf_duration())
+    }
+
+    fn imf_position(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.player()?.sequencer.imf_position())
+    }
+
+    fn seek_imf(&mut self, position: Duration) -> Result<(), Error> {
+        self.sequencer()?.seek_imf(position);
+        Ok(())
+    }
+
+    fn set_imf_tempo(&mut self, tempo: f32) -> Result<(), Error> {
+        self.sequencer()?.set_imf_tempo(tempo);
+        Ok(())
+    }
+
+    fn set_imf_transpose(&mut self, semitones: i8) -> Result<(), Error> {
+        self.sequencer()?.set_imf_transpose(semitones);
+        Ok(())
+    }
+
+    fn set_music_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.sequencer()?.set_music_volume(volume);
+        Ok(())
+    }
+
+    fn set_sfx_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.sequencer()?.set_sfx_volume(volume);
+        Ok(())
+    }
+
+    fn set_master_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.sequencer()?.set_master_volume(volume);
+        Ok(())
+    }
+
+    fn play_adl(&mut self, sound: AdlSound) -> Result<bool, Error> {
+        Ok(self.sequencer()?.play_adl(sound))
+    }
+
+    fn stop_adl(&mut self) -> Result<(), Error> {
+        self.sequencer()?.stop_adl();
+        Ok(())
+    }
+
+    fn is_adl_playing(&self) -> Result<bool, Error> {
+        Ok(self.player()?.sequencer.is_adl_playing())
+    }
+
+    fn play_pc(&mut self, sound: PcSound) -> Result<bool, Error> {
+        Ok(self.sequencer()?.play_pc(sound))
+    }
+
+    fn stop_pc(&mut self) -> Result<(), Error> {
+        self.sequencer()?.stop_pc();
+        Ok(())
+    }
+
+    fn is_pc_playing(&self) -> Result<bool, Error> {
+        Ok(self.player()?.sequencer.is_pc_playing())
+    }
+
+    fn play_digi(&mut self, voice: usize, sound: DigiSound) -> Result<(), Error> {
+        if !self.sequencer()?.play_digi(voice, sound) {
+            return Err(Error::OutOfRange {
+                kind: "digi voice",
+                index: voice,
+            });
+        }
+        Ok(())
+    }
+
+    fn stop_digi(&mut self, voice: usize) -> Result<(), Error> {
+        self.sequencer()?.stop_digi(voice);
+        Ok(())
+    }
+
+    fn is_digi_playing(&self, voice: usize) -> Result<bool, Error> {
+        Ok(self.player()?.sequencer.is_digi_playing(voice))
+    }
+
+    fn set_digi_volume(&mut self, voice: usize, volume: f32) -> Result<(), Error> {
+        self.sequencer()?.set_digi_volume(voice, volume);
+        Ok(())
+    }
+
+    fn is_imf_playing(&self) -> Result<bool, Error> {
+        Ok(self.player()?.sequencer.is_imf_playing())
+    }
+
+    fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), Error> {
+        self.sequencer()?.write_reg(reg, val);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::cell::Cell;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::chip::AdlSound;
+use crate::headless::{MemorySink, NullSink, OPL, OPLSettings, WavSink};
+use crate::sequencer::{ImfOptions, LoopMode};
+use crate::{Backend, Error};
+
+// key on and off a note on channel 0, 1 second in total (at the default clock rate of 560 Hz)
+const TRACK: [u8; 16] = [
+    0xa0, 0x44, 0x00, 0x00, //
+    0xb0, 0x32, 0x18, 0x01, //
+    0xb0, 0x12, 0x18, 0x01, //
+    0x00, 0x00, 0x00, 0x00, //
+];
+
+#[test]
+fn test_not_initialised() {
+    let mut opl = OPL::new(NullSink::new());
+    assert!(matches!(
+        opl.play_imf(TRACK.to_vec()),
+        Err(Error::NotInitialised)
+    ));
+    assert!(matches!(
+        opl.render(Duration::from_secs(1)),
+        Err(Error::NotInitialised)
+    ));
+}
+
+#[test]
+fn test_invalid_clock_rate() {
+    let mut opl = OPL::new(NullSink::new());
+    let result = opl.init(OPLSettings {
+        mixer_rate: 22050,
+        imf_clock_rate: 44100,
+        ..Default::default()
+    });
+    assert!(matches!(
+        result,
+        Err(Error::ClockRate {
+            kind: "IMF",
+            rate: 44100
+        })
+    ));
+    assert!(matches!(
+        opl.play_imf(TRACK.to_vec()),
+        Err(Error::NotInitialised)
+    ));
+
+    let result = opl.init(OPLSettings {
+        adl_clock_rate: 1000,
+        ..Default::default()
+    });
+    assert!(matches!(
+        result,
+        Err(Error::ClockRate {
+            kind: "ADL",
+            rate: 1000
+        })
+    ));
+}
+
+#[test]
+fn test_render_to_memory() {
+    let mut opl = OPL::new(MemorySink::new());
+    opl.init(OPLSettings::default()).expect("init");
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    assert!(opl.play_adl(sound).expect("play adl"));
+
+    opl.render(Duration::from_secs(1)).expect("render");
+    let sink = opl.sink();
+    assert_eq!(sink.mixer_rate(), 44100);
+    assert_eq!(sink.channels(), 2);
+    assert_eq!(sink.samples().len(), 44100 * 2);
+    assert!(sink.samples().iter().any(|s| *s != 0));
+    assert!(!opl.is_adl_playing().expect("is playing"));
+}
+
+#[test]
+fn test_imf_end_callback() {
+    let mut opl = OPL::new(NullSink::new());
+    opl.init(OPLSettings {
+        channels: 4,
+        ..Default::default()
+    })
+    .expect("init");
+    let ended = Rc::new(Cell::new(0));
+    let ended_clone = ended.clone();
+    opl.on_imf_end(move || ended_clone.set(ended_clone.get() + 1))
+        .expect("on_imf_end");
+    opl.play_imf_with_options(
+        TRACK.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Once,
+            fade_out_ms: 0,
+        },
+    )
+    .expect("play imf");
+
+    opl.render(Duration::from_millis(500)).expect("render");
+    assert!(opl.is_imf_playing().expect("is playing"));
+    assert_eq!(ended.get(), 0);
+    opl.render(Duration::from_secs(1)).expect("render");
+    assert!(!opl.is_imf_playing().expect("is playing"));
+    assert_eq!(ended.get(), 1);
+    assert_eq!(opl.sink().frames(), 44100 * 3 / 2);
+}
+
+#[test]
+fn test_wav_sink() {
+    let mut opl = OPL::new(WavSink::new(Cursor::new(Vec::new())));
+    opl.init(OPLSettings {
+        mixer_rate: 22050,
+        channels: 1,
+        ..Default::default()
+    })
+    .expect("init");
+    opl.render_frames(100).expect("render");
+    opl.finish().expect("finish");
+
+    let wav = opl.into_sink().into_inner().into_inner();
+    assert_eq!(wav.len(), 44 + 200);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 200);
+    assert_eq!(u16::from_le_bytes(wav[22..24].try_into().unwrap()), 1);
+    assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 22050);
+    assert_eq!(&wav[36..40], b"data");
+    assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 200);
+}
@@ -0,0 +1,155 @@
+#[cfg(test)]
+#[path = "./imf_test.rs"]
+mod imf_test;
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::Error;
+
+const TAG_SIGNATURE: u8 = 0x1a;
+const PROGRAM_NAME_LEN: usize = 9;
+const WOLF3D_PADDING_LEN: usize = 2;
+
+/// The two IMF file variants in the wild.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImfType {
+    /// Headerless, the whole file is the command stream.
+    Type0,
+    /// The command stream is prefixed with its length in bytes (u16 LE).
+    Type1,
+}
+
+/// Optional tag footer after the command stream of a type-1 file
+/// (as written by Adam Nohejl's tools and others).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImfTags {
+    pub title: String,
+    pub composer: String,
+    pub remarks: String,
+    pub program: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImfFile {
+    pub imf_type: ImfType,
+    /// The bare command stream (reg, val, delay u16 LE), ready to be played.
+    pub data: Vec<u8>,
+    pub tags: Option<ImfTags>,
+}
+
+impl ImfFile {
+    /// Parses an IMF file and detects the type from the content.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ImfFile, Error> {
+        ImfFile::from_bytes_with_type(bytes, detect_type(bytes))
+    }
+
+    /// Parses an IMF file of a known type (e.g. a music chunk from an
+    /// AUDIOT file is always type-1).
+    pub fn from_bytes_with_type(bytes: &[u8], imf_type: ImfType) -> Result<ImfFile, Error> {
+        match imf_type {
+            ImfType::Type0 => Ok(ImfFile {
+                imf_type,
+                data: bytes[..command_len(bytes.len())].to_vec(),
+                tags: None,
+            }),
+            ImfType::Type1 => {
+                if bytes.len() < 2 {
+                    return Err(Error::Format("imf data too short for type-1 header".into()));
+                }
+                let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+                if len + 2 > bytes.len() {
+                    return Err(Error::Format("imf type-1 length exceeds data".into()));
+                }
+                Ok(ImfFile {
+                    imf_type,
+                    data: bytes[2..(2 + command_len(len))].to_vec(),
+                    tags: parse_tags(&bytes[(2 + len)..]),
+                })
+            }
+        }
+    }
+
+    /// Playback length of the track when played with the given music clock rate
+    /// (e.g. 700 Hz for Wolfenstein 3D), `None` for a clock rate of 0.
+    pub fn duration(&self, clock_rate: u32) -> Option<Duration> {
+        ticks_to_duration(duration_ticks(&self.data), clock_rate)
+    }
+}
+
+/// Playback length of a command stream in music clock ticks, the sum
+/// of all command delays (the delay of the last command is waited for before
+/// the track ends or loops).
+pub fn duration_ticks(data: &[u8]) -> u32 {
+    data.chunks_exact(4)
+        .map(|cmd| u16::from_le_bytes([cmd[2], cmd[3]]) as u32)
+        .sum()
+}
+
+/// `None` for a clock rate of 0.
+pub fn ticks_to_duration(ticks: u32, clock_rate: u32) -> Option<Duration> {
+    let nanos = (ticks as u128 * 1_000_000_000).checked_div(clock_rate as u128)?;
+    Some(Duration::from_nanos(nanos as u64))
+}
+
+/// Guesses the IMF type. A type-0 file usually starts with an all zero
+/// command, a type-1 file with a length that is a multiple of the command
+/// size and is followed by nothing but a known trailer: the tag footer or the
+/// two padding bytes of the Wolf3D music chunks.
+pub fn detect_type(bytes: &[u8]) -> ImfType {
+    if bytes.len() < 2 {
+        return ImfType::Type0;
+    }
+    let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    if len == 0 || !len.is_multiple_of(4) || len + 2 > bytes.len() {
+        return ImfType::Type0;
+    }
+    let trailer = &bytes[(2 + len)..];
+    if trailer.len() <= WOLF3D_PADDING_LEN || trailer[0] == TAG_SIGNATURE {
+        ImfType::Type1
+    } else {
+        ImfType::Type0
+    }
+}
+
+// only whole commands are played
+fn command_len(len: usize) -> usize {
+    len - (len % 4)
+}
+
+fn parse_tags(footer: &[u8]) -> Option<ImfTags> {
+    if footer.first() != Some(&TAG_SIGNATURE) {
+        return None;
+    }
+
+    let mut rest = &footer[1..];
+    let mut next_str = || {
+        let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        let s = latin1_to_string(&rest[..end]);
+        rest = &rest[(end + 1).min(rest.len())..];
+        s
+    };
+    let title = next_str();
+    let composer = next_str();
+    let remarks = next_str();
+
+    let program_bytes = &rest[..rest.len().min(PROGRAM_NAME_LEN)];
+    let program_end = program_bytes
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(program_bytes.len());
+
+    Some(ImfTags {
+        title,
+        composer,
+        remarks,
+        program: latin1_to_string(&program_bytes[..program_end]),
+    })
+}
+
+fn latin1_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
@@ -0,0 +1,104 @@
+use core::time::Duration;
+
+use crate::Error;
+use crate::imf::{ImfFile, ImfTags, ImfType, detect_type, duration_ticks};
+
+const COMMANDS: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0xb0, 0x20, 0x10, 0x00];
+
+#[test]
+fn test_detect_type_0() {
+    assert_eq!(detect_type(&COMMANDS), ImfType::Type0);
+
+    let imf = ImfFile::from_bytes(&COMMANDS).expect("parse imf");
+    assert_eq!(imf.imf_type, ImfType::Type0);
+    assert_eq!(imf.data, COMMANDS.to_vec());
+    assert_eq!(imf.tags, None);
+}
+
+#[test]
+fn test_detect_type_0_with_length_like_start() {
+    // the first command reads as a type-1 length of 4, but more than a trailer follows
+    let bytes = [
+        0x04, 0x00, 0x00, 0x00, //
+        0xb0, 0x20, 0x10, 0x00, //
+        0xa0, 0x44, 0x10, 0x00, //
+    ];
+    assert_eq!(detect_type(&bytes), ImfType::Type0);
+
+    let imf = ImfFile::from_bytes(&bytes).expect("parse imf");
+    assert_eq!(imf.data, bytes.to_vec());
+}
+
+#[test]
+fn test_detect_type_1() {
+    let mut bytes = vec![0x08, 0x00];
+    bytes.extend_from_slice(&COMMANDS);
+    // Wolf3D music chunks carry some extra bytes after the commands
+    bytes.extend_from_slice(&[0x00, 0x00]);
+
+    assert_eq!(detect_type(&bytes), ImfType::Type1);
+
+    let imf = ImfFile::from_bytes(&bytes).expect("parse imf");
+    assert_eq!(imf.imf_type, ImfType::Type1);
+    assert_eq!(imf.data, COMMANDS.to_vec());
+    assert_eq!(imf.tags, None);
+}
+
+#[test]
+fn test_type_1_tags() {
+    let mut bytes = vec![0x08, 0x00];
+    bytes.extend_from_slice(&COMMANDS);
+    bytes.push(0x1a);
+    bytes.extend_from_slice(b"Wondering\0Bobby Prince\0");
+    bytes.extend_from_slice(&[b'S', 0xe4, b't', b'z', b'e', 0]); // Latin-1 a-umlaut
+    bytes.extend_from_slice(b"MUSE\0\0\0\0\0");
+
+    let imf = ImfFile::from_bytes(&bytes).expect("parse imf");
+    assert_eq!(imf.data, COMMANDS.to_vec());
+    assert_eq!(
+        imf.tags,
+        Some(ImfTags {
+            title: "Wondering".to_string(),
+            composer: "Bobby Prince".to_string(),
+            remarks: "Sätze".to_string(),
+            program: "MUSE".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_type_1_truncated_tags() {
+    let mut bytes = vec![0x08, 0x00];
+    bytes.extend_from_slice(&COMMANDS);
+    bytes.push(0x1a);
+    bytes.extend_from_slice(b"Title");
+
+    let tags = ImfFile::from_bytes(&bytes)
+        .expect("parse imf")
+        .tags
+        .expect("tags");
+    assert_eq!(tags.title, "Title");
+    assert_eq!(tags.composer, "");
+    assert_eq!(tags.program, "");
+}
+
+#[test]
+fn test_type_1_length_exceeds_data() {
+    let mut bytes = vec![0x10, 0x00];
+    bytes.extend_from_slice(&COMMANDS);
+
+    assert_eq!(detect_type(&bytes), ImfType::Type0);
+    assert!(matches!(
+        ImfFile::from_bytes_with_type(&bytes, ImfType::Type1),
+        Err(Error::Format(_))
+    ));
+}
+
+#[test]
+fn test_duration() {
+    let imf = ImfFile::from_bytes(&COMMANDS).expect("parse imf");
+    assert_eq!(duration_ticks(&imf.data), 16);
+    assert_eq!(imf.duration(700), Some(Duration::from_nanos(22_857_142)));
+    assert_eq!(imf.duration(16), Some(Duration::from_secs(1)));
+    assert_eq!(imf.duration(0), None);
+}
@@ -3,6 +3,27 @@
 #[cfg(feature = "chip")]
 pub mod chip;
 
+pub mod error;
+pub use error::Error;
+
+pub mod imf;
+
+#[cfg(feature = "chip")]
+pub mod digi;
+
+#[cfg(feature = "chip")]
+pub mod pcspeaker;
+
+#[cfg(feature = "chip")]
+pub mod sequencer;
+#[cfg(feature = "chip")]
+pub use sequencer::{ImfOptions, LoopMode, SfxChannelMode};
+
+#[cfg(all(feature = "chip", not(feature = "web-worklet")))]
+pub mod backend;
+#[cfg(all(feature = "chip", not(feature = "web-worklet")))]
+pub use backend::Backend;
+
 #[cfg(feature = "sdl")]
 pub mod sdl;
 #[cfg(feature = "sdl")]
@@ -13,8 +34,24 @@ pub mod web;
 #[cfg(feature = "web")]
 pub use web::{OPL, OPLSettings};
 
+#[cfg(feature = "headless")]
+pub mod headless;
+#[cfg(feature = "headless")]
+pub mod render;
+
+#[cfg(feature = "rodio")]
+pub mod rodio;
+
+#[cfg(any(feature = "sdl", feature = "rodio"))]
+mod control;
+#[cfg(any(feature = "sdl", feature = "rodio"))]
+mod spsc;
+
 #[cfg(feature = "web-worklet")]
 pub mod web_worklet;
 
 #[cfg(feature = "catalog")]
 pub mod catalog;
+
+#[cfg(all(feature = "chip", feature = "catalog", not(feature = "web-worklet")))]
+pub mod sound_manager;
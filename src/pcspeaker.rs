@@ -0,0 +1,145 @@
+#[cfg(test)]
+#[path = "./pcspeaker_test.rs"]
+mod pcspeaker_test;
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+pub const PC_HEADER_LEN: usize = 6;
+
+const PIT_CLOCK: u64 = 1_193_181;
+// id's sound manager programs the timer with the sample value times 60
+const PIT_DIVISOR_SCALE: u32 = 60;
+const PC_VOLUME: i32 = 1250;
+const PHASE_ONE: u64 = 1 << 16;
+
+/// A PC speaker sound effect. Each byte of `data` is played for one sound
+/// tick (140 Hz), 0 turns the speaker off.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcSound {
+    pub priority: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PcSoundError {
+    /// The data is shorter than the sound header.
+    TooShort { len: usize },
+    /// The header declares more sound data than there is.
+    LengthMismatch { declared: u32, available: usize },
+}
+
+impl core::fmt::Display for PcSoundError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PcSoundError::TooShort { len } => write!(f, "pc sound too short: {} bytes", len),
+            PcSoundError::LengthMismatch {
+                declared,
+                available,
+            } => write!(
+                f,
+                "pc sound length mismatch: {} bytes declared, {} available",
+                declared, available
+            ),
+        }
+    }
+}
+
+impl core::error::Error for PcSoundError {}
+
+impl TryFrom<&[u8]> for PcSound {
+    type Error = PcSoundError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        PcSound::from_bytes(data)
+    }
+}
+
+impl PcSound {
+    pub fn new(priority: u16, data: Vec<u8>) -> PcSound {
+        PcSound { priority, data }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<PcSound, PcSoundError> {
+        if data.len() < PC_HEADER_LEN {
+            return Err(PcSoundError::TooShort { len: data.len() });
+        }
+        let length = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        // the length is untrusted, a corrupted one must not overflow on 32 bit targets
+        let data_end = PC_HEADER_LEN
+            .checked_add(length as usize)
+            .filter(|end| *end <= data.len())
+            .ok_or(PcSoundError::LengthMismatch {
+                declared: length,
+                available: data.len() - PC_HEADER_LEN,
+            })?;
+        // the chunks in AUDIOT carry a terminator byte after the data, it is not needed
+        Ok(PcSound {
+            priority: u16::from_le_bytes(data[4..6].try_into().unwrap()),
+            data: data[PC_HEADER_LEN..data_end].to_vec(),
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PC_HEADER_LEN + self.data.len() + 1);
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.priority.to_le_bytes());
+        out.extend_from_slice(&self.data);
+        out.push(0);
+        out
+    }
+}
+
+/// Square wave generator emulating the PC speaker driven by the PIT.
+pub struct PcSpeaker {
+    mixer_rate: u32,
+    // length of a half wave in samples, 16.16 fixed point. 0 if the speaker is off.
+    half_period: u64,
+    phase: u64,
+    level: i32,
+}
+
+impl PcSpeaker {
+    pub fn new(mixer_rate: u32) -> PcSpeaker {
+        PcSpeaker {
+            mixer_rate,
+            half_period: 0,
+            phase: 0,
+            level: PC_VOLUME,
+        }
+    }
+
+    /// Plays a sample value of a `PcSound`, 0 turns the speaker off.
+    pub fn play_sample(&mut self, sample: u8) {
+        if sample == 0 {
+            self.off();
+            return;
+        }
+        let divisor = (sample as u32 * PIT_DIVISOR_SCALE) as u64;
+        self.half_period =
+            ((divisor * self.mixer_rate as u64 * PHASE_ONE) / (2 * PIT_CLOCK)).max(PHASE_ONE);
+    }
+
+    pub fn off(&mut self) {
+        self.half_period = 0;
+        self.phase = 0;
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.half_period != 0
+    }
+
+    pub fn next_sample(&mut self) -> i32 {
+        if self.half_period == 0 {
+            return 0;
+        }
+        let sample = self.level;
+        self.phase += PHASE_ONE;
+        while self.phase >= self.half_period {
+            self.phase -= self.half_period;
+            self.level = -self.level;
+        }
+        sample
+    }
+}
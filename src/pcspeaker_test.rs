@@ -0,0 +1,49 @@
+use crate::pcspeaker::{PcSound, PcSoundError, PcSpeaker};
+
+#[test]
+fn test_pc_sound_parse() {
+    let bytes = [3, 0, 0, 0, 5, 0, 0x20, 0x00, 0x30, 0x00];
+    let sound = PcSound::from_bytes(&bytes).expect("parse pc sound");
+    assert_eq!(sound.priority, 5);
+    assert_eq!(sound.data, vec![0x20, 0x00, 0x30]);
+    assert_eq!(sound.to_vec(), bytes.to_vec());
+
+    assert_eq!(
+        PcSound::try_from(&bytes[..4]).unwrap_err(),
+        PcSoundError::TooShort { len: 4 }
+    );
+    assert_eq!(
+        PcSound::try_from(&bytes[..8]).unwrap_err(),
+        PcSoundError::LengthMismatch {
+            declared: 3,
+            available: 2
+        }
+    );
+
+    let mut overflow = bytes;
+    overflow[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        PcSound::try_from(&overflow[..]).unwrap_err(),
+        PcSoundError::LengthMismatch {
+            declared: u32::MAX,
+            available: 4
+        }
+    );
+}
+
+#[test]
+fn test_pc_speaker_square_wave() {
+    let mut speaker = PcSpeaker::new(44100);
+    assert_eq!(speaker.next_sample(), 0);
+
+    // divisor 600 => 1193181 / 600 = 1988.6 Hz
+    speaker.play_sample(10);
+    let samples: Vec<i32> = (0..44100).map(|_| speaker.next_sample()).collect();
+    assert!(samples.iter().all(|s| *s != 0));
+    let edges = samples.windows(2).filter(|w| w[0] != w[1]).count();
+    assert!((3975..=3979).contains(&edges), "edges = {}", edges);
+
+    speaker.play_sample(0);
+    assert!(!speaker.is_on());
+    assert_eq!(speaker.next_sample(), 0);
+}
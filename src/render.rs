@@ -0,0 +1,237 @@
+#[cfg(test)]
+#[path = "./render_test.rs"]
+mod render_test;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::chip::AdlSound;
+use crate::digi::{DIGI_VOICES, DigiSound};
+use crate::headless::{AudioSink, OPL, OPLSettings, WavSink};
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{ImfOptions, LoopMode, Sequencer};
+use crate::{Backend, Error};
+
+// the sources are checked for their end after each step
+const RENDER_STEP: Duration = Duration::from_millis(10);
+
+/// What to render.
+#[derive(Clone, Debug)]
+pub enum RenderSource {
+    /// A bare IMF command stream (see `imf::ImfFile`), played with the `ImfOptions`
+    /// of the settings.
+    Imf(Vec<u8>),
+    Adl(AdlSound),
+    Pc(PcSound),
+    Digi(DigiSound),
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub mixer_rate: u32,
+    /// Number of output channels (all get the same signal), 0 for stereo.
+    pub channels: u8,
+    /// Music clock rate of the IMF track, 0 for 560 Hz (700 Hz for Wolfenstein 3D).
+    pub imf_clock_rate: u32,
+    pub adl_clock_rate: u32,
+    /// Loop count and fade-out of an IMF track, played once by default.
+    pub imf_options: ImfOptions,
+    /// Upper bound of the rendered length (the end of a `LoopMode::Forever` track).
+    pub max_duration: Duration,
+    /// Silence rendered after the end of the source, for the release of the last notes.
+    pub tail: Duration,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            mixer_rate: 44100,
+            channels: 0,
+            imf_clock_rate: 0,
+            adl_clock_rate: 0,
+            imf_options: ImfOptions {
+                loop_mode: LoopMode::Once,
+                fade_out_ms: 0,
+            },
+            max_duration: Duration::from_secs(600),
+            tail: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Renders the source into the sink as fast as possible and returns the finished sink.
+pub fn render<S: AudioSink>(
+    source: RenderSource,
+    settings: &RenderSettings,
+    sink: S,
+) -> Result<S, Error> {
+    let mut opl = OPL::new(sink);
+    opl.init(OPLSettings {
+        mixer_rate: settings.mixer_rate,
+        imf_clock_rate: settings.imf_clock_rate,
+        adl_clock_rate: settings.adl_clock_rate,
+        channels: settings.channels,
+        ..Default::default()
+    })?;
+
+    match source {
+        RenderSource::Imf(data) => opl.play_imf_with_options(data, settings.imf_options)?,
+        RenderSource::Adl(sound) => {
+            opl.play_adl(sound)?;
+        }
+        RenderSource::Pc(sound) => {
+            opl.play_pc(sound)?;
+        }
+        RenderSource::Digi(sound) => opl.play_digi(0, sound)?,
+    }
+
+    let mut rendered = Duration::ZERO;
+    while rendered < settings.max_duration && is_playing(&opl)? {
+        let step = RENDER_STEP.min(settings.max_duration - rendered);
+        opl.render(step)?;
+        rendered += step;
+    }
+    opl.render(settings.tail)?;
+    opl.finish()?;
+    Ok(opl.into_sink())
+}
+
+/// Renders the source into a WAV file.
+pub fn render_wav<W: Write + Seek>(
+    source: RenderSource,
+    settings: &RenderSettings,
+    writer: W,
+) -> Result<W, Error> {
+    Ok(render(source, settings, WavSink::new(writer))?.into_inner())
+}
+
+pub fn render_wav_file(
+    source: RenderSource,
+    settings: &RenderSettings,
+    path: &Path,
+) -> Result<(), Error> {
+    let writer = BufWriter::new(File::create(path)?);
+    render_wav(source, settings, writer)?;
+    Ok(())
+}
+
+fn is_playing<S: AudioSink>(opl: &OPL<S>) -> Result<bool, Error> {
+    Ok(opl.is_imf_playing()?
+        || opl.is_adl_playing()?
+        || opl.is_pc_playing()?
+        || opl.is_digi_playing(0)?)
+}
+
+/// Streams the output of a `Sequencer` as interleaved 16 bit samples, through
+/// `Iterator` or as little endian bytes through `io::Read`. The sequencer is
+/// advanced on demand, in blocks of `buffer_size` frames. The stream is endless
+/// (silence while nothing plays) unless `set_end_when_idle` is set.
+/// With both traits in scope the shared adapters (`take`, `by_ref`, ...) have
+/// to be called qualified.
+pub struct Renderer {
+    sequencer: Sequencer,
+    mixer_rate: u32,
+    channels: usize,
+    buffer: Vec<i16>,
+    pos: usize,
+    // high byte of a sample whose low byte was the last one read
+    pending_byte: Option<u8>,
+    end_when_idle: bool,
+}
+
+impl Renderer {
+    pub fn new(settings: OPLSettings) -> Result<Renderer, Error> {
+        let channels = settings.output_channels();
+        let buffer = vec![0; settings.block_frames() * channels];
+        Ok(Renderer {
+            sequencer: settings.new_sequencer()?,
+            mixer_rate: settings.mixer_rate,
+            channels,
+            pos: buffer.len(),
+            buffer,
+            pending_byte: None,
+            end_when_idle: false,
+        })
+    }
+
+    /// The sequencer to control the playback with. Changes take effect with
+    /// the next rendered block.
+    pub fn sequencer(&mut self) -> &mut Sequencer {
+        &mut self.sequencer
+    }
+
+    pub fn mixer_rate(&self) -> u32 {
+        self.mixer_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Ends the stream once no track and no sound is playing (checked before each block).
+    pub fn set_end_when_idle(&mut self, end_when_idle: bool) {
+        self.end_when_idle = end_when_idle;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        !self.sequencer.is_imf_playing()
+            && !self.sequencer.is_adl_playing()
+            && !self.sequencer.is_pc_playing()
+            && !(0..DIGI_VOICES).any(|voice| self.sequencer.is_digi_playing(voice))
+    }
+
+    // renders the next block if the current one is used up, false at the end of the stream
+    fn fill(&mut self) -> bool {
+        if self.pos < self.buffer.len() {
+            return true;
+        }
+        if self.end_when_idle && self.is_idle() {
+            return false;
+        }
+        self.sequencer
+            .generate_channels(&mut self.buffer, self.channels);
+        self.pos = 0;
+        true
+    }
+}
+
+impl Iterator for Renderer {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        self.pending_byte = None;
+        if !self.fill() {
+            return None;
+        }
+        let sample = self.buffer[self.pos];
+        self.pos += 1;
+        Some(sample)
+    }
+}
+
+impl Read for Renderer {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        if let Some(byte) = self.pending_byte.take()
+            && !out.is_empty()
+        {
+            out[0] = byte;
+            n = 1;
+        }
+        while n < out.len() && self.fill() {
+            let [low, high] = self.buffer[self.pos].to_le_bytes();
+            self.pos += 1;
+            out[n] = low;
+            if n + 1 < out.len() {
+                out[n + 1] = high;
+                n += 2;
+            } else {
+                self.pending_byte = Some(high);
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{Cursor, Read};
+use std::time::Duration;
+
+use crate::chip::AdlSound;
+use crate::headless::{MemorySink, OPLSettings};
+use crate::render::{RenderSettings, RenderSource, Renderer, render, render_wav};
+use crate::sequencer::{ImfOptions, LoopMode, Sequencer};
+
+// key on and off a note on channel 0, 1 second in total at 560 Hz
+const TRACK: [u8; 16] = [
+    0xa0, 0x44, 0x00, 0x00, //
+    0xb0, 0x32, 0x18, 0x01, //
+    0xb0, 0x12, 0x18, 0x01, //
+    0x00, 0x00, 0x00, 0x00, //
+];
+
+fn rendered_secs(sink: &MemorySink) -> f64 {
+    sink.samples().len() as f64 / (sink.mixer_rate() as f64 * sink.channels() as f64)
+}
+
+#[test]
+fn test_render_imf_loops() {
+    let settings = RenderSettings {
+        imf_options: ImfOptions {
+            loop_mode: LoopMode::Count(2),
+            fade_out_ms: 0,
+        },
+        tail: Duration::ZERO,
+        ..Default::default()
+    };
+    let sink = render(
+        RenderSource::Imf(TRACK.to_vec()),
+        &settings,
+        MemorySink::new(),
+    )
+    .expect("render");
+    let secs = rendered_secs(&sink);
+    assert!((1.95..2.05).contains(&secs), "rendered {}s", secs);
+
+    // the track is played once by default
+    let settings = RenderSettings {
+        tail: Duration::ZERO,
+        ..Default::default()
+    };
+    let sink = render(
+        RenderSource::Imf(TRACK.to_vec()),
+        &settings,
+        MemorySink::new(),
+    )
+    .expect("render");
+    let secs = rendered_secs(&sink);
+    assert!((0.95..1.05).contains(&secs), "rendered {}s", secs);
+
+    let settings = RenderSettings {
+        channels: 1,
+        imf_options: ImfOptions::default(),
+        max_duration: Duration::from_secs(3),
+        tail: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let sink = render(
+        RenderSource::Imf(TRACK.to_vec()),
+        &settings,
+        MemorySink::new(),
+    )
+    .expect("render");
+    assert_eq!(sink.samples().len(), 44100 * 4);
+}
+
+#[test]
+fn test_render_adl_wav() {
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    let settings = RenderSettings {
+        mixer_rate: 22050,
+        ..Default::default()
+    };
+    let wav = render_wav(RenderSource::Adl(sound), &settings, Cursor::new(Vec::new()))
+        .expect("render")
+        .into_inner();
+
+    let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize;
+    assert_eq!(wav.len(), 44 + data_len);
+    assert!(data_len > 22050 * 4 / 2, "sound + tail shorter than 0.5s");
+    assert!(wav[44..].iter().any(|b| *b != 0));
+}
+
+#[test]
+fn test_renderer_stream() {
+    let mut renderer = Renderer::new(OPLSettings {
+        buffer_size: 100,
+        ..Default::default()
+    })
+    .expect("renderer");
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    renderer
+        .sequencer()
+        .play_imf(TRACK.to_vec(), ImfOptions::default());
+    renderer.sequencer().play_adl(sound.clone());
+    let samples: Vec<i16> = (0..44100).map_while(|_| renderer.next()).collect();
+
+    // the same as driving the sequencer directly (clock rate 560 Hz)
+    let mut sequencer = Sequencer::new(44100, 560, 140).unwrap();
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    sequencer.play_adl(sound);
+    let mut expected = vec![0i16; 44100 + 4];
+    sequencer.generate(&mut expected);
+    assert_eq!(samples, expected[..44100]);
+    assert!(samples.iter().any(|s| *s != 0));
+
+    // odd reads continue in the middle of a sample
+    let mut bytes = [0u8; 5];
+    assert_eq!(renderer.read(&mut bytes).expect("read"), 5);
+    let mut rest = [0u8; 1];
+    assert_eq!(renderer.read(&mut rest).expect("read"), 1);
+    let read: Vec<i16> = bytes
+        .iter()
+        .chain(&rest)
+        .collect::<Vec<_>>()
+        .chunks(2)
+        .map(|b| i16::from_le_bytes([*b[0], *b[1]]))
+        .collect();
+    assert_eq!(read, expected[44100..44103]);
+}
+
+#[test]
+fn test_renderer_end_when_idle() {
+    let mut renderer = Renderer::new(OPLSettings::default()).expect("renderer");
+    renderer.set_end_when_idle(true);
+    assert_eq!(renderer.next(), None);
+
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    assert!(renderer.sequencer().play_adl(sound));
+    let mut bytes = Vec::new();
+    renderer.read_to_end(&mut bytes).expect("read");
+    assert!(!bytes.is_empty());
+    assert_eq!(bytes.len() % (1024 * 2 * 2), 0);
+    assert!(renderer.is_idle());
+}
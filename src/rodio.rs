@@ -0,0 +1,247 @@
+#[cfg(test)]
+#[path = "./rodio_test.rs"]
+mod rodio_test;
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use ::rodio::Source;
+use ::rodio::{ChannelCount, SampleRate};
+
+use crate::chip::AdlSound;
+use crate::control::{self, COMMAND_CAPACITY, Controller, Processor};
+use crate::digi::DigiSound;
+use crate::headless::OPLSettings;
+use crate::pcspeaker::PcSound;
+use crate::sequencer::ImfOptions;
+use crate::{Backend, Error};
+
+/// Creates a `rodio::Source` playing the OPL output and the handle to control it.
+/// The source is endless until `OPLHandle::close` is called.
+pub fn new(settings: OPLSettings) -> Result<(OPLSource, OPLHandle), Error> {
+    let channels = settings.output_channels();
+    let frames = settings.block_frames();
+    let (controller, processor) = control::channel(
+        settings.new_sequencer()?,
+        settings.mixer_rate,
+        channels,
+        frames as u64,
+        COMMAND_CAPACITY,
+    );
+    let buffer = vec![0.0; frames * channels];
+    let source = OPLSource {
+        processor,
+        mixer_rate: settings.mixer_rate,
+        channels,
+        pos: buffer.len(),
+        buffer,
+    };
+    let handle = OPLHandle {
+        controller: Arc::new(Mutex::new(controller)),
+    };
+    Ok((source, handle))
+}
+
+/// Renders the OPL output in blocks of `buffer_size` frames on the thread of the
+/// rodio mixer. The commands of the handle are applied before each block, the
+/// mixer never waits for the threads controlling the playback.
+pub struct OPLSource {
+    processor: Processor,
+    mixer_rate: u32,
+    channels: usize,
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Iterator for OPLSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pos == self.buffer.len() {
+            if self.processor.is_closed() {
+                return None;
+            }
+            self.processor.render(&mut self.buffer);
+            self.pos = 0;
+        }
+        let sample = self.buffer[self.pos];
+        self.pos += 1;
+        Some(sample)
+    }
+}
+
+impl Source for OPLSource {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels as ChannelCount
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.mixer_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Controls an `OPLSource` while it is played by rodio. The handle can be cloned
+/// and sent to other threads (the clones only wait for each other, never for
+/// the source). The status queries reflect the state after the last rendered
+/// block and the commands sent since then.
+#[derive(Clone)]
+pub struct OPLHandle {
+    controller: Arc<Mutex<Controller>>,
+}
+
+impl OPLHandle {
+    /// Ends the source, rodio drops it after the current block.
+    pub fn close(&self) {
+        self.controller().close();
+    }
+
+    /// Registers a function that is called each time an IMF track ended (which
+    /// never happens with `LoopMode::Forever`). The function is called from the audio
+    /// thread, it must not block.
+    pub fn on_imf_end<F>(&self, on_end: F) -> Result<(), Error>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.controller().on_imf_end(Box::new(on_end))
+    }
+
+    /// Resolves when the track ended (never with `LoopMode::Forever`), was stopped
+    /// or replaced, right away if none is playing. The rodio mixer wakes the task.
+    pub fn imf_finished(&self) -> Result<impl Future<Output = ()> + Send + use<>, Error> {
+        Ok(self.controller().imf_finished())
+    }
+
+    /// Resolves when the sound effect ended or was stopped, right away if none is
+    /// playing. A sound that replaces the playing one is waited for as well.
+    pub fn adl_finished(&self) -> Result<impl Future<Output = ()> + Send + use<>, Error> {
+        Ok(self.controller().adl_finished())
+    }
+
+    /// Number of frames rendered so far, the time base of `write_reg_at`.
+    /// rodio plays the frames with the latency of its output stream.
+    pub fn sample_clock(&self) -> Result<u64, Error> {
+        Ok(self.controller().sample_clock())
+    }
+
+    /// Applies the register write right before the frame `frame` is rendered,
+    /// independent of the block boundaries.
+    pub fn write_reg_at(&self, reg: u32, val: u8, frame: u64) -> Result<(), Error> {
+        self.controller().write_reg_at(reg, val, frame)
+    }
+
+    fn controller(&self) -> MutexGuard<'_, Controller> {
+        self.controller
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Backend for OPLHandle {
+    fn play_imf_with_options(&mut self, data: Vec<u8>, options: ImfOptions) -> Result<(), Error> {
+        self.controller().play_imf(data, options)
+    }
+
+    fn stop_imf(&mut self) -> Result<(), Error> {
+        self.controller().stop_imf()
+    }
+
+    fn pause_imf(&mut self) -> Result<(), Error> {
+        self.controller().pause_imf()
+    }
+
+    fn resume_imf(&mut self) -> Result<(), Error> {
+        self.controller().resume_imf()
+    }
+
+    fn is_imf_paused(&self) -> Result<bool, Error> {
+        Ok(self.controller().is_imf_paused())
+    }
+
+    fn imf_duration(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.controller().imf_duration())
+    }
+
+    fn imf_position(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.controller().imf_position())
+    }
+
+    fn seek_imf(&mut self, position: Duration) -> Result<(), Error> {
+        self.controller().seek_imf(position)
+    }
+
+    fn set_imf_tempo(&mut self, tempo: f32) -> Result<(), Error> {
+        self.controller().set_imf_tempo(tempo)
+    }
+
+    fn set_imf_transpose(&mut self, semitones: i8) -> Result<(), Error> {
+        self.controller().set_imf_transpose(semitones)
+    }
+
+    fn set_music_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.controller().set_music_volume(volume)
+    }
+
+    fn set_sfx_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.controller().set_sfx_volume(volume)
+    }
+
+    fn set_master_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.controller().set_master_volume(volume)
+    }
+
+    fn play_adl(&mut self, sound: AdlSound) -> Result<bool, Error> {
+        self.controller().play_adl(sound)
+    }
+
+    fn stop_adl(&mut self) -> Result<(), Error> {
+        self.controller().stop_adl()
+    }
+
+    fn is_adl_playing(&self) -> Result<bool, Error> {
+        Ok(self.controller().is_adl_playing())
+    }
+
+    fn play_pc(&mut self, sound: PcSound) -> Result<bool, Error> {
+        self.controller().play_pc(sound)
+    }
+
+    fn stop_pc(&mut self) -> Result<(), Error> {
+        self.controller().stop_pc()
+    }
+
+    fn is_pc_playing(&self) -> Result<bool, Error> {
+        Ok(self.controller().is_pc_playing())
+    }
+
+    fn play_digi(&mut self, voice: usize, sound: DigiSound) -> Result<(), Error> {
+        self.controller().play_digi(voice, sound)
+    }
+
+    fn stop_digi(&mut self, voice: usize) -> Result<(), Error> {
+        self.controller().stop_digi(voice)
+    }
+
+    fn is_digi_playing(&self, voice: usize) -> Result<bool, Error> {
+        Ok(self.controller().is_digi_playing(voice))
+    }
+
+    fn set_digi_volume(&mut self, voice: usize, volume: f32) -> Result<(), Error> {
+        self.controller().set_digi_volume(voice, volume)
+    }
+
+    fn is_imf_playing(&self) -> Result<bool, Error> {
+        Ok(self.controller().is_imf_playing())
+    }
+
+    fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), Error> {
+        self.controller().write_reg(reg, val)
+    }
+}
@@ -0,0 +1,30 @@
+use ::rodio::Source;
+
+use crate::Backend;
+use crate::chip::AdlSound;
+use crate::headless::OPLSettings;
+use crate::rodio;
+
+#[test]
+fn test_rodio_source() {
+    let (mut source, mut handle) = rodio::new(OPLSettings {
+        mixer_rate: 22050,
+        channels: 1,
+        ..Default::default()
+    })
+    .expect("rodio source");
+    assert_eq!(source.sample_rate(), 22050);
+    assert_eq!(source.channels(), 1);
+    assert!(source.by_ref().take(22050).all(|s| s == 0.0));
+
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    assert!(handle.play_adl(sound).expect("play adl"));
+    let samples: Vec<f32> = source.by_ref().take(22050).collect();
+    assert!(samples.iter().any(|s| *s != 0.0));
+    assert!(samples.iter().all(|s| (-1.0..=1.0).contains(s)));
+
+    // the source ends with the current block
+    handle.close();
+    assert!(source.by_ref().take(1024).count() <= 1024);
+    assert_eq!(source.next(), None);
+}
@@ -1,37 +1,60 @@
-use core::borrow::BorrowMut;
+use std::time::{Duration, Instant};
 
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
-use sdl2::{self, AudioSubsystem};
-
-use crate::chip::{AL_FREQ_H, AL_FREQ_L, AdlSound, Chip, adl_set_fx_inst};
-
+use sdl2::{self, AudioSubsystem, Sdl};
+
+use crate::chip::AdlSound;
+use crate::control::{self, COMMAND_CAPACITY, Controller, Processor};
+use crate::digi::DigiSound;
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{ImfOptions, Sequencer, SfxChannelMode};
+use crate::{Backend, Error};
+
+/// The OPL played through an SDL audio device. The calls never take the device
+/// lock: they send commands to the audio callback through a lock-free queue
+/// (applied at the start of the next buffer) and the queries read the status
+/// the callback published after its last buffer, updated by the commands sent since.
+/// The calls fail with `Error::QueueFull` if the callback does not keep up.
 pub struct OPL {
     audio_subsystem: AudioSubsystem,
-    device: Option<AudioDevice<OPLCallback>>,
+    device: Option<Device>,
+}
+
+struct Device {
+    // only kept open, the callback is not touched after `init`
+    _device: AudioDevice<OPLCallback>,
+    controller: Controller,
 }
 
 pub struct OPLSettings {
     pub mixer_rate: u32,
     pub imf_clock_rate: u32,
     pub adl_clock_rate: u32,
+    /// Melodic channel (0-8) the ADL sound effects are played on.
+    pub adl_channel: u8,
+    /// How the music is kept off the effect channel.
+    pub sfx_channel_mode: SfxChannelMode,
+    /// Name of the output device (see `OPL::playback_devices`), `None` for the default device.
+    pub device_name: Option<String>,
+    /// Audio buffer size in samples, 0 for 2048 samples at 44.1 kHz (scaled with the mixer rate).
+    pub buffer_size: u16,
+    /// Number of output channels (all get the same signal), 0 for stereo.
+    pub channels: u8,
 }
 
-struct SdlImfState {
-    pub data: Vec<u8>,
-
-    pub hack_ptr: usize,
-    pub hack_len: usize,
-    pub hack_seq_len: usize,
-    pub hack_time: u32,
-    pub al_time_count: u32,
-    pub sq_active: bool,
-}
-
-struct SdlAdlState {
-    pub sound: AdlSound,
-    pub data_ptr: usize,
-    pub sound_time_counter: u32,
-    pub al_block: u8,
+impl Default for OPLSettings {
+    fn default() -> Self {
+        OPLSettings {
+            mixer_rate: 44100,
+            imf_clock_rate: 0,
+            adl_clock_rate: 0,
+            adl_channel: 0,
+            sfx_channel_mode: SfxChannelMode::Shared,
+            device_name: None,
+            buffer_size: 0,
+            channels: 0,
+        }
+    }
 }
 
 // According to the SDL documentation the audio system is thread-safe.
@@ -40,283 +63,278 @@ struct SdlAdlState {
 unsafe impl Send for OPL {}
 
 impl OPL {
-    pub fn new() -> Result<OPL, &'static str> {
-        let sdl_context = sdl2::init().expect("sdl init failed");
-        let audio_subsystem = sdl_context.audio().expect("audio init failed");
-        Ok(OPL {
+    pub fn new() -> Result<OPL, Error> {
+        let sdl_context = sdl2::init().map_err(Error::Device)?;
+        OPL::with_sdl(&sdl_context)
+    }
+
+    /// Uses the SDL context of the host application instead of initialising SDL.
+    pub fn with_sdl(sdl_context: &Sdl) -> Result<OPL, Error> {
+        let audio_subsystem = sdl_context.audio().map_err(Error::Device)?;
+        Ok(OPL::with_audio_subsystem(audio_subsystem))
+    }
+
+    /// Uses the audio subsystem of the host application.
+    pub fn with_audio_subsystem(audio_subsystem: AudioSubsystem) -> OPL {
+        OPL {
             audio_subsystem,
             device: None,
-        })
+        }
+    }
+
+    /// Names of the available output devices. Empty if SDL can not list the devices.
+    pub fn playback_devices(&self) -> Result<Vec<String>, Error> {
+        let Some(num_devices) = self.audio_subsystem.num_audio_playback_devices() else {
+            return Ok(Vec::new());
+        };
+        (0..num_devices)
+            .map(|i| {
+                self.audio_subsystem
+                    .audio_playback_device_name(i)
+                    .map_err(Error::Device)
+            })
+            .collect()
     }
 
-    pub fn init(&mut self, settings: OPLSettings) {
+    pub fn init(&mut self, settings: OPLSettings) -> Result<(), Error> {
+        let buffer_size = if settings.buffer_size != 0 {
+            settings.buffer_size
+        } else {
+            ((settings.mixer_rate * 2048) / 44100) as u16
+        };
+        let channels = if settings.channels != 0 {
+            settings.channels
+        } else {
+            2
+        };
         let desired_spec = AudioSpecDesired {
             freq: Some(settings.mixer_rate as i32),
-            channels: Some(2),
-            samples: Some(((settings.mixer_rate * 2048) / 44100) as u16),
+            channels: Some(channels),
+            samples: Some(buffer_size),
         };
 
-        let samples_per_music_tick = if settings.imf_clock_rate != 0 {
-            settings.mixer_rate / settings.imf_clock_rate
+        let imf_clock_rate = if settings.imf_clock_rate != 0 {
+            settings.imf_clock_rate
         } else {
-            settings.mixer_rate / 560
+            560
         };
 
-        let adl_samples_per_tick = if settings.adl_clock_rate != 0 {
-            settings.imf_clock_rate / settings.adl_clock_rate
+        let adl_clock_rate = if settings.adl_clock_rate != 0 {
+            settings.adl_clock_rate
         } else {
-            settings.imf_clock_rate / 140
+            140
         };
 
+        let mut controller = Err(Error::NotInitialised);
         let device = self
             .audio_subsystem
-            .open_playback(None, &desired_spec, |_| {
-                // initialize the audio callback
+            .open_playback(settings.device_name.as_deref(), &desired_spec, |spec| {
+                // initialize the audio callback with the spec SDL actually opened
+                let mixer_rate = spec.freq as u32;
+                let mut sequencer = match Sequencer::new(mixer_rate, imf_clock_rate, adl_clock_rate)
+                {
+                    Ok(sequencer) => sequencer,
+                    Err(e) => {
+                        // the device is dropped without being resumed
+                        controller = Err(e);
+                        return OPLCallback { processor: None };
+                    }
+                };
+                sequencer.set_adl_channel(settings.adl_channel);
+                sequencer.set_sfx_channel_mode(settings.sfx_channel_mode);
+                let (control, processor) = control::channel(
+                    sequencer,
+                    mixer_rate,
+                    spec.channels as usize,
+                    spec.samples as u64,
+                    COMMAND_CAPACITY,
+                );
+                controller = Ok(control);
                 OPLCallback {
-                    mix_buffer: vec![0; settings.mixer_rate as usize],
-                    num_ready_samples: 0,
-                    samples_per_music_tick,
-                    adl_samples_per_tick,
-                    chip: Chip::new(settings.mixer_rate),
-                    imf_state: None,
-                    adl_state: None,
+                    processor: Some(processor),
                 }
             })
-            .expect("playback open failed");
-        self.device = Some(device);
-    }
-
-    pub fn play_imf(&mut self, data: Vec<u8>) -> Result<(), &'static str> {
-        self.assert_device()?;
-
-        let device = self.mut_device()?;
-        {
-            let mut cb = device.lock();
-            let hack_len = data.len();
-            cb.imf_state = Some(SdlImfState {
-                data,
-                hack_len,
-                hack_seq_len: hack_len,
-                hack_time: 0,
-                al_time_count: 0,
-                hack_ptr: 0,
-                sq_active: true,
-            });
-            cb.chip.setup();
-        }
+            .map_err(Error::Device)?;
+        let controller = controller?;
+        // the callback has to run to apply the commands
         device.resume();
+        self.device = Some(Device {
+            _device: device,
+            controller,
+        });
         Ok(())
     }
 
-    pub fn stop_imf(&mut self) -> Result<(), &'static str> {
-        self.assert_device()?;
-        let device = self.mut_device()?;
-        {
-            let mut cb = device.lock();
-            if let Some(imf_state) = &mut cb.imf_state {
-                imf_state.sq_active = false;
-            }
-        }
-        Ok(())
+    /// Registers a function that is called each time an IMF track ended (which
+    /// never happens with `LoopMode::Forever`). The function is called from the audio
+    /// thread, it must not block.
+    pub fn on_imf_end<F>(&mut self, on_end: F) -> Result<(), Error>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.mut_controller()?.on_imf_end(Box::new(on_end))
     }
 
-    pub fn pause_imf(&mut self) -> Result<(), &'static str> {
-        self.assert_device()?;
+    /// Resolves when the track ended (never with `LoopMode::Forever`), was stopped
+    /// or replaced, right away if none is playing. The audio callback wakes the task.
+    pub fn imf_finished(&self) -> Result<impl Future<Output = ()> + Send + use<>, Error> {
+        Ok(self.controller()?.imf_finished())
+    }
 
-        self.mut_device()?.pause();
-        Ok(())
+    /// Resolves when the sound effect ended or was stopped, right away if none is
+    /// playing. A sound that replaces the playing one is waited for as well.
+    pub fn adl_finished(&self) -> Result<impl Future<Output = ()> + Send + use<>, Error> {
+        Ok(self.controller()?.adl_finished())
     }
 
-    pub fn play_adl(&mut self, sound: AdlSound) -> Result<(), &'static str> {
-        self.assert_device()?;
+    /// Number of frames rendered up to the last audio buffer, the time base of
+    /// `write_reg_at`. The audible output lags behind it by up to one audio buffer.
+    pub fn sample_clock(&self) -> Result<u64, Error> {
+        Ok(self.controller()?.sample_clock())
+    }
 
-        let device = self.mut_device()?;
-        {
-            let mut cb = device.lock();
-            adl_set_fx_inst(&mut cb.chip, &sound.instrument);
-            let al_block = ((sound.block & 7) << 2) | 0x20;
-            cb.adl_state = Some(SdlAdlState {
-                sound,
-                data_ptr: 0,
-                al_block,
-                sound_time_counter: cb.adl_samples_per_tick,
-            })
+    /// Applies the register write right before the frame `frame` (see `sample_clock`)
+    /// is rendered, independent of the buffer boundaries.
+    pub fn write_reg_at(&mut self, reg: u32, val: u8, frame: u64) -> Result<(), Error> {
+        self.mut_controller()?.write_reg_at(reg, val, frame)
+    }
+
+    /// Applies the register write at the frame that corresponds to the host time `at`.
+    /// The writes are delayed by one audio buffer for this, but keep their distance
+    /// to each other to the sample (`write_reg` is applied at the next buffer boundary).
+    pub fn write_reg_at_instant(&mut self, reg: u32, val: u8, at: Instant) -> Result<(), Error> {
+        self.mut_controller()?.write_reg_at_instant(reg, val, at)
+    }
+
+    fn controller(&self) -> Result<&Controller, Error> {
+        match &self.device {
+            Some(device) => Ok(&device.controller),
+            None => Err(Error::NotInitialised),
         }
+    }
 
-        device.resume();
-        Ok(())
+    fn mut_controller(&mut self) -> Result<&mut Controller, Error> {
+        match &mut self.device {
+            Some(device) => Ok(&mut device.controller),
+            None => Err(Error::NotInitialised),
+        }
     }
+}
 
-    pub fn stop_adl(&mut self) -> Result<(), &'static str> {
-        self.assert_device()?;
-        let device = self.mut_device()?;
-        device.pause();
-        let mut cb = device.lock();
-        cb.adl_state = None;
-        Ok(())
+impl Backend for OPL {
+    fn play_imf_with_options(&mut self, data: Vec<u8>, options: ImfOptions) -> Result<(), Error> {
+        self.mut_controller()?.play_imf(data, options)
     }
 
-    pub fn is_adl_playing(&mut self) -> Result<bool, &'static str> {
-        self.assert_device()?;
-        let device = self.mut_device()?;
-        let cb = device.lock();
-        Ok(cb.adl_state.is_some())
+    fn stop_imf(&mut self) -> Result<(), Error> {
+        self.mut_controller()?.stop_imf()
     }
 
-    pub fn is_imf_playing(&mut self) -> Result<bool, &'static str> {
-        self.assert_device()?;
-        let device = self.mut_device()?;
-        let cb = device.lock();
-        Ok(cb.imf_state.is_some())
+    fn pause_imf(&mut self) -> Result<(), Error> {
+        self.mut_controller()?.pause_imf()
     }
 
-    pub fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), &'static str> {
-        self.assert_device()?;
+    fn resume_imf(&mut self) -> Result<(), Error> {
+        self.mut_controller()?.resume_imf()
+    }
 
-        let device = self.mut_device()?;
-        let mut cb = device.lock();
-        cb.chip.write_reg(reg, val);
-        Ok(())
+    fn is_imf_paused(&self) -> Result<bool, Error> {
+        Ok(self.controller()?.is_imf_paused())
     }
 
-    fn assert_device(&self) -> Result<(), &'static str> {
-        if self.device.is_none() {
-            return Err("OPL not initialized, did you call init()?");
-        }
-        Ok(())
+    fn set_music_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.mut_controller()?.set_music_volume(volume)
     }
 
-    fn mut_device(&mut self) -> Result<&mut AudioDevice<OPLCallback>, &'static str> {
-        let may_device = self.device.as_mut();
-        if let Some(device) = may_device {
-            Ok(device)
-        } else {
-            Err("no device")
-        }
+    fn set_sfx_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.mut_controller()?.set_sfx_volume(volume)
+    }
+
+    fn set_master_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.mut_controller()?.set_master_volume(volume)
+    }
+
+    fn imf_duration(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.controller()?.imf_duration())
+    }
+
+    fn imf_position(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.controller()?.imf_position())
+    }
+
+    fn seek_imf(&mut self, position: Duration) -> Result<(), Error> {
+        self.mut_controller()?.seek_imf(position)
+    }
+
+    fn set_imf_tempo(&mut self, tempo: f32) -> Result<(), Error> {
+        self.mut_controller()?.set_imf_tempo(tempo)
+    }
+
+    fn set_imf_transpose(&mut self, semitones: i8) -> Result<(), Error> {
+        self.mut_controller()?.set_imf_transpose(semitones)
+    }
+
+    fn play_adl(&mut self, sound: AdlSound) -> Result<bool, Error> {
+        self.mut_controller()?.play_adl(sound)
+    }
+
+    fn stop_adl(&mut self) -> Result<(), Error> {
+        self.mut_controller()?.stop_adl()
+    }
+
+    fn is_adl_playing(&self) -> Result<bool, Error> {
+        Ok(self.controller()?.is_adl_playing())
+    }
+
+    fn play_pc(&mut self, sound: PcSound) -> Result<bool, Error> {
+        self.mut_controller()?.play_pc(sound)
+    }
+
+    fn stop_pc(&mut self) -> Result<(), Error> {
+        self.mut_controller()?.stop_pc()
+    }
+
+    fn is_pc_playing(&self) -> Result<bool, Error> {
+        Ok(self.controller()?.is_pc_playing())
+    }
+
+    fn play_digi(&mut self, voice: usize, sound: DigiSound) -> Result<(), Error> {
+        self.mut_controller()?.play_digi(voice, sound)
+    }
+
+    fn stop_digi(&mut self, voice: usize) -> Result<(), Error> {
+        self.mut_controller()?.stop_digi(voice)
+    }
+
+    fn is_digi_playing(&self, voice: usize) -> Result<bool, Error> {
+        Ok(self.controller()?.is_digi_playing(voice))
+    }
+
+    fn set_digi_volume(&mut self, voice: usize, volume: f32) -> Result<(), Error> {
+        self.mut_controller()?.set_digi_volume(voice, volume)
+    }
+
+    fn is_imf_playing(&self) -> Result<bool, Error> {
+        Ok(self.controller()?.is_imf_playing())
+    }
+
+    fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), Error> {
+        self.mut_controller()?.write_reg(reg, val)
     }
 }
 
 struct OPLCallback {
-    mix_buffer: Vec<i32>,
-    num_ready_samples: u32,
-    samples_per_music_tick: u32,
-    adl_samples_per_tick: u32,
-
-    chip: Chip,
-    imf_state: Option<SdlImfState>,
-    adl_state: Option<SdlAdlState>,
+    // None if the sequencer could not be created for the opened spec
+    processor: Option<Processor>,
 }
 
 impl AudioCallback for OPLCallback {
     type Channel = i16;
 
     fn callback(&mut self, out: &mut [i16]) {
-        let mut samples_len = out.len() as u32 >> 1;
-        let mut out_offset = 0;
-
-        if let Some(imf_state) = self.imf_state.borrow_mut() {
-            loop {
-                if self.num_ready_samples > 0 {
-                    if self.num_ready_samples < samples_len {
-                        opl_update(
-                            &mut self.chip,
-                            out,
-                            out_offset,
-                            self.num_ready_samples as usize,
-                            &mut self.mix_buffer,
-                        );
-                        out_offset += self.num_ready_samples as usize * 2;
-                        samples_len -= self.num_ready_samples;
-                    } else {
-                        opl_update(
-                            &mut self.chip,
-                            out,
-                            out_offset,
-                            samples_len as usize,
-                            &mut self.mix_buffer,
-                        );
-                        self.num_ready_samples -= samples_len;
-                        break;
-                    }
-                }
-
-                if self.adl_state.is_some() {
-                    let state = self.adl_state.as_mut().expect("adl state");
-                    state.sound_time_counter -= 1;
-                    if state.sound_time_counter == 0 {
-                        state.sound_time_counter = self.adl_samples_per_tick;
-                        if state.data_ptr < state.sound.data.len() {
-                            let al_sound = state.sound.data[state.data_ptr];
-                            if al_sound != 0 {
-                                self.chip.write_reg(AL_FREQ_L, al_sound);
-                                self.chip.write_reg(AL_FREQ_H, state.al_block);
-                            } else {
-                                self.chip.write_reg(AL_FREQ_H, 0);
-                            }
-                            state.data_ptr += 1;
-                        } else {
-                            self.adl_state = None;
-                            self.chip.write_reg(AL_FREQ_H, 0); // write silence at the end so that last note does not repeat
-                        }
-                    }
-                }
-
-                if imf_state.sq_active {
-                    loop {
-                        if imf_state.hack_time > imf_state.al_time_count {
-                            break;
-                        }
-
-                        let t = u16::from_le_bytes(
-                            imf_state.data[(imf_state.hack_ptr + 2)..(imf_state.hack_ptr + 4)]
-                                .try_into()
-                                .unwrap(),
-                        ) as u32;
-                        imf_state.hack_time = imf_state.al_time_count + t;
-
-                        let reg = imf_state.data[imf_state.hack_ptr] as u32;
-                        let val = imf_state.data[imf_state.hack_ptr + 1];
-
-                        self.chip.write_reg(reg, val);
-                        imf_state.hack_ptr += 4;
-                        imf_state.hack_len -= 4;
-
-                        if imf_state.hack_len == 0 {
-                            break;
-                        }
-                    }
-                    imf_state.al_time_count += 1;
-                    if imf_state.hack_len == 0 {
-                        imf_state.hack_ptr = 0;
-                        imf_state.hack_len = imf_state.hack_seq_len;
-                        imf_state.hack_time = 0;
-                        imf_state.al_time_count = 0;
-                    }
-                }
-                self.num_ready_samples = self.samples_per_music_tick;
-            }
+        match &mut self.processor {
+            Some(processor) => processor.render(out),
+            None => out.fill(0),
         }
     }
 }
-
-fn opl_update(
-    chip: &mut Chip,
-    sdl_out: &mut [i16],
-    offset: usize,
-    len: usize,
-    mix_buffer: &mut Vec<i32>,
-) {
-    chip.generate_block_2(len, mix_buffer);
-
-    let mut mix_ptr = 0;
-    let mut out_ptr = offset;
-    for _ in 0..len {
-        let mix = (mix_buffer[mix_ptr] << 2) as i16; // increase volume a bit
-        mix_ptr += 1;
-
-        sdl_out[out_ptr] = mix;
-        out_ptr += 1;
-        sdl_out[out_ptr] = mix;
-        out_ptr += 1;
-    }
-}
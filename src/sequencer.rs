@@ -0,0 +1,1105 @@
+#[cfg(test)]
+#[path = "./sequencer_test.rs"]
+mod sequencer_test;
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::Error;
+use crate::chip::{
+    AL_FREQ_H, AL_FREQ_L, AdlSound, AdlVoice, CHANNEL_MODULATOR_OFFSET, Chip, adl_set_fx_inst,
+};
+use crate::digi::{DIGI_VOICES, DigiSound, DigiVoice};
+use crate::imf::duration_ticks;
+use crate::pcspeaker::{PcSound, PcSpeaker};
+
+const GAIN_ONE: i32 = 256;
+const RATE_ONE: u32 = 1 << 16;
+const AL_RHYTHM: u32 = 0xbd;
+const REMAP_CODE: u32 = 0x100;
+const MAX_VOLUME: f32 = 4.0;
+const MIN_TEMPO: f32 = 0.1;
+const MAX_TEMPO: f32 = 8.0;
+
+/// How often an IMF track is played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    Once,
+    /// Play the track n times (0 is treated like `Once`).
+    Count(u32),
+    Forever,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImfOptions {
+    pub loop_mode: LoopMode,
+    /// Fade out the last n milliseconds of the final loop (0 = no fade).
+    /// Has no effect with `LoopMode::Forever`.
+    pub fade_out_ms: u32,
+}
+
+impl Default for ImfOptions {
+    fn default() -> Self {
+        ImfOptions {
+            loop_mode: LoopMode::Forever,
+            fade_out_ms: 0,
+        }
+    }
+}
+
+impl LoopMode {
+    /// Number of times the track is played, 0 for forever.
+    pub fn to_count(self) -> u32 {
+        match self {
+            LoopMode::Once => 1,
+            LoopMode::Count(n) => n.max(1),
+            LoopMode::Forever => 0,
+        }
+    }
+
+    pub fn from_count(count: u32) -> LoopMode {
+        if count == 0 {
+            LoopMode::Forever
+        } else {
+            LoopMode::Count(count)
+        }
+    }
+}
+
+/// How the music is kept off the channel of the ADL sound effects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SfxChannelMode {
+    /// Music and effects share the channel, the music is expected to leave it
+    /// alone (id's tracks do not use channel 0).
+    #[default]
+    Shared,
+    /// Music writes to the effect channel are dropped.
+    Drop,
+    /// Music writes to the effect channel are moved to the given channel, which
+    /// should be one the music does not use.
+    Remap(u8),
+    /// The effects are played on a second chip that is mixed into the output,
+    /// the music keeps all channels.
+    SecondChip,
+}
+
+impl SfxChannelMode {
+    /// Number encoding of the mode (for the worklet interface).
+    pub fn to_code(self) -> u32 {
+        match self {
+            SfxChannelMode::Shared => 0,
+            SfxChannelMode::Drop => 1,
+            SfxChannelMode::SecondChip => 2,
+            SfxChannelMode::Remap(channel) => REMAP_CODE | channel as u32,
+        }
+    }
+
+    pub fn from_code(code: u32) -> SfxChannelMode {
+        match code {
+            1 => SfxChannelMode::Drop,
+            2 => SfxChannelMode::SecondChip,
+            code if (code & REMAP_CODE) != 0 => SfxChannelMode::Remap((code & 0xff) as u8),
+            _ => SfxChannelMode::Shared,
+        }
+    }
+}
+
+/// Conversion of the chip output into a backend sample format.
+pub trait Sample: Copy {
+    fn from_mix(mix: i32) -> Self;
+}
+
+impl Sample for i16 {
+    fn from_mix(mix: i32) -> Self {
+        (mix << 2).clamp(i16::MIN as i32, i16::MAX as i32) as i16 // increase volume a bit
+    }
+}
+
+impl Sample for f32 {
+    fn from_mix(mix: i32) -> Self {
+        ((mix << 2) as f32 / 32768.0).clamp(-1.0, 1.0) // increase volume a bit
+    }
+}
+
+struct ImfState {
+    data: Vec<u8>,
+
+    hack_ptr: usize,
+    hack_len: usize,
+    hack_seq_len: usize,
+    hack_time: u32,
+    al_time_count: u32,
+    sq_active: bool,
+    paused: bool,
+
+    loops_left: Option<u32>,
+    duration_ticks: u32,
+    fade_ticks: u32,
+}
+
+struct AdlState {
+    sound: AdlSound,
+    data_ptr: usize,
+    sound_time_counter: u32,
+    al_block: u8,
+    voice: AdlVoice,
+}
+
+struct PcState {
+    sound: PcSound,
+    data_ptr: usize,
+    sound_time_counter: u32,
+}
+
+/// A track or sound the sequencer let go of: replaced, stopped, rejected or
+/// played to the end.
+// the payloads are only held to be freed on another thread
+#[allow(dead_code)]
+pub(crate) enum Released {
+    Imf(Vec<u8>),
+    Adl(AdlSound),
+    Pc(PcSound),
+    Digi(DigiSound),
+}
+
+// Between two calls of `take_released` after each command and each rendered
+// buffer, a command lets go of at most two payloads (a replaced track and an
+// empty one), a buffer of one per track, sound and digitized voice.
+const RELEASED_SLOTS: usize = DIGI_VOICES + 3;
+
+/// A register write waiting for its sample in `Sequencer::queue_write`.
+struct QueuedWrite {
+    at: u64,
+    reg: u32,
+    val: u8,
+}
+
+/// Drives the chip from an IMF track and an ADL sound effect and mixes in the
+/// PC speaker and the digitized sounds. Shared by all backends, which only have to supply the output buffer.
+/// The volumes are 1.0 for the original level (up to 4.0).
+pub struct Sequencer {
+    chip: Chip,
+    // only set with `SfxChannelMode::SecondChip`
+    sfx_chip: Option<Chip>,
+    mixer_rate: u32,
+    mix_buffer: Vec<i32>,
+    sfx_buffer: Vec<i32>,
+    num_ready_samples: u32,
+    samples_per_music_tick: u32,
+    adl_samples_per_tick: u32,
+    adl_channel: u8,
+    sfx_channel_mode: SfxChannelMode,
+    // fade-out of the track
+    gain: i32,
+    music_volume: i32,
+    sfx_volume: i32,
+    master_volume: i32,
+    // music ticks per sequencer tick in 16.16 fixed point
+    tempo: u32,
+    tempo_phase: u32,
+    transposer: Transposer,
+    pc_speaker: PcSpeaker,
+    digi_voices: [DigiVoice; DIGI_VOICES],
+
+    imf_state: Option<ImfState>,
+    adl_state: Option<AdlState>,
+    pc_state: Option<PcState>,
+
+    // frames rendered so far
+    sample_clock: u64,
+    // ordered by time
+    write_queue: VecDeque<QueuedWrite>,
+
+    // payloads waiting for `take_released`, only filled with `keep_released`
+    keep_released: bool,
+    released: [Option<Released>; RELEASED_SLOTS],
+}
+
+/// Checks that the music clock is not faster than the mixer and the sound effect
+/// clock not faster than the music clock, the sequencer ticks each from the other.
+pub fn check_clock_rates(
+    mixer_rate: u32,
+    imf_clock_rate: u32,
+    adl_clock_rate: u32,
+) -> Result<(), Error> {
+    if imf_clock_rate == 0 || imf_clock_rate > mixer_rate {
+        return Err(Error::ClockRate {
+            kind: "IMF",
+            rate: imf_clock_rate,
+        });
+    }
+    if adl_clock_rate == 0 || adl_clock_rate > imf_clock_rate {
+        return Err(Error::ClockRate {
+            kind: "ADL",
+            rate: adl_clock_rate,
+        });
+    }
+    Ok(())
+}
+
+impl Sequencer {
+    /// Creates a sequencer that mixes at `mixer_rate` and ticks the music with
+    /// `imf_clock_rate` and the sound effects with `adl_clock_rate` per second.
+    pub fn new(
+        mixer_rate: u32,
+        imf_clock_rate: u32,
+        adl_clock_rate: u32,
+    ) -> Result<Sequencer, Error> {
+        check_clock_rates(mixer_rate, imf_clock_rate, adl_clock_rate)?;
+        let samples_per_music_tick = mixer_rate / imf_clock_rate;
+        let adl_samples_per_tick = imf_clock_rate / adl_clock_rate;
+        let mut chip = Chip::new(mixer_rate);
+        chip.setup();
+        Ok(Sequencer {
+            chip,
+            sfx_chip: None,
+            mixer_rate,
+            mix_buffer: vec![0; samples_per_music_tick as usize],
+            sfx_buffer: vec![0; samples_per_music_tick as usize],
+            num_ready_samples: 0,
+            samples_per_music_tick,
+            adl_samples_per_tick,
+            adl_channel: 0,
+            sfx_channel_mode: SfxChannelMode::Shared,
+            gain: GAIN_ONE,
+            music_volume: GAIN_ONE,
+            sfx_volume: GAIN_ONE,
+            master_volume: GAIN_ONE,
+            tempo: RATE_ONE,
+            tempo_phase: 0,
+            transposer: Transposer::new(),
+            pc_speaker: PcSpeaker::new(mixer_rate),
+            digi_voices: Default::default(),
+            imf_state: None,
+            adl_state: None,
+            pc_state: None,
+            sample_clock: 0,
+            write_queue: VecDeque::new(),
+            keep_released: false,
+            released: Default::default(),
+        })
+    }
+
+    /// Keeps the tracks and sounds the sequencer lets go of until `take_released`
+    /// hands them out, so that they are not freed on the thread rendering the audio.
+    #[cfg_attr(not(any(feature = "sdl", feature = "rodio")), allow(dead_code))]
+    pub(crate) fn set_keep_released(&mut self, keep: bool) {
+        self.keep_released = keep;
+    }
+
+    #[cfg_attr(not(any(feature = "sdl", feature = "rodio")), allow(dead_code))]
+    pub(crate) fn take_released(&mut self) -> Option<Released> {
+        self.released.iter_mut().find_map(Option::take)
+    }
+
+    // Drops the payload unless it is kept for `take_released`.
+    fn release(&mut self, released: Released) {
+        if self.keep_released
+            && let Some(slot) = self.released.iter_mut().find(|slot| slot.is_none())
+        {
+            *slot = Some(released);
+        }
+    }
+
+    pub fn play_imf(&mut self, mut data: Vec<u8>, options: ImfOptions) {
+        // only whole commands can be played
+        data.truncate(data.len() - data.len() % 4);
+        if let Some(imf_state) = self.imf_state.take() {
+            self.release(Released::Imf(imf_state.data));
+        }
+        if data.is_empty() {
+            self.release(Released::Imf(data));
+            return;
+        }
+
+        let hack_len = data.len();
+        let music_clock_rate = self.mixer_rate / self.samples_per_music_tick;
+        let loops_left = match options.loop_mode {
+            LoopMode::Forever => None,
+            mode => Some(mode.to_count()),
+        };
+        self.imf_state = Some(ImfState {
+            duration_ticks: duration_ticks(&data),
+            data,
+            hack_len,
+            hack_seq_len: hack_len,
+            hack_time: 0,
+            al_time_count: 0,
+            hack_ptr: 0,
+            sq_active: true,
+            paused: false,
+            loops_left,
+            fade_ticks: ((options.fade_out_ms as u64 * music_clock_rate as u64) / 1000) as u32,
+        });
+        self.gain = GAIN_ONE;
+        self.tempo_phase = 0;
+        self.reset_music_chip();
+    }
+
+    // Clears the chip for the music. A sound effect playing on the same chip keeps its instrument.
+    fn reset_music_chip(&mut self) {
+        self.chip.setup();
+        self.transposer.reset();
+        if let Some(adl_state) = &self.adl_state
+            && self.sfx_chip.is_none()
+        {
+            adl_set_fx_inst(
+                &mut self.chip,
+                self.adl_channel,
+                &adl_state.sound.instrument,
+            );
+        }
+    }
+
+    pub fn stop_imf(&mut self) {
+        if let Some(imf_state) = &mut self.imf_state {
+            imf_state.sq_active = false;
+        }
+    }
+
+    /// Pauses the track and keys off its notes. The sound effects keep playing.
+    pub fn pause_imf(&mut self) {
+        let Some(imf_state) = self.imf_state.as_mut() else {
+            return;
+        };
+        if !imf_state.paused {
+            imf_state.paused = true;
+            self.key_off_music();
+        }
+    }
+
+    /// Continues a paused track, the notes that were held are keyed on again.
+    pub fn resume_imf(&mut self) {
+        let Some(imf_state) = self.imf_state.as_mut() else {
+            return;
+        };
+        if imf_state.paused {
+            imf_state.paused = false;
+            let skip = self.sfx_owned_channel();
+            self.transposer.key_on(&mut self.chip, skip);
+        }
+    }
+
+    pub fn is_imf_paused(&self) -> bool {
+        self.imf_state.as_ref().is_some_and(|state| state.paused)
+    }
+
+    pub fn set_music_volume(&mut self, volume: f32) {
+        self.music_volume = to_gain(volume);
+    }
+
+    /// Volume of all sound effects (ADL, PC speaker and digitized sounds).
+    pub fn set_sfx_volume(&mut self, volume: f32) {
+        self.sfx_volume = to_gain(volume);
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = to_gain(volume);
+    }
+
+    /// Starts the sound effect if no effect is playing or the playing one
+    /// has an equal or lower priority (as id's sound manager does).
+    /// Returns whether the sound was started.
+    pub fn play_adl(&mut self, sound: AdlSound) -> bool {
+        if let Some(playing) = self.adl_priority()
+            && sound.priority < playing
+        {
+            self.release(Released::Adl(sound));
+            return false;
+        }
+
+        let chip = self.sfx_chip.as_mut().unwrap_or(&mut self.chip);
+        let voice = adl_set_fx_inst(chip, self.adl_channel, &sound.instrument);
+        let al_block = ((sound.block & 7) << 2) | 0x20;
+        if let Some(adl_state) = self.adl_state.take() {
+            if adl_state.voice != voice {
+                self.adl_note(adl_state.voice, None);
+            }
+            self.release(Released::Adl(adl_state.sound));
+        }
+        self.adl_state = Some(AdlState {
+            sound,
+            data_ptr: 0,
+            al_block,
+            sound_time_counter: self.adl_samples_per_tick,
+            voice,
+        });
+        true
+    }
+
+    /// Sets the melodic channel (0-8) the sound effects are played on. Id's games
+    /// use channel 0 and do not use it for music. Stops the playing effect.
+    pub fn set_adl_channel(&mut self, channel: u8) {
+        self.stop_adl();
+        self.adl_channel = channel.min(8);
+    }
+
+    /// Sets how the music is kept off the effect channel. Stops the playing effect.
+    pub fn set_sfx_channel_mode(&mut self, mode: SfxChannelMode) {
+        self.stop_adl();
+        self.sfx_channel_mode = match mode {
+            SfxChannelMode::Remap(channel) => SfxChannelMode::Remap(channel.min(8)),
+            mode => mode,
+        };
+        self.sfx_chip = if mode == SfxChannelMode::SecondChip {
+            let mut sfx_chip = Chip::new(self.mixer_rate);
+            sfx_chip.setup();
+            Some(sfx_chip)
+        } else {
+            None
+        };
+    }
+
+    /// Priority of the playing sound effect.
+    pub fn adl_priority(&self) -> Option<u16> {
+        self.adl_state.as_ref().map(|state| state.sound.priority)
+    }
+
+    pub fn stop_adl(&mut self) {
+        if let Some(adl_state) = self.adl_state.take() {
+            self.adl_note(adl_state.voice, None);
+            self.release(Released::Adl(adl_state.sound));
+        }
+    }
+
+    // Keys the voice of the sound effect on with the frequency registers (0xA0, 0xB0)
+    // or off (`None`). The rhythm voices keep the 0xBD bits of the music on its chip.
+    fn adl_note(&mut self, voice: AdlVoice, note: Option<(u8, u8)>) {
+        let rhythm = if self.sfx_chip.is_some() {
+            0
+        } else {
+            self.transposer.rhythm_val
+        };
+        let chip = self.sfx_chip.as_mut().unwrap_or(&mut self.chip);
+        match (voice, note) {
+            (AdlVoice::Melodic(channel), Some((freq_l, freq_h))) => {
+                chip.write_reg(AL_FREQ_L + channel as u32, freq_l);
+                chip.write_reg(AL_FREQ_H + channel as u32, freq_h);
+            }
+            (AdlVoice::Melodic(channel), None) => chip.write_reg(AL_FREQ_H + channel as u32, 0),
+            (AdlVoice::Rhythm { channel, bit }, Some((freq_l, freq_h))) => {
+                chip.write_reg(AL_FREQ_L + channel as u32, freq_l);
+                chip.write_reg(AL_FREQ_H + channel as u32, freq_h & !0x20);
+                chip.write_reg(AL_RHYTHM, rhythm | 0x20 | bit);
+            }
+            (AdlVoice::Rhythm { .. }, None) => chip.write_reg(AL_RHYTHM, rhythm),
+        }
+    }
+
+    /// Starts the PC speaker sound with the same priority rules as `play_adl`.
+    /// Returns whether the sound was started.
+    pub fn play_pc(&mut self, sound: PcSound) -> bool {
+        if let Some(playing) = self.pc_priority()
+            && sound.priority < playing
+        {
+            self.release(Released::Pc(sound));
+            return false;
+        }
+
+        if let Some(pc_state) = self.pc_state.take() {
+            self.release(Released::Pc(pc_state.sound));
+        }
+        self.pc_state = Some(PcState {
+            sound,
+            data_ptr: 0,
+            sound_time_counter: self.adl_samples_per_tick,
+        });
+        true
+    }
+
+    /// Priority of the playing PC speaker sound.
+    pub fn pc_priority(&self) -> Option<u16> {
+        self.pc_state.as_ref().map(|state| state.sound.priority)
+    }
+
+    pub fn stop_pc(&mut self) {
+        if let Some(pc_state) = self.pc_state.take() {
+            self.release(Released::Pc(pc_state.sound));
+        }
+        self.pc_speaker.off();
+    }
+
+    pub fn is_pc_playing(&self) -> bool {
+        self.pc_state.is_some()
+    }
+
+    /// Plays the digitized sound on the voice (0..`DIGI_VOICES`), replacing the
+    /// sound playing on it. Returns false for an invalid voice.
+    pub fn play_digi(&mut self, voice: usize, sound: DigiSound) -> bool {
+        let Some(digi_voice) = self.digi_voices.get_mut(voice) else {
+            self.release(Released::Digi(sound));
+            return false;
+        };
+        if let Some(playing) = digi_voice.play(sound, self.mixer_rate) {
+            self.release(Released::Digi(playing));
+        }
+        true
+    }
+
+    pub fn stop_digi(&mut self, voice: usize) {
+        if let Some(playing) = self
+            .digi_voices
+            .get_mut(voice)
+            .and_then(|digi_voice| digi_voice.stop())
+        {
+            self.release(Released::Digi(playing));
+        }
+    }
+
+    pub fn is_digi_playing(&self, voice: usize) -> bool {
+        self.digi_voices
+            .get(voice)
+            .is_some_and(|digi_voice| digi_voice.is_playing())
+    }
+
+    pub fn set_digi_volume(&mut self, voice: usize, volume: f32) {
+        if let Some(digi_voice) = self.digi_voices.get_mut(voice) {
+            digi_voice.set_volume(volume);
+        }
+    }
+
+    pub fn is_imf_playing(&self) -> bool {
+        self.imf_state.is_some()
+    }
+
+    pub fn is_adl_playing(&self) -> bool {
+        self.adl_state.is_some()
+    }
+
+    pub fn write_reg(&mut self, reg: u32, val: u8) {
+        self.chip.write_reg(reg, val);
+    }
+
+    /// Number of frames rendered so far, the time base of `queue_write`.
+    pub fn sample_clock(&self) -> u64 {
+        self.sample_clock
+    }
+
+    /// Applies the register write right before the frame `at` (see `sample_clock`)
+    /// is rendered. Writes for a time that already passed are applied before the
+    /// next frame, writes with the same time in the order they were queued.
+    pub fn queue_write(&mut self, at: u64, reg: u32, val: u8) {
+        let ix = self.write_queue.partition_point(|write| write.at <= at);
+        self.write_queue.insert(ix, QueuedWrite { at, reg, val });
+    }
+
+    /// Drops all queued writes that were not applied yet.
+    pub fn clear_write_queue(&mut self) {
+        self.write_queue.clear();
+    }
+
+    /// Scales the speed of the track, 1.0 is the original tempo. Clamped to 0.1
+    /// to 8.0, NaN and infinity reset it to 1.0. Sound effects are not affected.
+    pub fn set_imf_tempo(&mut self, tempo: f32) {
+        let tempo = if tempo.is_finite() {
+            tempo.clamp(MIN_TEMPO, MAX_TEMPO)
+        } else {
+            1.0
+        };
+        self.tempo = (tempo * RATE_ONE as f32) as u32;
+    }
+
+    /// Transposes the track by the given number of semitones by rewriting
+    /// the frequency registers of the music. Takes effect immediately, also on
+    /// the notes currently playing.
+    pub fn set_imf_transpose(&mut self, semitones: i8) {
+        self.transposer.set_semitones(semitones);
+        if self.imf_state.is_some() {
+            let skip = self.sfx_owned_channel();
+            self.transposer.rewrite(&mut self.chip, skip);
+        }
+        if self.is_imf_paused() {
+            self.key_off_music();
+        }
+    }
+
+    /// Length of one loop of the current track (in track time, the tempo is not considered).
+    pub fn imf_duration(&self) -> Option<Duration> {
+        let imf_state = self.imf_state.as_ref()?;
+        Some(self.ticks_to_duration(imf_state.duration_ticks))
+    }
+
+    /// Position in the current loop of the track (in track time, the tempo is not considered).
+    pub fn imf_position(&self) -> Option<Duration> {
+        let imf_state = self.imf_state.as_ref()?;
+        Some(self.ticks_to_duration(imf_state.al_time_count))
+    }
+
+    /// Jumps to a position in the current loop of the track. The register writes up to
+    /// the position are replayed on the chip without rendering any audio. Positions
+    /// beyond the end are clamped to the last command.
+    pub fn seek_imf(&mut self, position: Duration) {
+        if self.imf_state.is_none() {
+            return;
+        }
+        let target = self.duration_to_ticks(position);
+        self.reset_music_chip();
+        let Some(imf_state) = self.imf_state.as_mut() else {
+            return;
+        };
+
+        let mut ptr = 0;
+        let mut time = 0;
+        while ptr + 4 < imf_state.data.len() && time < target {
+            let cmd = &imf_state.data[ptr..(ptr + 4)];
+            if let Some(reg) = map_music_reg(self.sfx_channel_mode, self.adl_channel, cmd[0] as u32)
+            {
+                self.transposer.write_reg(&mut self.chip, reg, cmd[1]);
+            }
+            time += u16::from_le_bytes([cmd[2], cmd[3]]) as u32;
+            ptr += 4;
+        }
+
+        imf_state.hack_ptr = ptr;
+        imf_state.hack_len = imf_state.hack_seq_len - ptr;
+        imf_state.hack_time = time;
+        imf_state.al_time_count = target.min(time);
+        self.gain = GAIN_ONE;
+        if imf_state.paused {
+            self.key_off_music();
+        }
+    }
+
+    /// Fills the interleaved stereo buffer.
+    pub fn generate<S: Sample>(&mut self, out: &mut [S]) {
+        self.generate_channels(out, 2);
+    }
+
+    /// Fills the interleaved buffer with the given number of channels, all channels
+    /// get the same (mono) signal. The chip is also rendered without a track (for the
+    /// sound effects and notes written with `write_reg`).
+    pub fn generate_channels<S: Sample>(&mut self, out: &mut [S], channels: usize) {
+        let channels = channels.max(1);
+
+        let mut samples_len = out.len() / channels;
+        let mut out_offset = 0;
+        loop {
+            if self.num_ready_samples > 0 {
+                let ready = self.num_ready_samples as usize;
+                if ready < samples_len {
+                    self.update_queued(out, out_offset, ready, channels);
+                    out_offset += ready * channels;
+                    samples_len -= ready;
+                } else {
+                    self.update_queued(out, out_offset, samples_len, channels);
+                    self.num_ready_samples -= samples_len as u32;
+                    break;
+                }
+            }
+
+            self.adl_tick();
+            self.pc_tick();
+            self.tempo_phase += self.tempo;
+            while self.tempo_phase >= RATE_ONE {
+                self.tempo_phase -= RATE_ONE;
+                self.imf_tick();
+            }
+            self.num_ready_samples = self.samples_per_music_tick;
+        }
+
+        for voice in 0..DIGI_VOICES {
+            if let Some(ended) = self.digi_voices[voice].take_ended() {
+                self.release(Released::Digi(ended));
+            }
+        }
+    }
+
+    fn adl_tick(&mut self) {
+        let Some(state) = self.adl_state.as_mut() else {
+            return;
+        };
+        state.sound_time_counter -= 1;
+        if state.sound_time_counter == 0 {
+            state.sound_time_counter = self.adl_samples_per_tick;
+            let voice = state.voice;
+            if state.data_ptr < state.sound.data.len() {
+                let al_sound = state.sound.data[state.data_ptr];
+                let note = (al_sound != 0).then_some((al_sound, state.al_block));
+                state.data_ptr += 1;
+                self.adl_note(voice, note);
+            } else {
+                self.adl_note(voice, None); // write silence at the end so that last note does not repeat
+                if let Some(adl_state) = self.adl_state.take() {
+                    self.release(Released::Adl(adl_state.sound));
+                }
+            }
+        }
+    }
+
+    // PC speaker sounds are clocked like the ADL sounds
+    fn pc_tick(&mut self) {
+        let Some(state) = self.pc_state.as_mut() else {
+            return;
+        };
+        state.sound_time_counter -= 1;
+        if state.sound_time_counter == 0 {
+            state.sound_time_counter = self.adl_samples_per_tick;
+            if state.data_ptr < state.sound.data.len() {
+                self.pc_speaker
+                    .play_sample(state.sound.data[state.data_ptr]);
+                state.data_ptr += 1;
+            } else {
+                self.pc_speaker.off();
+                if let Some(pc_state) = self.pc_state.take() {
+                    self.release(Released::Pc(pc_state.sound));
+                }
+            }
+        }
+    }
+
+    fn imf_tick(&mut self) {
+        let Some(imf_state) = self.imf_state.as_mut() else {
+            return;
+        };
+        if !imf_state.sq_active || imf_state.paused {
+            return;
+        }
+
+        loop {
+            if imf_state.hack_time > imf_state.al_time_count {
+                break;
+            }
+
+            // the delay of the last command was waited for, the loop ends here
+            if imf_state.hack_len == 0 {
+                if let Some(loops_left) = imf_state.loops_left.as_mut() {
+                    *loops_left -= 1;
+                    if *loops_left == 0 {
+                        self.finish_imf();
+                        return;
+                    }
+                }
+                imf_state.hack_ptr = 0;
+                imf_state.hack_len = imf_state.hack_seq_len;
+                imf_state.hack_time = 0;
+                imf_state.al_time_count = 0;
+                // the next loop starts in this tick, unless it would never wait
+                if imf_state.duration_ticks == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            let t = u16::from_le_bytes(
+                imf_state.data[(imf_state.hack_ptr + 2)..(imf_state.hack_ptr + 4)]
+                    .try_into()
+                    .unwrap(),
+            ) as u32;
+            imf_state.hack_time = imf_state.al_time_count + t;
+
+            let reg = imf_state.data[imf_state.hack_ptr] as u32;
+            let val = imf_state.data[imf_state.hack_ptr + 1];
+
+            if let Some(reg) = map_music_reg(self.sfx_channel_mode, self.adl_channel, reg) {
+                self.transposer.write_reg(&mut self.chip, reg, val);
+            }
+            imf_state.hack_ptr += 4;
+            imf_state.hack_len -= 4;
+        }
+        imf_state.al_time_count += 1;
+
+        if imf_state.loops_left == Some(1) && imf_state.fade_ticks > 0 {
+            let left = imf_state
+                .duration_ticks
+                .saturating_sub(imf_state.al_time_count);
+            if left < imf_state.fade_ticks {
+                self.gain = ((left as i64 * GAIN_ONE as i64) / imf_state.fade_ticks as i64) as i32;
+            }
+        }
+    }
+
+    fn finish_imf(&mut self) {
+        if let Some(imf_state) = self.imf_state.take() {
+            self.release(Released::Imf(imf_state.data));
+        }
+        self.gain = GAIN_ONE;
+        // key off the music voices, a playing sound effect is left alone
+        let skip = match &self.adl_state {
+            Some(state) if self.sfx_chip.is_none() => Some(state.voice.channel() as u32),
+            _ => None,
+        };
+        for channel in 0..9 {
+            if Some(channel) != skip {
+                self.chip.write_reg(AL_FREQ_H + channel, 0);
+            }
+        }
+    }
+
+    fn ticks_to_duration(&self, ticks: u32) -> Duration {
+        let samples = ticks as u128 * self.samples_per_music_tick as u128;
+        Duration::from_nanos(((samples * 1_000_000_000) / self.mixer_rate as u128) as u64)
+    }
+
+    fn duration_to_ticks(&self, duration: Duration) -> u32 {
+        let samples = (duration.as_nanos() * self.mixer_rate as u128) / 1_000_000_000;
+        (samples / self.samples_per_music_tick as u128).min(u32::MAX as u128) as u32
+    }
+
+    // The channel of the music chip that currently belongs to the sound effects.
+    fn sfx_owned_channel(&self) -> Option<usize> {
+        match self.sfx_channel_mode {
+            SfxChannelMode::SecondChip => None,
+            SfxChannelMode::Shared if self.adl_state.is_none() => None,
+            _ => Some(
+                self.adl_state
+                    .as_ref()
+                    .map_or(self.adl_channel, |state| state.voice.channel())
+                    as usize,
+            ),
+        }
+    }
+
+    fn key_off_music(&mut self) {
+        let skip = self.sfx_owned_channel();
+        self.transposer.key_off(&mut self.chip, skip);
+    }
+
+    // renders in pieces to apply the queued writes at their frame
+    fn update_queued<S: Sample>(
+        &mut self,
+        out: &mut [S],
+        mut offset: usize,
+        mut len: usize,
+        channels: usize,
+    ) {
+        while len > 0 {
+            let n = match self.write_queue.front() {
+                Some(write) if write.at <= self.sample_clock => {
+                    self.chip.write_reg(write.reg, write.val);
+                    self.write_queue.pop_front();
+                    continue;
+                }
+                Some(write) => ((write.at - self.sample_clock) as usize).min(len),
+                None => len,
+            };
+            self.update(out, offset, n, channels);
+            self.sample_clock += n as u64;
+            offset += n * channels;
+            len -= n;
+        }
+    }
+
+    fn update<S: Sample>(&mut self, out: &mut [S], offset: usize, len: usize, channels: usize) {
+        // the effects are rendered apart from the music to give them their own volume
+        if let Some(sfx_chip) = self.sfx_chip.as_mut() {
+            self.chip.generate_block_2(len, &mut self.mix_buffer);
+            sfx_chip.generate_block_2(len, &mut self.sfx_buffer);
+        } else if let Some(channel) = self.sfx_owned_channel() {
+            // the rhythm voices are all rendered by the bass drum channel
+            let channel = match self.adl_state.as_ref().map(|state| state.voice) {
+                Some(AdlVoice::Rhythm { .. }) => 6,
+                _ => channel,
+            };
+            self.chip.generate_block_split(
+                len,
+                &mut self.mix_buffer,
+                Some((channel, &mut self.sfx_buffer)),
+            );
+        } else {
+            self.chip.generate_block_2(len, &mut self.mix_buffer);
+            self.sfx_buffer.fill(0);
+        }
+
+        // the fade-out only applies to the music
+        let music_gain = (self.gain * self.music_volume) >> 8;
+        let mut out_ptr = offset;
+        for (music, sfx) in self.mix_buffer[..len].iter().zip(&self.sfx_buffer[..len]) {
+            let mut sfx = sfx + self.pc_speaker.next_sample();
+            for voice in &mut self.digi_voices {
+                sfx += voice.next_sample();
+            }
+            let mix = ((music * music_gain) >> 8) + ((sfx * self.sfx_volume) >> 8);
+            let mix = (mix * self.master_volume) >> 8;
+            out[out_ptr..(out_ptr + channels)].fill(S::from_mix(mix));
+            out_ptr += channels;
+        }
+    }
+}
+
+fn to_gain(volume: f32) -> i32 {
+    (volume.clamp(0.0, MAX_VOLUME) * GAIN_ONE as f32) as i32
+}
+
+// Applies the `SfxChannelMode` to a register written by the music,
+// `None` if the write has to be dropped.
+fn map_music_reg(mode: SfxChannelMode, sfx_channel: u8, reg: u32) -> Option<u32> {
+    if reg_channel(reg) != Some(sfx_channel) {
+        return Some(reg);
+    }
+    match mode {
+        SfxChannelMode::Shared | SfxChannelMode::SecondChip => Some(reg),
+        SfxChannelMode::Drop => None,
+        SfxChannelMode::Remap(to) => Some(move_reg(reg, to)),
+    }
+}
+
+// The channel a channel or operator register belongs to.
+fn reg_channel(reg: u32) -> Option<u8> {
+    match reg {
+        0x20..=0x95 | 0xe0..=0xf5 => {
+            let offset = reg & 0x1f;
+            let (group, slot) = (offset / 8, offset % 8);
+            if group > 2 || slot > 5 {
+                return None;
+            }
+            Some((group * 3 + slot % 3) as u8)
+        }
+        0xa0..=0xa8 | 0xb0..=0xb8 | 0xc0..=0xc8 => Some((reg & 0x0f) as u8),
+        _ => None,
+    }
+}
+
+fn move_reg(reg: u32, to: u8) -> u32 {
+    match reg {
+        0xa0..=0xc8 => (reg & 0xf0) | to as u32,
+        _ => {
+            // operators 3-5 of a group are the carriers
+            let carrier = if (reg & 0x1f) % 8 >= 3 { 3 } else { 0 };
+            (reg & !0x1f) | (CHANNEL_MODULATOR_OFFSET[to as usize] + carrier)
+        }
+    }
+}
+
+// Filters the frequency registers written by the music. Keeps the original
+// values to be able to re-transpose playing notes.
+struct Transposer {
+    factor: u32,
+    freq_l: [u8; 9],
+    freq_h: [u8; 9],
+    written: u16,
+    rhythm: bool,
+    rhythm_val: u8,
+}
+
+impl Transposer {
+    fn new() -> Transposer {
+        Transposer {
+            factor: RATE_ONE,
+            freq_l: [0; 9],
+            freq_h: [0; 9],
+            written: 0,
+            rhythm: false,
+            rhythm_val: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.freq_l = [0; 9];
+        self.freq_h = [0; 9];
+        self.written = 0;
+        self.rhythm = false;
+        self.rhythm_val = 0;
+    }
+
+    fn set_semitones(&mut self, semitones: i8) {
+        self.factor = if semitones == 0 {
+            RATE_ONE
+        } else {
+            (libm::pow(2.0, semitones as f64 / 12.0) * RATE_ONE as f64) as u32
+        };
+    }
+
+    fn write_reg(&mut self, chip: &mut Chip, reg: u32, val: u8) {
+        match reg {
+            AL_RHYTHM => {
+                self.rhythm = (val & 0x20) != 0;
+                self.rhythm_val = val;
+            }
+            0xa0..=0xa8 => {
+                let channel = (reg - AL_FREQ_L) as usize;
+                self.freq_l[channel] = val;
+                self.written |= 1 << channel;
+                if self.is_transposed(channel) {
+                    self.write_channel(chip, channel);
+                    return;
+                }
+            }
+            0xb0..=0xb8 => {
+                let channel = (reg - AL_FREQ_H) as usize;
+                self.freq_h[channel] = val;
+                self.written |= 1 << channel;
+                if self.is_transposed(channel) {
+                    self.write_channel(chip, channel);
+                    return;
+                }
+            }
+            _ => {}
+        }
+        chip.write_reg(reg, val);
+    }
+
+    // writes all channels the music used with the current transposition
+    fn rewrite(&self, chip: &mut Chip, skip: Option<usize>) {
+        for channel in 0..9 {
+            if (self.written & (1 << channel)) != 0
+                && !(self.rhythm && channel >= 6)
+                && Some(channel) != skip
+            {
+                self.write_channel(chip, channel);
+            }
+        }
+    }
+
+    // keys off the notes of the music, the frequencies are kept for `key_on`
+    fn key_off(&self, chip: &mut Chip, skip: Option<usize>) {
+        for channel in 0..9 {
+            if (self.written & (1 << channel)) != 0 && Some(channel) != skip {
+                let (_, freq_h) = self.channel_freq(channel);
+                chip.write_reg(AL_FREQ_H + channel as u32, freq_h & !0x20);
+            }
+        }
+        if self.rhythm {
+            chip.write_reg(AL_RHYTHM, self.rhythm_val & !0x1f);
+        }
+    }
+
+    // restores the key state of the music after a `key_off`
+    fn key_on(&self, chip: &mut Chip, skip: Option<usize>) {
+        for channel in 0..9 {
+            if (self.written & (1 << channel)) != 0 && Some(channel) != skip {
+                let (_, freq_h) = self.channel_freq(channel);
+                chip.write_reg(AL_FREQ_H + channel as u32, freq_h);
+            }
+        }
+        if self.rhythm {
+            chip.write_reg(AL_RHYTHM, self.rhythm_val);
+        }
+    }
+
+    fn is_transposed(&self, channel: usize) -> bool {
+        // the percussion channels keep their pitch in rhythm mode
+        self.factor != RATE_ONE && !(self.rhythm && channel >= 6)
+    }
+
+    fn write_channel(&self, chip: &mut Chip, channel: usize) {
+        let (freq_l, freq_h) = self.channel_freq(channel);
+        chip.write_reg(AL_FREQ_L + channel as u32, freq_l);
+        chip.write_reg(AL_FREQ_H + channel as u32, freq_h);
+    }
+
+    // (freq_l, freq_h) of the channel as written to the chip
+    fn channel_freq(&self, channel: usize) -> (u8, u8) {
+        let freq_h = self.freq_h[channel];
+        if !self.is_transposed(channel) {
+            return (self.freq_l[channel], freq_h);
+        }
+        let f_num = self.freq_l[channel] as u32 | ((freq_h as u32 & 3) << 8);
+        let mut block = (freq_h >> 2) & 7;
+
+        let mut f_num = ((f_num as u64 * self.factor as u64) >> 16) as u32;
+        while f_num > 0x3ff && block < 7 {
+            f_num >>= 1;
+            block += 1;
+        }
+        let f_num = f_num.min(0x3ff);
+
+        (
+            f_num as u8,
+            (freq_h & 0xe0) | (block << 2) | (f_num >> 8) as u8,
+        )
+    }
+}
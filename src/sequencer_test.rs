@@ -0,0 +1,560 @@
+use core::time::Duration;
+
+use crate::Error;
+use crate::chip::AdlSound;
+use crate::digi::{DIGI_VOICES, DigiSound};
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{GAIN_ONE, ImfOptions, LoopMode, Sequencer, SfxChannelMode, map_music_reg};
+
+const TEST_RATE: u32 = 44100;
+// 100 ticks per second
+const TEST_SAMPLES_PER_TICK: u32 = 441;
+
+// key on and off a note on channel 0, 1 second in total
+const TRACK: [u8; 16] = [
+    0xa0, 0x44, 0x00, 0x00, //
+    0xb0, 0x32, 0x32, 0x00, //
+    0xb0, 0x12, 0x32, 0x00, //
+    0x00, 0x00, 0x00, 0x00, //
+];
+
+fn test_sequencer() -> Sequencer {
+    Sequencer::new(TEST_RATE, TEST_RATE / TEST_SAMPLES_PER_TICK, 25).unwrap()
+}
+
+fn render_secs(sequencer: &mut Sequencer, secs: usize) {
+    let mut buf = vec![0i16; TEST_RATE as usize * 2];
+    for _ in 0..secs {
+        sequencer.generate(&mut buf);
+    }
+}
+
+#[test]
+fn test_clock_rates() {
+    assert!(matches!(
+        Sequencer::new(TEST_RATE, TEST_RATE + 1, 140),
+        Err(Error::ClockRate { kind: "IMF", .. })
+    ));
+    assert!(matches!(
+        Sequencer::new(TEST_RATE, 0, 140),
+        Err(Error::ClockRate { kind: "IMF", .. })
+    ));
+    assert!(matches!(
+        Sequencer::new(TEST_RATE, 560, 561),
+        Err(Error::ClockRate { kind: "ADL", .. })
+    ));
+    assert!(matches!(
+        Sequencer::new(TEST_RATE, 560, 0),
+        Err(Error::ClockRate { kind: "ADL", .. })
+    ));
+
+    // the fastest clocks tick on every sample
+    let mut sequencer = Sequencer::new(TEST_RATE, TEST_RATE, TEST_RATE).expect("sequencer");
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    assert!(sequencer.play_adl(sound));
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    let mut buf = [0i16; 64];
+    sequencer.generate(&mut buf);
+    assert!(!sequencer.is_adl_playing());
+}
+
+#[test]
+fn test_imf_play_once() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(
+        TRACK.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Once,
+            fade_out_ms: 0,
+        },
+    );
+    assert!(sequencer.is_imf_playing());
+    render_secs(&mut sequencer, 2);
+    assert!(!sequencer.is_imf_playing());
+}
+
+#[test]
+fn test_imf_loop_count() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(
+        TRACK.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Count(3),
+            fade_out_ms: 500,
+        },
+    );
+    render_secs(&mut sequencer, 2);
+    assert!(sequencer.is_imf_playing());
+    render_secs(&mut sequencer, 2);
+    assert!(!sequencer.is_imf_playing());
+}
+
+#[test]
+fn test_imf_fade_out() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(
+        TRACK.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Count(2),
+            fade_out_ms: 500,
+        },
+    );
+    // one music tick at a time
+    let mut buf = vec![0i16; TEST_SAMPLES_PER_TICK as usize * 2];
+    // the first loop is not faded
+    for _ in 0..100 {
+        sequencer.generate(&mut buf);
+        assert_eq!(sequencer.gain, GAIN_ONE);
+    }
+    let mut last_gain = sequencer.gain;
+    for _ in 0..100 {
+        sequencer.generate(&mut buf);
+        assert!(sequencer.gain <= last_gain);
+        last_gain = sequencer.gain;
+    }
+    assert!(sequencer.is_imf_playing());
+    assert_eq!(sequencer.gain, 0);
+}
+
+#[test]
+fn test_imf_loop_forever() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    render_secs(&mut sequencer, 5);
+    assert!(sequencer.is_imf_playing());
+}
+
+#[test]
+fn test_imf_position_reaches_duration() {
+    // the last command is followed by a delay of 20 ticks, 1 second in total
+    let track = [
+        0xa0, 0x44, 0x00, 0x00, //
+        0xb0, 0x32, 0x32, 0x00, //
+        0xb0, 0x12, 0x1e, 0x00, //
+        0x00, 0x00, 0x14, 0x00, //
+    ];
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(
+        track.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Once,
+            fade_out_ms: 0,
+        },
+    );
+    assert_eq!(sequencer.imf_duration(), Some(Duration::from_secs(1)));
+    // the track ends after the delay of its last command
+    render_secs(&mut sequencer, 1);
+    assert!(sequencer.is_imf_playing());
+    assert_eq!(sequencer.imf_position(), sequencer.imf_duration());
+
+    let mut buf = [0i16; 2];
+    sequencer.generate(&mut buf);
+    assert!(!sequencer.is_imf_playing());
+}
+
+#[test]
+fn test_imf_seek() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    assert_eq!(sequencer.imf_duration(), Some(Duration::from_secs(1)));
+    assert_eq!(sequencer.imf_position(), Some(Duration::ZERO));
+
+    sequencer.seek_imf(Duration::from_millis(300));
+    assert_eq!(sequencer.imf_position(), Some(Duration::from_millis(300)));
+
+    // clamped to the start of the last command
+    sequencer.seek_imf(Duration::from_secs(10));
+    assert_eq!(sequencer.imf_position(), Some(Duration::from_secs(1)));
+}
+
+#[test]
+fn test_imf_tempo() {
+    let mut sequencer = test_sequencer();
+    sequencer.set_imf_tempo(2.0);
+    sequencer.set_imf_transpose(-12);
+    sequencer.play_imf(
+        TRACK.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Count(3),
+            fade_out_ms: 0,
+        },
+    );
+    // 3 seconds of music at double speed
+    render_secs(&mut sequencer, 2);
+    assert!(!sequencer.is_imf_playing());
+}
+
+#[test]
+fn test_imf_tempo_clamped() {
+    let mut sequencer = test_sequencer();
+    sequencer.set_imf_tempo(1.0e9);
+    sequencer.play_imf(
+        TRACK.to_vec(),
+        ImfOptions {
+            loop_mode: LoopMode::Count(4),
+            fade_out_ms: 0,
+        },
+    );
+    // 4 seconds of music at the maximum speed of 8.0, the phase must not overflow
+    render_secs(&mut sequencer, 1);
+    assert!(!sequencer.is_imf_playing());
+
+    sequencer.set_imf_tempo(f32::NAN);
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    render_secs(&mut sequencer, 1);
+    assert!(sequencer.is_imf_playing());
+}
+
+fn render_ticks(sequencer: &mut Sequencer, ticks: usize) {
+    let mut buf = vec![0i16; TEST_SAMPLES_PER_TICK as usize * 2];
+    for _ in 0..ticks {
+        sequencer.generate(&mut buf);
+    }
+}
+
+#[test]
+fn test_imf_transpose_registers() {
+    let mut sequencer = test_sequencer();
+    // F-number 0x244 in block 4, keyed on
+    sequencer.set_imf_transpose(12);
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    render_ticks(&mut sequencer, 1);
+    // the doubled F-number does not fit, it is carried into the next block
+    assert_eq!(sequencer.chip.freq_regs(0), (0x44, 0x20 | (5 << 2) | 0x02));
+
+    sequencer.set_imf_transpose(-12);
+    assert_eq!(sequencer.chip.freq_regs(0), (0x22, 0x20 | (4 << 2) | 0x01));
+
+    // a new track does not key on with the frequency of the previous one
+    sequencer.play_imf(vec![0xa0, 0x50, 0x10, 0x00], ImfOptions::default());
+    render_ticks(&mut sequencer, 1);
+    assert_eq!(sequencer.chip.freq_regs(0), (0x28, 0x00));
+}
+
+#[test]
+fn test_imf_transpose_skips_sfx_channel() {
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    render_ticks(&mut sequencer, 1);
+    assert!(sequencer.play_adl(sound));
+    // the first note of the effect in block 4
+    render_ticks(&mut sequencer, 4);
+    assert_eq!(sequencer.chip.freq_regs(0), (0x33, 0x20 | (4 << 2)));
+
+    sequencer.set_imf_transpose(12);
+    assert_eq!(sequencer.chip.freq_regs(0), (0x33, 0x20 | (4 << 2)));
+}
+
+#[test]
+fn test_adl_rhythm_voice() {
+    let mut sound =
+        AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    // the snare drum plays the modulator settings at full level
+    sound.instrument.m_scale = 0x00;
+    sound.instrument.m_sus = 0x00;
+    sound.instrument.mode = 1;
+    sound.instrument.voice = 1;
+    let mut sequencer = test_sequencer();
+    assert!(sequencer.play_adl(sound.clone()));
+    render_ticks(&mut sequencer, 4);
+    // the snare is keyed on in rhythm mode, not on the melodic channel
+    assert_eq!(sequencer.chip.freq_regs(7), (0x33, 4 << 2));
+    assert_eq!(sequencer.chip.rhythm_reg(), 0x20 | 0x08);
+    assert_eq!(sequencer.chip.freq_regs(0), (0x00, 0x00));
+    assert!(render_peak(&mut sequencer) > 0);
+    assert!(!sequencer.is_adl_playing());
+    assert_eq!(sequencer.chip.rhythm_reg(), 0);
+
+    assert!(sequencer.play_adl(sound));
+    render_ticks(&mut sequencer, 4);
+    sequencer.stop_adl();
+    assert_eq!(sequencer.chip.rhythm_reg(), 0);
+}
+
+#[test]
+fn test_adl_priority() {
+    let mut sound =
+        AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    let mut sequencer = test_sequencer();
+
+    sound.priority = 10;
+    assert!(sequencer.play_adl(sound.clone()));
+    sound.priority = 9;
+    assert!(!sequencer.play_adl(sound.clone()));
+    assert_eq!(sequencer.adl_priority(), Some(10));
+    sound.priority = 10;
+    assert!(sequencer.play_adl(sound.clone()));
+
+    sequencer.stop_adl();
+    sound.priority = 0;
+    assert!(sequencer.play_adl(sound));
+}
+
+#[test]
+fn test_pc_sound() {
+    let mut sequencer = test_sequencer();
+    // 25 sound ticks per second with the test rates, the first one after 1764 samples
+    assert!(sequencer.play_pc(PcSound::new(5, vec![10; 25])));
+    assert!(!sequencer.play_pc(PcSound::new(4, vec![10; 25])));
+
+    let mut buf = vec![0i16; TEST_RATE as usize];
+    sequencer.generate(&mut buf);
+    assert!(sequencer.is_pc_playing());
+    assert!(buf[4000..].iter().all(|s| *s != 0), "speaker not sounding");
+
+    render_secs(&mut sequencer, 1);
+    assert!(!sequencer.is_pc_playing());
+    assert_eq!(sequencer.pc_priority(), None);
+}
+
+#[test]
+fn test_digi_sound() {
+    let mut sequencer = test_sequencer();
+    // 1s of a full scale square wave at a quarter of the mixer rate
+    let data: Vec<u8> = (0..TEST_RATE / 4)
+        .map(|i| if i % 2 == 0 { 0xff } else { 0x00 })
+        .collect();
+    assert!(sequencer.play_digi(1, DigiSound::new(TEST_RATE / 4, data)));
+    assert!(!sequencer.play_digi(DIGI_VOICES, DigiSound::new(TEST_RATE, vec![0xff])));
+    assert!(sequencer.is_digi_playing(1));
+
+    let mut buf = vec![0i16; TEST_RATE as usize];
+    sequencer.generate(&mut buf);
+    // resampled, every sample is repeated four times with linear interpolation in between
+    assert_eq!(buf[0], 32512);
+    assert!(buf[8] < 0);
+    assert!(sequencer.is_digi_playing(1));
+
+    sequencer.set_digi_volume(1, 0.5);
+    let mut buf = vec![0i16; TEST_RATE as usize * 2];
+    sequencer.generate(&mut buf);
+    assert!(buf.iter().all(|s| s.abs() <= 16384));
+    assert!(!sequencer.is_digi_playing(1));
+}
+
+// sets up an instrument on channel 0 and holds a note for 5 seconds
+const NOTE_TRACK: [u8; 40] = [
+    0x20, 0x01, 0x00, 0x00, //
+    0x40, 0x10, 0x00, 0x00, //
+    0x60, 0xf0, 0x00, 0x00, //
+    0x80, 0x77, 0x00, 0x00, //
+    0x23, 0x01, 0x00, 0x00, //
+    0x43, 0x00, 0x00, 0x00, //
+    0x63, 0xf0, 0x00, 0x00, //
+    0x83, 0x77, 0x00, 0x00, //
+    0xa0, 0x44, 0x00, 0x00, //
+    0xb0, 0x32, 0xf4, 0x01, //
+];
+
+fn render_peak(sequencer: &mut Sequencer) -> i16 {
+    let mut buf = vec![0i16; TEST_RATE as usize];
+    sequencer.generate(&mut buf);
+    buf.iter().map(|s| s.saturating_abs()).max().unwrap_or(0)
+}
+
+#[test]
+fn test_map_music_reg() {
+    let remap = SfxChannelMode::Remap(4);
+    // modulator and carrier of channel 0 move to the operators of channel 4
+    assert_eq!(map_music_reg(remap, 0, 0x20), Some(0x29));
+    assert_eq!(map_music_reg(remap, 0, 0x43), Some(0x4c));
+    assert_eq!(map_music_reg(remap, 0, 0xb0), Some(0xb4));
+    assert_eq!(map_music_reg(remap, 0, 0xc0), Some(0xc4));
+    // other channels and global registers are left alone
+    assert_eq!(map_music_reg(remap, 0, 0x21), Some(0x21));
+    assert_eq!(map_music_reg(remap, 0, 0xbd), Some(0xbd));
+
+    assert_eq!(map_music_reg(SfxChannelMode::Drop, 8, 0xf5), None);
+    assert_eq!(map_music_reg(SfxChannelMode::Drop, 8, 0xa8), None);
+    assert_eq!(map_music_reg(SfxChannelMode::Drop, 8, 0xa7), Some(0xa7));
+    assert_eq!(map_music_reg(SfxChannelMode::Shared, 0, 0xb0), Some(0xb0));
+
+    for mode in [
+        SfxChannelMode::Shared,
+        SfxChannelMode::Drop,
+        SfxChannelMode::Remap(5),
+        SfxChannelMode::SecondChip,
+    ] {
+        assert_eq!(SfxChannelMode::from_code(mode.to_code()), mode);
+    }
+}
+
+#[test]
+fn test_sfx_channel_modes() {
+    // the test track plays on channel 0, the effect channel
+    let mut sequencer = test_sequencer();
+    sequencer.set_sfx_channel_mode(SfxChannelMode::Drop);
+    sequencer.play_imf(NOTE_TRACK.to_vec(), ImfOptions::default());
+    assert_eq!(render_peak(&mut sequencer), 0);
+
+    let mut sequencer = test_sequencer();
+    sequencer.set_sfx_channel_mode(SfxChannelMode::Remap(3));
+    sequencer.play_imf(NOTE_TRACK.to_vec(), ImfOptions::default());
+    assert!(render_peak(&mut sequencer) > 0);
+}
+
+#[test]
+fn test_sfx_second_chip() {
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    let silent_track = [0x00, 0x00, 0x64, 0x00];
+
+    // the effect is audible on the second chip while the music has its own chip
+    let mut sequencer = test_sequencer();
+    sequencer.set_sfx_channel_mode(SfxChannelMode::SecondChip);
+    sequencer.play_imf(silent_track.to_vec(), ImfOptions::default());
+    assert!(sequencer.play_adl(sound.clone()));
+    assert!(render_peak(&mut sequencer) > 0);
+
+    // the effect does not touch the music on the same channel number
+    let mut sequencer = test_sequencer();
+    sequencer.set_sfx_channel_mode(SfxChannelMode::SecondChip);
+    sequencer.play_imf(NOTE_TRACK.to_vec(), ImfOptions::default());
+    let music_peak = render_peak(&mut sequencer);
+    assert!(music_peak > 0);
+    assert!(sequencer.play_adl(sound));
+    render_secs(&mut sequencer, 1);
+    assert!(!sequencer.is_adl_playing());
+    assert_eq!(render_peak(&mut sequencer), music_peak);
+}
+
+#[test]
+fn test_generate_channels() {
+    let sound = DigiSound::new(TEST_RATE, (0..=255).collect());
+
+    let mut stereo = test_sequencer();
+    stereo.play_digi(0, sound.clone());
+    let mut stereo_buf = vec![0i16; 512];
+    stereo.generate(&mut stereo_buf);
+
+    let mut quad = test_sequencer();
+    quad.play_digi(0, sound);
+    let mut quad_buf = vec![0i16; 1024];
+    quad.generate_channels(&mut quad_buf, 4);
+
+    for (frame, quad_frame) in stereo_buf.chunks(2).zip(quad_buf.chunks(4)) {
+        assert_eq!(quad_frame, [frame[0]; 4]);
+    }
+}
+
+#[test]
+fn test_play_without_track() {
+    let mut sequencer = test_sequencer();
+    let mut buf = vec![1i16; TEST_RATE as usize];
+    sequencer.generate(&mut buf);
+    assert!(buf.iter().all(|s| *s == 0), "idle output not silent");
+
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    assert!(sequencer.play_adl(sound));
+    assert!(render_peak(&mut sequencer) > 0);
+    assert!(!sequencer.is_adl_playing());
+
+    let mut sequencer = test_sequencer();
+    for cmd in NOTE_TRACK.chunks(4) {
+        sequencer.write_reg(cmd[0] as u32, cmd[1]);
+    }
+    assert!(render_peak(&mut sequencer) > 0);
+}
+
+#[test]
+fn test_volumes() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(NOTE_TRACK.to_vec(), ImfOptions::default());
+    let music_peak = render_peak(&mut sequencer) as i32;
+
+    sequencer.set_music_volume(0.5);
+    let half_peak = render_peak(&mut sequencer) as i32;
+    assert!(
+        (half_peak - music_peak / 2).abs() <= 2,
+        "{} {}",
+        half_peak,
+        music_peak
+    );
+
+    // the effect on the shared channel is still audible with the music off
+    let sound = AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    sequencer.set_music_volume(0.0);
+    assert_eq!(render_peak(&mut sequencer), 0);
+    assert!(sequencer.play_adl(sound.clone()));
+    assert!(render_peak(&mut sequencer) > 0);
+
+    sequencer.set_sfx_volume(0.0);
+    assert!(sequencer.play_adl(sound));
+    assert!(sequencer.play_pc(PcSound::new(0, vec![10; 25])));
+    assert_eq!(render_peak(&mut sequencer), 0);
+
+    sequencer.set_music_volume(1.0);
+    sequencer.set_sfx_volume(1.0);
+    sequencer.set_master_volume(0.0);
+    assert_eq!(render_peak(&mut sequencer), 0);
+}
+
+#[test]
+fn test_pause_imf() {
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(TRACK.to_vec(), ImfOptions::default());
+    sequencer.seek_imf(Duration::from_millis(300));
+    sequencer.pause_imf();
+    render_secs(&mut sequencer, 1);
+    assert_eq!(sequencer.imf_position(), Some(Duration::from_millis(300)));
+    sequencer.resume_imf();
+    let mut buf = vec![0i16; 8820];
+    sequencer.generate(&mut buf);
+    assert_eq!(sequencer.imf_position(), Some(Duration::from_millis(400)));
+
+    let mut sequencer = test_sequencer();
+    sequencer.play_imf(NOTE_TRACK.to_vec(), ImfOptions::default());
+    let music_peak = render_peak(&mut sequencer);
+
+    sequencer.pause_imf();
+    assert!(sequencer.is_imf_paused());
+    render_secs(&mut sequencer, 1);
+    assert_eq!(render_peak(&mut sequencer), 0);
+
+    // effects play while the music is paused
+    assert!(sequencer.play_pc(PcSound::new(0, vec![10; 25])));
+    assert!(render_peak(&mut sequencer) > 0);
+    sequencer.stop_pc();
+
+    sequencer.resume_imf();
+    assert!(!sequencer.is_imf_paused());
+    assert_eq!(render_peak(&mut sequencer), music_peak);
+}
+
+fn queue_note(sequencer: &mut Sequencer, at: u64) {
+    for cmd in NOTE_TRACK.chunks(4) {
+        sequencer.queue_write(at, cmd[0] as u32, cmd[1]);
+    }
+}
+
+#[test]
+fn test_queue_write() {
+    let mut sequencer = test_sequencer();
+    queue_note(&mut sequencer, 1000);
+    let mut buf = vec![0i16; 2000];
+    sequencer.generate_channels(&mut buf, 1);
+    assert_eq!(sequencer.sample_clock(), 2000);
+    assert!(buf[..1000].iter().all(|s| *s == 0));
+    assert!(buf[1000..1100].iter().any(|s| *s != 0));
+
+    // the writes land on the same frame regardless of the buffer size
+    let mut chunked = test_sequencer();
+    queue_note(&mut chunked, 1000);
+    let mut chunked_buf = vec![0i16; 2000];
+    for chunk in chunked_buf.chunks_mut(7) {
+        chunked.generate_channels(chunk, 1);
+    }
+    assert_eq!(chunked_buf, buf);
+
+    // writes in the past are applied right away, a cleared queue is dropped
+    let mut sequencer = test_sequencer();
+    sequencer.generate_channels(&mut buf, 1);
+    queue_note(&mut sequencer, 0);
+    sequencer.generate_channels(&mut buf, 1);
+    assert!(buf[..100].iter().any(|s| *s != 0));
+    sequencer.queue_write(5000, 0xb0, 0x12);
+    sequencer.clear_write_queue();
+    sequencer.generate_channels(&mut buf, 1);
+    sequencer.generate_channels(&mut buf, 1);
+    assert!(buf.iter().any(|s| *s != 0));
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::catalog::GameModule;
+
+#[cfg(all(test, feature = "headless"))]
+#[path = "./sound_manager_test.rs"]
+mod sound_manager_test;
+
+use crate::chip::AdlSound;
+use crate::digi::DigiSound;
+use crate::pcspeaker::PcSound;
+use crate::{Backend, Error};
+
+const DIGI_VOICE: usize = 0;
+
+/// Device the sound effects are played on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoundMode {
+    Off,
+    PcSpeaker,
+    AdLib,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MusicMode {
+    Off,
+    AdLib,
+}
+
+/// Device the digitized sounds are played on. Sounds without a digitized
+/// version (or with digitized sounds off) fall back to the `SoundMode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigiMode {
+    Off,
+    SoundBlaster,
+}
+
+/// Sound manager in the style of id's `SD_*` layer, loads the sounds and music
+/// of a game with the loaders of its `GameModule` and plays them on an initialised
+/// backend (`OPL` of the `sdl` or `web` feature, `headless::OPL` or `rodio::OPLHandle`).
+pub struct SoundManager<B: Backend> {
+    opl: B,
+    module: &'static GameModule,
+    game_path: PathBuf,
+
+    sound_mode: SoundMode,
+    music_mode: MusicMode,
+    digi_mode: DigiMode,
+    // sound number => digitized sound number in VSWAP
+    digi_map: HashMap<usize, usize>,
+
+    sound_number: Option<usize>,
+    digi_number: Option<usize>,
+    digi_priority: u16,
+    music_track: Option<Vec<u8>>,
+
+    adl_sounds: HashMap<usize, AdlSound>,
+    pc_sounds: HashMap<usize, PcSound>,
+    digi_sounds: HashMap<usize, DigiSound>,
+}
+
+impl<B: Backend> SoundManager<B> {
+    /// Creates a sound manager with sound and music on AdLib and digitized sounds off.
+    pub fn new(opl: B, module: &'static GameModule, game_path: &Path) -> SoundManager<B> {
+        SoundManager {
+            opl,
+            module,
+            game_path: game_path.to_path_buf(),
+            sound_mode: SoundMode::AdLib,
+            music_mode: MusicMode::AdLib,
+            digi_mode: DigiMode::Off,
+            digi_map: HashMap::new(),
+            sound_number: None,
+            digi_number: None,
+            digi_priority: 0,
+            music_track: None,
+            adl_sounds: HashMap::new(),
+            pc_sounds: HashMap::new(),
+            digi_sounds: HashMap::new(),
+        }
+    }
+
+    pub fn opl(&mut self) -> &mut B {
+        &mut self.opl
+    }
+
+    /// Sets which sounds have a digitized version (the `DigiMap` of the game).
+    pub fn set_digi_map(&mut self, digi_map: HashMap<usize, usize>) {
+        self.digi_map = digi_map;
+    }
+
+    pub fn sound_mode(&self) -> SoundMode {
+        self.sound_mode
+    }
+
+    /// Switches the sound effect device, stops the playing sound.
+    pub fn set_sound_mode(&mut self, mode: SoundMode) -> Result<(), Error> {
+        self.stop_sound()?;
+        self.sound_mode = mode;
+        Ok(())
+    }
+
+    pub fn music_mode(&self) -> MusicMode {
+        self.music_mode
+    }
+
+    pub fn set_music_mode(&mut self, mode: MusicMode) -> Result<(), Error> {
+        self.music_off()?;
+        self.music_mode = mode;
+        Ok(())
+    }
+
+    pub fn digi_mode(&self) -> DigiMode {
+        self.digi_mode
+    }
+
+    /// Switches the digitized sound device, stops the playing sound.
+    pub fn set_digi_mode(&mut self, mode: DigiMode) -> Result<(), Error> {
+        self.stop_sound()?;
+        self.digi_mode = mode;
+        Ok(())
+    }
+
+    /// Loads the track and starts it if the music is on AdLib.
+    pub fn start_music(&mut self, track_no: usize) -> Result<(), Error> {
+        self.music_off()?;
+        self.music_track = Some((self.module.track_loader)(&self.game_path, track_no)?);
+        self.music_on()
+    }
+
+    /// (Re)starts the last started track.
+    pub fn music_on(&mut self) -> Result<(), Error> {
+        if self.music_mode == MusicMode::AdLib
+            && let Some(track) = &self.music_track
+        {
+            self.opl.play_imf(track.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn music_off(&mut self) -> Result<(), Error> {
+        self.opl.stop_imf()?;
+        Ok(())
+    }
+
+    /// Plays the sound with id's rules: the digitized version is preferred, a sound
+    /// only interrupts a playing sound with an equal or lower priority.
+    /// Returns whether the sound was started.
+    pub fn play_sound(&mut self, sound_no: usize) -> Result<bool, Error> {
+        let priority = self.sound_priority(sound_no)?;
+
+        if self.digi_mode != DigiMode::Off
+            && let Some(&digi_no) = self.digi_map.get(&sound_no)
+        {
+            if self.opl.is_digi_playing(DIGI_VOICE)? && priority < self.digi_priority {
+                return Ok(false);
+            }
+            let digi = self.digi_sound(digi_no)?;
+            self.opl.play_digi(DIGI_VOICE, digi)?;
+            self.digi_number = Some(sound_no);
+            self.digi_priority = priority;
+            return Ok(true);
+        }
+
+        let started = match self.sound_mode {
+            SoundMode::Off => false,
+            SoundMode::PcSpeaker => {
+                let sound = self.pc_sound(sound_no)?;
+                self.opl.play_pc(sound)?
+            }
+            SoundMode::AdLib => {
+                let sound = self.adl_sound(sound_no)?;
+                self.opl.play_adl(sound)?
+            }
+        };
+        if started {
+            self.sound_number = Some(sound_no);
+        }
+        Ok(started)
+    }
+
+    /// The number of the playing sound (the digitized sound takes precedence).
+    pub fn sound_playing(&self) -> Result<Option<usize>, Error> {
+        if self.digi_number.is_some() && self.opl.is_digi_playing(DIGI_VOICE)? {
+            return Ok(self.digi_number);
+        }
+        let playing = match self.sound_mode {
+            SoundMode::Off => false,
+            SoundMode::PcSpeaker => self.opl.is_pc_playing()?,
+            SoundMode::AdLib => self.opl.is_adl_playing()?,
+        };
+        Ok(if playing { self.sound_number } else { None })
+    }
+
+    pub fn stop_sound(&mut self) -> Result<(), Error> {
+        self.opl.stop_digi(DIGI_VOICE)?;
+        self.opl.stop_pc()?;
+        self.opl.stop_adl()?;
+        self.sound_number = None;
+        self.digi_number = None;
+        Ok(())
+    }
+
+    // the digitized sounds have no header, they use the priority of the AdLib or PC version
+    // (with the sound off no chunk is loaded, the digitized sounds then all have priority 0)
+    fn sound_priority(&mut self, sound_no: usize) -> Result<u16, Error> {
+        match self.sound_mode {
+            SoundMode::Off => Ok(0),
+            SoundMode::PcSpeaker => Ok(self.pc_sound(sound_no)?.priority),
+            SoundMode::AdLib => Ok(self.adl_sound(sound_no)?.priority),
+        }
+    }
+
+    fn adl_sound(&mut self, sound_no: usize) -> Result<AdlSound, Error> {
+        if let Some(sound) = self.adl_sounds.get(&sound_no) {
+            return Ok(sound.clone());
+        }
+        let data = (self.module.sound_loader)(&self.game_path, sound_no)?;
+        let sound = AdlSound::from_bytes(&data)?;
+        self.adl_sounds.insert(sound_no, sound.clone());
+        Ok(sound)
+    }
+
+    fn pc_sound(&mut self, sound_no: usize) -> Result<PcSound, Error> {
+        if let Some(sound) = self.pc_sounds.get(&sound_no) {
+            return Ok(sound.clone());
+        }
+        let data = (self.module.pc_sound_loader)(&self.game_path, sound_no)?;
+        let sound = PcSound::from_bytes(&data)?;
+        self.pc_sounds.insert(sound_no, sound.clone());
+        Ok(sound)
+    }
+
+    fn digi_sound(&mut self, digi_no: usize) -> Result<DigiSound, Error> {
+        if let Some(sound) = self.digi_sounds.get(&digi_no) {
+            return Ok(sound.clone());
+        }
+        let data = (self.module.digi_loader)(&self.game_path, digi_no)?;
+        let sound = DigiSound::new(self.module.metadata.digi_sample_rate, data);
+        self.digi_sounds.insert(digi_no, sound.clone());
+        Ok(sound)
+    }
+}
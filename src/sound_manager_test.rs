@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::catalog::{Game, GameModule, Metadata};
+use crate::chip::AdlSound;
+use crate::headless::{NullSink, OPL, OPLSettings};
+use crate::pcspeaker::PcSound;
+use crate::sound_manager::{DigiMode, MusicMode, SoundManager, SoundMode};
+use crate::{Backend, Error};
+
+// sound n has priority n * 10 and plays for 1 second, sound 99 does not exist
+static TEST_MODULE: GameModule = GameModule {
+    game: Game::W3D,
+    metadata: &TEST_METADATA,
+    inferrer: || true,
+    track_loader: load_track,
+    sound_loader: load_sound,
+    pc_sound_loader: load_pc_sound,
+    digi_loader: load_digi,
+};
+
+static TEST_METADATA: Metadata = Metadata {
+    name: "Test",
+    year: 1992,
+    imf_clock_rate: 560,
+    digi_sample_rate: 7000,
+    tracks: &[],
+};
+
+fn check_sound_no(sound_no: usize) -> Result<(), Error> {
+    if sound_no == 99 {
+        return Err(Error::OutOfRange {
+            kind: "sound",
+            index: sound_no,
+        });
+    }
+    Ok(())
+}
+
+fn load_track(_: &Path, _: usize) -> Result<Vec<u8>, Error> {
+    // a note for 1 second
+    Ok(vec![0xa0, 0x44, 0x30, 0x02, 0xb0, 0x32, 0x00, 0x00])
+}
+
+fn load_sound(_: &Path, sound_no: usize) -> Result<Vec<u8>, Error> {
+    check_sound_no(sound_no)?;
+    let mut sound =
+        AdlSound::from_bytes(include_bytes!("../testdata/test.adl")).expect("parse adl");
+    sound.priority = sound_no as u16 * 10;
+    sound.data = vec![0x40; 140];
+    Ok(sound.to_vec())
+}
+
+fn load_pc_sound(_: &Path, sound_no: usize) -> Result<Vec<u8>, Error> {
+    check_sound_no(sound_no)?;
+    Ok(PcSound::new(sound_no as u16 * 10, vec![0x40; 140]).to_vec())
+}
+
+fn load_digi(_: &Path, _: usize) -> Result<Vec<u8>, Error> {
+    Ok(vec![0xff; 7000])
+}
+
+fn test_manager() -> SoundManager<OPL<NullSink>> {
+    let mut opl = OPL::new(NullSink::new());
+    opl.init(OPLSettings::default()).expect("init");
+    SoundManager::new(opl, &TEST_MODULE, Path::new("."))
+}
+
+fn render(manager: &mut SoundManager<OPL<NullSink>>, duration: Duration) {
+    manager.opl().render(duration).expect("render");
+}
+
+#[test]
+fn test_sound_priority() {
+    let mut manager = test_manager();
+    assert!(manager.play_sound(1).expect("play"));
+    render(&mut manager, Duration::from_millis(100));
+    assert_eq!(manager.sound_playing().expect("playing"), Some(1));
+
+    // a lower priority does not interrupt, an equal or higher one does
+    assert!(!manager.play_sound(0).expect("play"));
+    assert_eq!(manager.sound_playing().expect("playing"), Some(1));
+    assert!(manager.play_sound(1).expect("play"));
+    assert!(manager.play_sound(2).expect("play"));
+    render(&mut manager, Duration::from_millis(100));
+    assert_eq!(manager.sound_playing().expect("playing"), Some(2));
+
+    render(&mut manager, Duration::from_secs(2));
+    assert_eq!(manager.sound_playing().expect("playing"), None);
+    assert!(manager.play_sound(0).expect("play"));
+    assert!(matches!(
+        manager.play_sound(99),
+        Err(Error::OutOfRange { index: 99, .. })
+    ));
+}
+
+#[test]
+fn test_sound_mode() {
+    let mut manager = test_manager();
+    manager.set_sound_mode(SoundMode::PcSpeaker).expect("mode");
+    assert!(manager.play_sound(1).expect("play"));
+    render(&mut manager, Duration::from_millis(100));
+    assert!(manager.opl().is_pc_playing().expect("pc playing"));
+    assert!(!manager.opl().is_adl_playing().expect("adl playing"));
+    assert_eq!(manager.sound_playing().expect("playing"), Some(1));
+
+    // switching stops the sound, with the sound off nothing is loaded
+    manager.set_sound_mode(SoundMode::Off).expect("mode");
+    assert!(!manager.opl().is_pc_playing().expect("pc playing"));
+    assert_eq!(manager.sound_playing().expect("playing"), None);
+    assert!(!manager.play_sound(99).expect("play"));
+
+    // the digitized version is preferred, also with the sound off
+    manager.set_digi_map(HashMap::from([(1, 0)]));
+    manager.set_digi_mode(DigiMode::SoundBlaster).expect("mode");
+    assert!(manager.play_sound(1).expect("play"));
+    render(&mut manager, Duration::from_millis(100));
+    assert!(manager.opl().is_digi_playing(0).expect("digi playing"));
+    assert_eq!(manager.sound_playing().expect("playing"), Some(1));
+
+    manager.set_sound_mode(SoundMode::AdLib).expect("mode");
+    manager.set_digi_mode(DigiMode::Off).expect("mode");
+    assert!(manager.play_sound(1).expect("play"));
+    render(&mut manager, Duration::from_millis(100));
+    assert!(manager.opl().is_adl_playing().expect("adl playing"));
+    assert!(!manager.opl().is_digi_playing(0).expect("digi playing"));
+}
+
+// the track stays loaded when stopped, only its position shows whether it plays
+fn music_position(manager: &mut SoundManager<OPL<NullSink>>) -> Duration {
+    let position = manager.opl().imf_position().expect("position");
+    position.expect("track loaded")
+}
+
+#[test]
+fn test_music() {
+    let mut manager = test_manager();
+    manager.start_music(0).expect("start music");
+    render(&mut manager, Duration::from_millis(100));
+    let position = music_position(&mut manager);
+    assert!(position > Duration::ZERO);
+
+    manager.music_off().expect("music off");
+    render(&mut manager, Duration::from_millis(100));
+    assert_eq!(music_position(&mut manager), position);
+
+    // restarts the track
+    manager.music_on().expect("music on");
+    assert_eq!(music_position(&mut manager), Duration::ZERO);
+    render(&mut manager, Duration::from_millis(100));
+    assert!(music_position(&mut manager) > Duration::ZERO);
+
+    // with the music off the track is kept, but not played
+    manager.set_music_mode(MusicMode::Off).expect("mode");
+    let position = music_position(&mut manager);
+    manager.music_on().expect("music on");
+    render(&mut manager, Duration::from_millis(100));
+    assert_eq!(music_position(&mut manager), position);
+
+    manager.set_music_mode(MusicMode::AdLib).expect("mode");
+    manager.music_on().expect("music on");
+    assert_eq!(music_position(&mut manager), Duration::ZERO);
+}
@@ -0,0 +1,85 @@
+#[cfg(test)]
+#[path = "./spsc_test.rs"]
+mod spsc_test;
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Bounded single-producer/single-consumer queue. Neither side ever blocks,
+// `push` fails when the queue is full. `head` and `tail` count the popped and
+// pushed items, the slot of an item is its count modulo the capacity.
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// only the producer writes a free slot and only the consumer reads a filled one
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            unsafe { self.slots[head & self.mask].get_mut().assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+pub(crate) struct Producer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+pub(crate) struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Creates a queue for at least `capacity` items (rounded up to a power of two).
+pub(crate) fn ring<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let slots = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let ring = Arc::new(Ring {
+        slots,
+        mask: capacity - 1,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+impl<T> Producer<T> {
+    /// Appends the item, gives it back if the queue is full.
+    pub(crate) fn push(&mut self, item: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) > ring.mask {
+            return Err(item);
+        }
+        unsafe { (*ring.slots[tail & ring.mask].get()).write(item) };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let ring = &*self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let item = unsafe { (*ring.slots[head & ring.mask].get()).assume_init_read() };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+}
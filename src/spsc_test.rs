@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::spsc::ring;
+
+#[test]
+fn test_ring_order_and_capacity() {
+    let (mut producer, mut consumer) = ring(3);
+    assert_eq!(consumer.pop(), None);
+    for i in 0..4 {
+        assert_eq!(producer.push(i), Ok(()));
+    }
+    assert_eq!(producer.push(4), Err(4));
+
+    // wraps around
+    for round in 0..10 {
+        assert_eq!(consumer.pop(), Some(round));
+        assert_eq!(producer.push(round + 4), Ok(()));
+    }
+    assert_eq!(consumer.pop(), Some(10));
+}
+
+#[test]
+fn test_ring_threads() {
+    const ITEMS: usize = 100_000;
+    let (mut producer, mut consumer) = ring(64);
+    let writer = thread::spawn(move || {
+        for i in 0..ITEMS {
+            let mut item = i;
+            while let Err(back) = producer.push(item) {
+                item = back;
+                thread::yield_now();
+            }
+        }
+    });
+
+    let mut expected = 0;
+    while expected < ITEMS {
+        match consumer.pop() {
+            Some(item) => {
+                assert_eq!(item, expected);
+                expected += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    writer.join().expect("writer");
+}
+
+struct Counted(Arc<AtomicUsize>);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_ring_drops_queued_items() {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let (mut producer, mut consumer) = ring(8);
+    for _ in 0..5 {
+        assert!(producer.push(Counted(drops.clone())).is_ok());
+    }
+    drop(consumer.pop());
+    assert_eq!(drops.load(Ordering::SeqCst), 1);
+    drop(producer);
+    drop(consumer);
+    assert_eq!(drops.load(Ordering::SeqCst), 5);
+}
@@ -1,54 +1,150 @@
 use crate::chip::AdlSound;
+use crate::digi::{DIGI_VOICES, DigiSound};
+use crate::pcspeaker::PcSound;
+use crate::sequencer::{ImfOptions, SfxChannelMode, check_clock_rates};
+use crate::{Backend, Error};
 
-use js_sys::{Object, Reflect, Uint8Array};
-use std::cell::RefCell;
+use js_sys::{Atomics, Float64Array, Int32Array, Object, Reflect, SharedArrayBuffer, Uint8Array};
+use std::cell::{Cell, RefCell};
+use std::future::poll_fn;
 use std::rc::Rc;
+use std::task::{Poll, Waker};
+use std::time::Duration;
 use wasm_bindgen::JsValue;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{AudioContext, AudioWorkletNode, AudioWorkletNodeOptions};
+use web_sys::{AudioContext, AudioContextOptions, AudioWorkletNode, AudioWorkletNodeOptions};
 
+// called when a sound or track ended, shared with the message handler of the worklet
+type EndCallback = Rc<RefCell<Option<Box<dyn FnMut()>>>>;
+type DigiEndCallbacks = Rc<RefCell<[Option<Box<dyn FnMut()>>; DIGI_VOICES]>>;
+
+/// The OPL played by an AudioWorklet. The calls post messages to the worklet
+/// (register writes go through a shared queue if available, see `has_shared_queue`),
+/// the status reflects the commands sent and the messages of the worklet. It reports
+/// the position and duration of the track about every 100ms, both are `None` until
+/// the first report arrived.
 pub struct OPL {
     audio_ctx: AudioContext,
     node: Option<Rc<AudioWorkletNode>>,
 
-    on_adl_end: Rc<RefCell<Option<Box<dyn FnMut()>>>>,
+    on_adl_end: EndCallback,
+    // priority of the sound effect playing in the worklet
+    adl_priority: Rc<Cell<Option<u16>>>,
+    on_pc_end: EndCallback,
+    pc_priority: Rc<Cell<Option<u16>>>,
+    on_digi_end: DigiEndCallbacks,
+    digi_playing: Rc<[Cell<bool>; DIGI_VOICES]>,
+    on_imf_end: EndCallback,
+    // a track is loaded (also if stopped), as `Sequencer::is_imf_playing`
+    imf_loaded: Rc<Cell<bool>>,
+    // (position, duration) as last reported by the worklet
+    imf_progress: Rc<Cell<Option<(Duration, Duration)>>>,
+    imf_paused: bool,
+    // a track is playing and not stopped
+    imf_active: Rc<Cell<bool>>,
+    // number of times a sound effect / a track ended or was stopped
+    adl_ends: Rc<Cell<u64>>,
+    imf_ends: Rc<Cell<u64>>,
+    // tasks waiting in `adl_finished` or `imf_finished`
+    wakers: Rc<RefCell<Vec<Waker>>>,
+    // sequence number of the last play command of each kind, echoed by the worklet
+    // in its end messages so that the end of a replaced sound or track is ignored
+    adl_seq: Rc<Cell<u32>>,
+    pc_seq: Rc<Cell<u32>>,
+    imf_seq: Rc<Cell<u32>>,
+    digi_seq: Rc<[Cell<u32>; DIGI_VOICES]>,
+    // register writes shared with the worklet, if the page is cross-origin isolated
+    reg_queue: Option<RegQueue>,
+    // number of commands posted to the worklet, orders the queued writes after them
+    messages_sent: u32,
 }
 
+/// The settings of `sdl::OPL`, with the fields that do not apply to the browser ignored.
+#[derive(Default)]
 pub struct OPLSettings {
+    /// Sample rate of the AudioContext, 0 for the rate of the output device.
+    pub mixer_rate: u32,
     pub imf_clock_rate: u32,
     pub adl_clock_rate: u32,
+    /// Melodic channel (0-8) the ADL sound effects are played on.
+    pub adl_channel: u8,
+    /// How the music is kept off the effect channel.
+    pub sfx_channel_mode: SfxChannelMode,
+    /// Ignored, the AudioContext plays on the default output device.
+    pub device_name: Option<String>,
+    /// Ignored, the worklet renders blocks of 128 frames.
+    pub buffer_size: u16,
+    /// Number of output channels (all get the same signal), 0 for stereo.
+    pub channels: u8,
 }
 
 impl OPL {
-    pub async fn new() -> Result<OPL, &'static str> {
-        let audio_ctx = AudioContext::new().map_err(|_| "err init AudioContext")?;
-        let worklet = audio_ctx
-            .audio_worklet()
-            .map_err(|_| "err getting audio worklet")?;
-        let module_add = worklet
-            .add_module("oplProcessor.js")
-            .map_err(|_| "err start oplProcessor.js")?;
-        JsFuture::from(module_add)
-            .await
-            .map_err(|_| "err adding oplProcessor.js")?;
-
+    pub async fn new() -> Result<OPL, Error> {
+        let audio_ctx = new_audio_context(0).await?;
         Ok(OPL {
             audio_ctx,
             node: None,
             on_adl_end: Rc::new(RefCell::new(None)),
+            adl_priority: Rc::new(Cell::new(None)),
+            on_pc_end: Rc::new(RefCell::new(None)),
+            pc_priority: Rc::new(Cell::new(None)),
+            on_digi_end: Rc::new(RefCell::new(Default::default())),
+            digi_playing: Rc::new(Default::default()),
+            on_imf_end: Rc::new(RefCell::new(None)),
+            imf_loaded: Rc::new(Cell::new(false)),
+            imf_progress: Rc::new(Cell::new(None)),
+            imf_paused: false,
+            imf_active: Rc::new(Cell::new(false)),
+            adl_ends: Rc::new(Cell::new(0)),
+            imf_ends: Rc::new(Cell::new(0)),
+            wakers: Rc::new(RefCell::new(Vec::new())),
+            adl_seq: Rc::new(Cell::new(0)),
+            pc_seq: Rc::new(Cell::new(0)),
+            imf_seq: Rc::new(Cell::new(0)),
+            digi_seq: Rc::new(Default::default()),
+            reg_queue: None,
+            messages_sent: 0,
         })
     }
 
     /// Initialises the background AudioWorklet. This function must be called
     /// from a user-context in a webbrowser. Otherwise the AudioContext init
     /// is denied by the browser.
-    pub async fn init(&mut self, settings: OPLSettings) -> Result<(), String> {
+    pub async fn init(&mut self, settings: OPLSettings) -> Result<(), Error> {
+        if settings.mixer_rate != 0 && settings.mixer_rate != self.audio_ctx.sample_rate() as u32 {
+            let audio_ctx = new_audio_context(settings.mixer_rate).await?;
+            // the old context has no node yet, closing it only frees its resources
+            let _ = self.audio_ctx.close();
+            self.audio_ctx = audio_ctx;
+        }
+        // the worklet falls back to a 700 Hz music and a 140 Hz sound effect clock,
+        // it can't report rates it rejects
+        let imf_clock_rate = if settings.imf_clock_rate != 0 {
+            settings.imf_clock_rate
+        } else {
+            700
+        };
+        let adl_clock_rate = if settings.adl_clock_rate != 0 {
+            settings.adl_clock_rate
+        } else {
+            140
+        };
+        check_clock_rates(
+            self.audio_ctx.sample_rate() as u32,
+            imf_clock_rate,
+            adl_clock_rate,
+        )?;
         let wasm_bytes = include_bytes!("../web/worklet.wasm");
+        let channels = if settings.channels != 0 {
+            settings.channels
+        } else {
+            2
+        };
 
         let options = AudioWorkletNodeOptions::new();
         options.set_number_of_outputs(1);
-        options.set_output_channel_count(&js_sys::Array::of1(&2.into()));
+        options.set_output_channel_count(&js_sys::Array::of1(&channels.into()));
 
         let processor_options = js_sys::Object::new();
         js_sys::Reflect::set(
@@ -56,40 +152,125 @@ impl OPL {
             &JsValue::from_str("wasmBytes"),
             &js_sys::Uint8Array::from(wasm_bytes.as_slice()),
         )
-        .map_err(|_| "err setting wasm bytes")?;
+        .map_err(js_err("err setting wasm bytes"))?;
         js_sys::Reflect::set(
             &processor_options,
             &JsValue::from_str("mixerRate"),
             &(self.audio_ctx.sample_rate() as u32).into(),
         )
-        .map_err(|_| "err setting mixerRate")?;
+        .map_err(js_err("err setting mixerRate"))?;
         js_sys::Reflect::set(
             &processor_options,
             &JsValue::from_str("imfClockRate"),
             &settings.imf_clock_rate.into(),
         )
-        .map_err(|_| "err setting imfClockRate")?;
+        .map_err(js_err("err setting imfClockRate"))?;
         js_sys::Reflect::set(
             &processor_options,
             &JsValue::from_str("adlClockRate"),
             &settings.adl_clock_rate.into(),
         )
-        .map_err(|_| "err setting adlClockRate")?;
+        .map_err(js_err("err setting adlClockRate"))?;
+        js_sys::Reflect::set(
+            &processor_options,
+            &JsValue::from_str("adlChannel"),
+            &settings.adl_channel.into(),
+        )
+        .map_err(js_err("err setting adlChannel"))?;
+        js_sys::Reflect::set(
+            &processor_options,
+            &JsValue::from_str("sfxChannelMode"),
+            &settings.sfx_channel_mode.to_code().into(),
+        )
+        .map_err(js_err("err setting sfxChannelMode"))?;
+        let reg_queue = RegQueue::new();
+        if let Some(queue) = &reg_queue {
+            js_sys::Reflect::set(
+                &processor_options,
+                &JsValue::from_str("regQueue"),
+                &queue.buffer,
+            )
+            .map_err(js_err("err setting regQueue"))?;
+        }
 
         options.set_processor_options(Some(&processor_options.into()));
 
         let node = AudioWorkletNode::new_with_options(&self.audio_ctx, "opl-processor", &options)
-            .map_err(|_| "err creating AudioWorkletNode")?;
+            .map_err(js_err("err creating AudioWorkletNode"))?;
 
         let on_adl_end_clone = self.on_adl_end.clone();
+        let adl_priority_clone = self.adl_priority.clone();
+        let adl_seq_clone = self.adl_seq.clone();
+        let on_pc_end_clone = self.on_pc_end.clone();
+        let pc_priority_clone = self.pc_priority.clone();
+        let pc_seq_clone = self.pc_seq.clone();
+        let on_digi_end_clone = self.on_digi_end.clone();
+        let digi_playing_clone = self.digi_playing.clone();
+        let digi_seq_clone = self.digi_seq.clone();
+        let imf_loaded_clone = self.imf_loaded.clone();
+        let on_imf_end_clone = self.on_imf_end.clone();
+        let imf_progress_clone = self.imf_progress.clone();
+        let imf_active_clone = self.imf_active.clone();
+        let imf_seq_clone = self.imf_seq.clone();
+        let adl_ends_clone = self.adl_ends.clone();
+        let imf_ends_clone = self.imf_ends.clone();
+        let wakers_clone = self.wakers.clone();
         let on_message = Closure::<dyn FnMut(web_sys::MessageEvent)>::new(
             move |event: web_sys::MessageEvent| {
                 let data = event.data();
-                if let Some(cmd) = data.as_string() {
-                    if cmd == "adl_finished" {
-                        if let Some(mut cb) = on_adl_end_clone.borrow_mut().take() {
-                            cb()
-                        }
+                let Some(cmd) = read_cmd(&data) else {
+                    return;
+                };
+                // messages of a sound or track that was replaced in the meantime are dropped
+                let seq = read_number(&data, "seq").map(|seq| seq as u32);
+                if cmd == "adl_finished" {
+                    if seq != Some(adl_seq_clone.get()) {
+                        return;
+                    }
+                    if adl_priority_clone.take().is_some() {
+                        count_end(&adl_ends_clone, &wakers_clone);
+                    }
+                    if let Some(mut cb) = on_adl_end_clone.borrow_mut().take() {
+                        cb()
+                    }
+                } else if cmd == "pc_finished" {
+                    if seq != Some(pc_seq_clone.get()) {
+                        return;
+                    }
+                    pc_priority_clone.set(None);
+                    if let Some(mut cb) = on_pc_end_clone.borrow_mut().take() {
+                        cb()
+                    }
+                } else if cmd == "imf_finished" {
+                    if seq != Some(imf_seq_clone.get()) {
+                        return;
+                    }
+                    imf_progress_clone.set(None);
+                    imf_loaded_clone.set(false);
+                    if imf_active_clone.replace(false) {
+                        count_end(&imf_ends_clone, &wakers_clone);
+                    }
+                    if let Some(cb) = on_imf_end_clone.borrow_mut().as_mut() {
+                        cb()
+                    }
+                } else if cmd == "imf_position" {
+                    if seq != Some(imf_seq_clone.get()) {
+                        return;
+                    }
+                    if let Some(progress) = read_imf_progress(&data) {
+                        imf_progress_clone.set(Some(progress));
+                    }
+                } else if cmd == "digi_finished" {
+                    let Some(voice) = read_voice(&data) else {
+                        return;
+                    };
+                    if seq != Some(digi_seq_clone[voice].get()) {
+                        return;
+                    }
+                    digi_playing_clone[voice].set(false);
+                    let on_end = on_digi_end_clone.borrow_mut()[voice].take();
+                    if let Some(mut cb) = on_end {
+                        cb()
                     }
                 }
             },
@@ -100,63 +281,531 @@ impl OPL {
         on_message.forget();
 
         node.connect_with_audio_node(&self.audio_ctx.destination())
-            .map_err(|_| "err connecting with audio node")?;
+            .map_err(js_err("err connecting with audio node"))?;
 
-        JsFuture::from(self.audio_ctx.resume().map_err(|_| "resume failed")?)
+        JsFuture::from(self.audio_ctx.resume().map_err(js_err("resume failed"))?)
             .await
-            .map_err(|_| "failed to resume audio context")?;
+            .map_err(js_err("failed to resume audio context"))?;
 
         self.node = Some(Rc::new(node));
+        self.reg_queue = reg_queue;
+        self.messages_sent = 0;
 
         Ok(())
     }
 
-    pub fn play_imf(&mut self, data: Vec<u8>) -> Result<(), &'static str> {
-        self.send_data_cmd("play_imf", data)
+    /// Registers a function that is called each time an IMF track ended (which
+    /// never happens with `LoopMode::Forever`).
+    pub fn on_imf_end<F>(&mut self, on_end: F) -> Result<(), Error>
+    where
+        F: FnMut() + 'static,
+    {
+        *self.on_imf_end.borrow_mut() = Some(Box::new(on_end));
+        Ok(())
     }
 
-    pub fn play_adl<F>(&mut self, sound: AdlSound, on_end: F) -> Result<(), &'static str>
+    /// `play_adl` with a function called when the sound played to the end,
+    /// `on_end` is only called for started sounds.
+    pub fn play_adl_with_end<F>(&mut self, sound: AdlSound, on_end: F) -> Result<bool, Error>
     where
         F: FnMut() + 'static,
     {
-        *self.on_adl_end.borrow_mut() = Some(Box::new(on_end));
+        if let Some(playing) = self.adl_priority.get()
+            && sound.priority < playing
+        {
+            return Ok(false);
+        }
+
+        // the worklet parses the bytes again, a sound it would reject is reported here
         let data = sound.to_vec();
-        self.send_data_cmd("play_adl", data)
+        AdlSound::from_bytes(&data)?;
+        let cmd = data_cmd_object("play_adl", data)?;
+        let seq = set_next_seq(&cmd, &self.adl_seq)?;
+        self.send_cmd(cmd)?;
+        self.adl_seq.set(seq);
+        self.adl_priority.set(Some(sound.priority));
+        *self.on_adl_end.borrow_mut() = Some(Box::new(on_end));
+        Ok(true)
     }
 
-    pub fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), &'static str> {
-        let cmd = cmd_object("write_reg")?;
-        Reflect::set(&cmd, &"reg".into(), &reg.into()).map_err(|_| "err setting reg")?;
-        Reflect::set(&cmd, &"value".into(), &val.into()).map_err(|_| "err setting value")?;
-        self.send_cmd(cmd)
+    /// `play_pc` with a function called when the sound played to the end.
+    pub fn play_pc_with_end<F>(&mut self, sound: PcSound, on_end: F) -> Result<bool, Error>
+    where
+        F: FnMut() + 'static,
+    {
+        if let Some(playing) = self.pc_priority.get()
+            && sound.priority < playing
+        {
+            return Ok(false);
+        }
+
+        let cmd = data_cmd_object("play_pc", sound.to_vec())?;
+        let seq = set_next_seq(&cmd, &self.pc_seq)?;
+        self.send_cmd(cmd)?;
+        self.pc_seq.set(seq);
+        self.pc_priority.set(Some(sound.priority));
+        *self.on_pc_end.borrow_mut() = Some(Box::new(on_end));
+        Ok(true)
     }
 
-    pub fn stop_imf(&mut self) -> Result<(), &'static str> {
-        let cmd = cmd_object("stop_imf")?;
+    /// `play_digi` with a function called when the sound played to the end.
+    pub fn play_digi_with_end<F>(
+        &mut self,
+        voice: usize,
+        sound: DigiSound,
+        on_end: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut() + 'static,
+    {
+        if voice >= DIGI_VOICES {
+            return Err(Error::OutOfRange {
+                kind: "digi voice",
+                index: voice,
+            });
+        }
+        let cmd = data_cmd_object("play_digi", sound.data)?;
+        Reflect::set(&cmd, &"voice".into(), &(voice as u32).into())
+            .map_err(js_err("err setting voice"))?;
+        Reflect::set(&cmd, &"rate".into(), &sound.rate.into())
+            .map_err(js_err("err setting rate"))?;
+        let seq = set_next_seq(&cmd, &self.digi_seq[voice])?;
+        self.send_cmd(cmd)?;
+        self.digi_seq[voice].set(seq);
+        self.on_digi_end.borrow_mut()[voice] = Some(Box::new(on_end));
+        self.digi_playing[voice].set(true);
+        Ok(())
+    }
+
+    /// Applies the register write at the time `time` of the AudioContext (in
+    /// seconds, see `current_time`), to the sample inside the rendered block.
+    pub fn write_reg_at(&mut self, reg: u32, val: u8, time: f64) -> Result<(), Error> {
+        if self.queue_write(OP_WRITE_REG_AT, reg, val, time) {
+            return Ok(());
+        }
+        let cmd = cmd_object("write_reg_at")?;
+        Reflect::set(&cmd, &"reg".into(), &reg.into()).map_err(js_err("err setting reg"))?;
+        Reflect::set(&cmd, &"value".into(), &val.into()).map_err(js_err("err setting value"))?;
+        Reflect::set(&cmd, &"time".into(), &time.into()).map_err(js_err("err setting time"))?;
         self.send_cmd(cmd)
     }
 
-    fn send_data_cmd(&mut self, cmd_name: &'static str, data: Vec<u8>) -> Result<(), &'static str> {
+    /// Whether `write_reg` and `write_reg_at` pass the writes to the worklet
+    /// through a queue in a SharedArrayBuffer instead of messages. Needs a
+    /// cross-origin isolated page (served with the `Cross-Origin-Opener-Policy:
+    /// same-origin` and `Cross-Origin-Embedder-Policy: require-corp` headers).
+    pub fn has_shared_queue(&self) -> bool {
+        self.reg_queue.is_some()
+    }
+
+    /// The time of the AudioContext in seconds, the time base of `write_reg_at`.
+    pub fn current_time(&self) -> f64 {
+        self.audio_ctx.current_time()
+    }
+
+    /// Resolves when the track ended (never with `LoopMode::Forever`), was stopped
+    /// or replaced, right away if none is playing. Woken by the `imf_finished`
+    /// message of the worklet.
+    pub fn imf_finished(&self) -> Result<impl Future<Output = ()> + use<>, Error> {
+        let imf_active = self.imf_active.clone();
+        Ok(self.finished(&self.imf_ends, move || !imf_active.get()))
+    }
+
+    /// Resolves when the sound effect ended or was stopped, right away if none is
+    /// playing. A sound that replaces the playing one is waited for as well. Woken
+    /// by the `adl_finished` message of the worklet.
+    pub fn adl_finished(&self) -> Result<impl Future<Output = ()> + use<>, Error> {
+        let adl_priority = self.adl_priority.clone();
+        Ok(self.finished(&self.adl_ends, move || adl_priority.get().is_none()))
+    }
+
+    fn finished<F>(&self, ends: &Rc<Cell<u64>>, idle: F) -> impl Future<Output = ()> + use<F>
+    where
+        F: Fn() -> bool + 'static,
+    {
+        let ends = ends.clone();
+        let since = ends.get();
+        let wakers = self.wakers.clone();
+        poll_fn(move |cx| {
+            if ends.get() > since || idle() {
+                return Poll::Ready(());
+            }
+            let mut wakers = wakers.borrow_mut();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+
+    // false if there is no shared queue or it is full, the write is then sent as message
+    fn queue_write(&mut self, op: i32, reg: u32, val: u8, time: f64) -> bool {
+        match &self.reg_queue {
+            Some(queue) => queue.push(op, reg, val, time, self.messages_sent),
+            None => false,
+        }
+    }
+
+    fn send_volume_cmd(&mut self, cmd_name: &'static str, volume: f32) -> Result<(), Error> {
         let cmd = cmd_object(cmd_name)?;
-        let js_data = Uint8Array::from(&data[..]);
-        Reflect::set(&cmd, &"data".into(), &js_data).map_err(|_| "err setting data")?;
+        Reflect::set(&cmd, &"volume".into(), &volume.into())
+            .map_err(js_err("err setting volume"))?;
         self.send_cmd(cmd)
     }
 
-    fn send_cmd(&mut self, cmd: Object) -> Result<(), &'static str> {
+    fn send_cmd(&mut self, cmd: Object) -> Result<(), Error> {
         if let Some(node) = &self.node {
             node.port()
                 .unwrap()
                 .post_message(&cmd.into())
-                .map_err(|_| "err sending command")
+                .map_err(js_err("err sending command"))?;
+            self.messages_sent = self.messages_sent.wrapping_add(1);
+            Ok(())
         } else {
-            Err("OPL not initialised")
+            Err(Error::NotInitialised)
+        }
+    }
+}
+
+impl Backend for OPL {
+    fn play_imf_with_options(&mut self, data: Vec<u8>, options: ImfOptions) -> Result<(), Error> {
+        let cmd = data_cmd_object("play_imf", data)?;
+        Reflect::set(
+            &cmd,
+            &"loopCount".into(),
+            &options.loop_mode.to_count().into(),
+        )
+        .map_err(js_err("err setting loopCount"))?;
+        Reflect::set(&cmd, &"fadeOutMs".into(), &options.fade_out_ms.into())
+            .map_err(js_err("err setting fadeOutMs"))?;
+        let seq = set_next_seq(&cmd, &self.imf_seq)?;
+        self.imf_progress.set(None);
+        self.send_cmd(cmd)?;
+        self.imf_seq.set(seq);
+        self.imf_paused = false;
+        self.imf_loaded.set(true);
+        // a replaced track counts as ended
+        if self.imf_active.replace(true) {
+            count_end(&self.imf_ends, &self.wakers);
+        }
+        Ok(())
+    }
+
+    fn pause_imf(&mut self) -> Result<(), Error> {
+        let cmd = cmd_object("pause_imf")?;
+        self.send_cmd(cmd)?;
+        // like the sequencer, only a loaded track is paused
+        self.imf_paused = self.imf_loaded.get();
+        Ok(())
+    }
+
+    fn resume_imf(&mut self) -> Result<(), Error> {
+        let cmd = cmd_object("resume_imf")?;
+        self.send_cmd(cmd)?;
+        self.imf_paused = false;
+        Ok(())
+    }
+
+    fn is_imf_paused(&self) -> Result<bool, Error> {
+        // a track that ended is no longer paused
+        Ok(self.imf_paused && self.imf_loaded.get())
+    }
+
+    fn is_imf_playing(&self) -> Result<bool, Error> {
+        Ok(self.imf_loaded.get())
+    }
+
+    fn set_music_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.send_volume_cmd("set_music_volume", volume)
+    }
+
+    fn set_sfx_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.send_volume_cmd("set_sfx_volume", volume)
+    }
+
+    fn set_master_volume(&mut self, volume: f32) -> Result<(), Error> {
+        self.send_volume_cmd("set_master_volume", volume)
+    }
+
+    fn imf_duration(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.imf_progress.get().map(|(_, duration)| duration))
+    }
+
+    fn imf_position(&self) -> Result<Option<Duration>, Error> {
+        Ok(self.imf_progress.get().map(|(position, _)| position))
+    }
+
+    fn seek_imf(&mut self, position: Duration) -> Result<(), Error> {
+        let cmd = cmd_object("seek_imf")?;
+        Reflect::set(
+            &cmd,
+            &"positionMs".into(),
+            &(position.as_millis() as u32).into(),
+        )
+        .map_err(js_err("err setting positionMs"))?;
+        if let Some((_, duration)) = self.imf_progress.get() {
+            self.imf_progress
+                .set(Some((position.min(duration), duration)));
+        }
+        self.send_cmd(cmd)
+    }
+
+    fn set_imf_tempo(&mut self, tempo: f32) -> Result<(), Error> {
+        let cmd = cmd_object("set_imf_tempo")?;
+        Reflect::set(&cmd, &"tempo".into(), &tempo.into()).map_err(js_err("err setting tempo"))?;
+        self.send_cmd(cmd)
+    }
+
+    fn set_imf_transpose(&mut self, semitones: i8) -> Result<(), Error> {
+        let cmd = cmd_object("set_imf_transpose")?;
+        Reflect::set(&cmd, &"semitones".into(), &semitones.into())
+            .map_err(js_err("err setting semitones"))?;
+        self.send_cmd(cmd)
+    }
+
+    fn play_adl(&mut self, sound: AdlSound) -> Result<bool, Error> {
+        self.play_adl_with_end(sound, || {})
+    }
+
+    fn stop_adl(&mut self) -> Result<(), Error> {
+        let cmd = cmd_object("stop_adl")?;
+        self.send_cmd(cmd)?;
+        self.on_adl_end.borrow_mut().take();
+        if self.adl_priority.take().is_some() {
+            count_end(&self.adl_ends, &self.wakers);
+        }
+        Ok(())
+    }
+
+    fn is_adl_playing(&self) -> Result<bool, Error> {
+        Ok(self.adl_priority.get().is_some())
+    }
+
+    fn play_pc(&mut self, sound: PcSound) -> Result<bool, Error> {
+        self.play_pc_with_end(sound, || {})
+    }
+
+    fn stop_pc(&mut self) -> Result<(), Error> {
+        self.pc_priority.set(None);
+        self.on_pc_end.borrow_mut().take();
+        let cmd = cmd_object("stop_pc")?;
+        self.send_cmd(cmd)
+    }
+
+    fn is_pc_playing(&self) -> Result<bool, Error> {
+        Ok(self.pc_priority.get().is_some())
+    }
+
+    fn play_digi(&mut self, voice: usize, sound: DigiSound) -> Result<(), Error> {
+        self.play_digi_with_end(voice, sound, || {})
+    }
+
+    fn stop_digi(&mut self, voice: usize) -> Result<(), Error> {
+        if voice >= DIGI_VOICES {
+            return Err(Error::OutOfRange {
+                kind: "digi voice",
+                index: voice,
+            });
         }
+        self.on_digi_end.borrow_mut()[voice].take();
+        self.digi_playing[voice].set(false);
+        let cmd = cmd_object("stop_digi")?;
+        Reflect::set(&cmd, &"voice".into(), &(voice as u32).into())
+            .map_err(js_err("err setting voice"))?;
+        self.send_cmd(cmd)
+    }
+
+    fn is_digi_playing(&self, voice: usize) -> Result<bool, Error> {
+        Ok(self
+            .digi_playing
+            .get(voice)
+            .is_some_and(|playing| playing.get()))
+    }
+
+    fn set_digi_volume(&mut self, voice: usize, volume: f32) -> Result<(), Error> {
+        let cmd = cmd_object("set_digi_volume")?;
+        Reflect::set(&cmd, &"voice".into(), &(voice as u32).into())
+            .map_err(js_err("err setting voice"))?;
+        Reflect::set(&cmd, &"volume".into(), &volume.into())
+            .map_err(js_err("err setting volume"))?;
+        self.send_cmd(cmd)
+    }
+
+    fn write_reg(&mut self, reg: u32, val: u8) -> Result<(), Error> {
+        if self.queue_write(OP_WRITE_REG, reg, val, 0.0) {
+            return Ok(());
+        }
+        let cmd = cmd_object("write_reg")?;
+        Reflect::set(&cmd, &"reg".into(), &reg.into()).map_err(js_err("err setting reg"))?;
+        Reflect::set(&cmd, &"value".into(), &val.into()).map_err(js_err("err setting value"))?;
+        self.send_cmd(cmd)
+    }
+
+    fn stop_imf(&mut self) -> Result<(), Error> {
+        let cmd = cmd_object("stop_imf")?;
+        self.send_cmd(cmd)?;
+        if self.imf_active.replace(false) {
+            count_end(&self.imf_ends, &self.wakers);
+        }
+        Ok(())
+    }
+}
+
+// Layout of the register queue: a header with the number of read (`QUEUE_HEAD`,
+// advanced by the worklet) and written (`QUEUE_TAIL`) records, followed by
+// `QUEUE_RECORDS` records of the int32 words [op, reg, value, messages_before]
+// and the float64 time. `messages_before` is the number of messages posted before
+// the write, the worklet applies the write only after it received them so that
+// writes and commands stay in order. Must match oplProcessor.js.
+const QUEUE_RECORDS: u32 = 4096;
+const QUEUE_HEADER_BYTES: u32 = 16;
+const QUEUE_RECORD_BYTES: u32 = 32;
+const QUEUE_HEAD: u32 = 0;
+const QUEUE_TAIL: u32 = 1;
+const OP_WRITE_REG: i32 = 1;
+const OP_WRITE_REG_AT: i32 = 2;
+
+// Producer side of the lock-free single-producer/single-consumer register queue
+struct RegQueue {
+    buffer: SharedArrayBuffer,
+    header: Int32Array,
+    words: Int32Array,
+    times: Float64Array,
+}
+
+impl RegQueue {
+    // `None` if SharedArrayBuffers can not be shared with the worklet
+    fn new() -> Option<RegQueue> {
+        let isolated = Reflect::get(&js_sys::global(), &"crossOriginIsolated".into())
+            .ok()
+            .and_then(|isolated| isolated.as_bool())
+            .unwrap_or(false);
+        if !isolated {
+            return None;
+        }
+        let buffer =
+            SharedArrayBuffer::new(QUEUE_HEADER_BYTES + QUEUE_RECORDS * QUEUE_RECORD_BYTES);
+        let header = Int32Array::new_with_byte_offset_and_length(&buffer, 0, 2);
+        let words = Int32Array::new_with_byte_offset_and_length(
+            &buffer,
+            QUEUE_HEADER_BYTES,
+            QUEUE_RECORDS * QUEUE_RECORD_BYTES / 4,
+        );
+        let times = Float64Array::new_with_byte_offset_and_length(
+            &buffer,
+            QUEUE_HEADER_BYTES,
+            QUEUE_RECORDS * QUEUE_RECORD_BYTES / 8,
+        );
+        Some(RegQueue {
+            buffer,
+            header,
+            words,
+            times,
+        })
+    }
+
+    // false if the queue is full
+    fn push(&self, op: i32, reg: u32, val: u8, time: f64, messages_before: u32) -> bool {
+        let (Ok(head), Ok(tail)) = (
+            Atomics::load(&self.header, QUEUE_HEAD),
+            Atomics::load(&self.header, QUEUE_TAIL),
+        ) else {
+            return false;
+        };
+        if tail.wrapping_sub(head) as u32 >= QUEUE_RECORDS {
+            return false;
+        }
+        let record = tail as u32 & (QUEUE_RECORDS - 1);
+        let word = record * QUEUE_RECORD_BYTES / 4;
+        self.words.set_index(word, op);
+        self.words.set_index(word + 1, reg as i32);
+        self.words.set_index(word + 2, val as i32);
+        self.words.set_index(word + 3, messages_before as i32);
+        self.times
+            .set_index(record * QUEUE_RECORD_BYTES / 8 + 2, time);
+        // publishes the record, the atomic store orders the plain writes before it
+        Atomics::store(&self.header, QUEUE_TAIL, tail.wrapping_add(1)).is_ok()
     }
 }
 
-fn cmd_object(cmd_name: &'static str) -> Result<Object, &'static str> {
+async fn new_audio_context(mixer_rate: u32) -> Result<AudioContext, Error> {
+    let audio_ctx = if mixer_rate == 0 {
+        AudioContext::new()
+    } else {
+        let options = AudioContextOptions::new();
+        options.set_sample_rate(mixer_rate as f32);
+        AudioContext::new_with_context_options(&options)
+    }
+    .map_err(js_err("err init AudioContext"))?;
+    let worklet = audio_ctx
+        .audio_worklet()
+        .map_err(js_err("err getting audio worklet"))?;
+    let module_add = worklet
+        .add_module("oplProcessor.js")
+        .map_err(js_err("err start oplProcessor.js"))?;
+    JsFuture::from(module_add)
+        .await
+        .map_err(js_err("err adding oplProcessor.js"))?;
+    Ok(audio_ctx)
+}
+
+// counts the end of a sound or track and wakes the waiting tasks
+fn count_end(ends: &Cell<u64>, wakers: &RefCell<Vec<Waker>>) {
+    ends.set(ends.get() + 1);
+    let wakers = wakers.take();
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+fn js_err(msg: &'static str) -> impl FnOnce(JsValue) -> Error {
+    move |_| Error::Device(msg.into())
+}
+
+fn cmd_object(cmd_name: &'static str) -> Result<Object, Error> {
     let cmd = Object::new();
-    Reflect::set(&cmd, &"cmd".into(), &cmd_name.into()).map_err(|_| "err setting cmd")?;
+    Reflect::set(&cmd, &"cmd".into(), &cmd_name.into()).map_err(js_err("err setting cmd"))?;
     Ok(cmd)
 }
+
+fn data_cmd_object(cmd_name: &'static str, data: Vec<u8>) -> Result<Object, Error> {
+    let cmd = cmd_object(cmd_name)?;
+    let js_data = Uint8Array::from(&data[..]);
+    Reflect::set(&cmd, &"data".into(), &js_data).map_err(js_err("err setting data"))?;
+    Ok(cmd)
+}
+
+// sets the sequence number following `seq` on the play command and returns it
+fn set_next_seq(cmd: &Object, seq: &Cell<u32>) -> Result<u32, Error> {
+    let next = seq.get().wrapping_add(1);
+    Reflect::set(cmd, &"seq".into(), &next.into()).map_err(js_err("err setting seq"))?;
+    Ok(next)
+}
+
+fn read_cmd(data: &JsValue) -> Option<String> {
+    Reflect::get(data, &"cmd".into()).ok()?.as_string()
+}
+
+fn read_number(data: &JsValue, key: &str) -> Option<f64> {
+    Reflect::get(data, &key.into()).ok()?.as_f64()
+}
+
+fn read_imf_progress(data: &JsValue) -> Option<(Duration, Duration)> {
+    let position_ms = read_number(data, "positionMs")?;
+    let duration_ms = read_number(data, "durationMs")?;
+    if position_ms < 0.0 || duration_ms < 0.0 {
+        return None;
+    }
+    Some((
+        Duration::from_millis(position_ms as u64),
+        Duration::from_millis(duration_ms as u64),
+    ))
+}
+
+fn read_voice(data: &JsValue) -> Option<usize> {
+    let voice = read_number(data, "voice")? as usize;
+    if voice >= DIGI_VOICES {
+        return None;
+    }
+    Some(voice)
+}
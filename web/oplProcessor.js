@@ -1,66 +1,239 @@
+const POSITION_REPORT_BLOCKS = 32;
+const DIGI_VOICES = 4;
+
+// register queue shared with web::OPL, the layout must match src/web.rs
+const QUEUE_RECORDS = 4096;
+const QUEUE_HEADER_BYTES = 16;
+const QUEUE_RECORD_BYTES = 32;
+const QUEUE_HEAD = 0;
+const QUEUE_TAIL = 1;
+const OP_WRITE_REG = 1;
+const OP_WRITE_REG_AT = 2;
+
 class OPLProcessor extends AudioWorkletProcessor {
   constructor(options) {
     super();
-    this.imf_data_ptr = 0;
-    this.imf_data_len = 0;
+    this.imf_playing = false;
+    this.blocks_since_position = 0;
     this.adl_data_ptr = 0;
     this.adl_data_len = 0;
     this.adl_playing = false;
+    this.pc_playing = false;
+    this.digi_playing = new Array(DIGI_VOICES).fill(false);
+    // sequence numbers of the last play commands, echoed in the end messages
+    this.imf_seq = 0;
+    this.adl_seq = 0;
+    this.pc_seq = 0;
+    this.digi_seq = new Array(DIGI_VOICES).fill(0);
+    // context frame at which the sample clock of the generator started
+    this.frame_offset = null;
 
-    const { wasmBytes, mixerRate, imfClockRate, adlClockRate } = options.processorOptions;
+    const { wasmBytes, mixerRate, imfClockRate, adlClockRate, adlChannel, sfxChannelMode, regQueue } =
+      options.processorOptions;
+    // number of messages handled, queued writes wait for the messages posted before them
+    this.messages_received = 0;
+    this.queue_header = null;
+    if (regQueue) {
+      this.queue_header = new Int32Array(regQueue, 0, 2);
+      this.queue_words = new Int32Array(regQueue, QUEUE_HEADER_BYTES, (QUEUE_RECORDS * QUEUE_RECORD_BYTES) / 4);
+      this.queue_times = new Float64Array(regQueue, QUEUE_HEADER_BYTES, (QUEUE_RECORDS * QUEUE_RECORD_BYTES) / 8);
+    }
     const module = new WebAssembly.Module(wasmBytes);
     const instance = new WebAssembly.Instance(module, {});
     this.wasm = instance.exports;
 
-    this.generatorPtr = this.wasm.new_generator(mixerRate, imfClockRate, adlClockRate);
+    this.generatorPtr = this.wasm.new_generator(
+      mixerRate,
+      imfClockRate,
+      adlClockRate,
+      adlChannel,
+      sfxChannelMode,
+    );
 
     this.port.onmessage = (event) => {
-      if (event.data.cmd === "play_imf") {
-        if (this.imf_data_ptr) {
-          this.wasm.dealloc(this.imf_data_ptr, this.imf_data_len);
-        }
-
-        let bytes = event.data.data;
-        this.imf_data_len = bytes.length;
-        this.imf_data_ptr = this.wasm.alloc(this.imf_data_len);
-
-        let ptr_bytes = new Uint8Array(this.wasm.memory.buffer, this.imf_data_ptr, this.imf_data_len);
-        ptr_bytes.set(bytes);
-        this.wasm.play_imf(this.generatorPtr, this.imf_data_ptr, this.imf_data_len);
-      } else if (event.data.cmd === "play_adl") {
-        if (this.adl_data_ptr) {
-          this.wasm.dealloc(this.adl_data_ptr, this.adl_data_len);
-        }
-        let bytes = event.data.data;
-        this.adl_data_len = bytes.length;
-        this.adl_data_ptr = this.wasm.alloc(this.adl_data_len);
-        let ptr_bytes = new Uint8Array(this.wasm.memory.buffer, this.adl_data_ptr, this.adl_data_len);
-        ptr_bytes.set(bytes);
-        this.wasm.play_adl(this.generatorPtr, this.adl_data_ptr, this.adl_data_len);
-        this.adl_playing = true;
-      } else if (event.data.cmd === "write_reg") {
-        this.wasm.write_reg(event.data.reg, event.data.value);
-      } else if (event.data.cmd === "stop_imf") {
-        this.wasm.stop_imf();
+      if (!this.generatorPtr) {
+        return;
       }
+      this.drainRegQueue();
+      this.handleMessage(event);
+      this.messages_received = (this.messages_received + 1) | 0;
     };
   }
 
+  handleMessage(event) {
+    if (event.data.cmd === "play_imf") {
+      // the generator copies the track, the data can be freed right away
+      let bytes = event.data.data;
+      let len = bytes.length;
+      let ptr = this.wasm.alloc(len);
+
+      let ptr_bytes = new Uint8Array(this.wasm.memory.buffer, ptr, len);
+      ptr_bytes.set(bytes);
+      this.wasm.play_imf(this.generatorPtr, ptr, len, event.data.loopCount, event.data.fadeOutMs);
+      this.wasm.dealloc(ptr, len);
+      this.imf_playing = true;
+      this.imf_seq = event.data.seq;
+    } else if (event.data.cmd === "play_adl") {
+      if (this.adl_data_ptr) {
+        this.wasm.dealloc(this.adl_data_ptr, this.adl_data_len);
+      }
+      let bytes = event.data.data;
+      this.adl_data_len = bytes.length;
+      this.adl_data_ptr = this.wasm.alloc(this.adl_data_len);
+      let ptr_bytes = new Uint8Array(this.wasm.memory.buffer, this.adl_data_ptr, this.adl_data_len);
+      ptr_bytes.set(bytes);
+      // taken also if the sound is rejected, the end of the playing one is then reported with it
+      this.adl_seq = event.data.seq;
+      this.wasm.play_adl(this.generatorPtr, this.adl_data_ptr, this.adl_data_len);
+      // a rejected sound with nothing playing is reported as finished with the next block
+      this.adl_playing = true;
+    } else if (event.data.cmd === "stop_adl") {
+      this.wasm.stop_adl(this.generatorPtr);
+      this.adl_playing = false;
+    } else if (event.data.cmd === "play_pc") {
+      // the generator copies the sound, the data can be freed right away
+      let bytes = event.data.data;
+      let len = bytes.length;
+      let ptr = this.wasm.alloc(len);
+      let ptr_bytes = new Uint8Array(this.wasm.memory.buffer, ptr, len);
+      ptr_bytes.set(bytes);
+      this.pc_seq = event.data.seq;
+      if (this.wasm.play_pc(this.generatorPtr, ptr, len)) {
+        this.pc_playing = true;
+      }
+      this.wasm.dealloc(ptr, len);
+    } else if (event.data.cmd === "stop_pc") {
+      this.wasm.stop_pc(this.generatorPtr);
+      this.pc_playing = false;
+    } else if (event.data.cmd === "play_digi") {
+      // the generator copies the samples, the data can be freed right away
+      let bytes = event.data.data;
+      let len = bytes.length;
+      let ptr = this.wasm.alloc(len);
+      let ptr_bytes = new Uint8Array(this.wasm.memory.buffer, ptr, len);
+      ptr_bytes.set(bytes);
+      this.digi_seq[event.data.voice] = event.data.seq;
+      if (this.wasm.play_digi(this.generatorPtr, event.data.voice, ptr, len, event.data.rate)) {
+        this.digi_playing[event.data.voice] = true;
+      }
+      this.wasm.dealloc(ptr, len);
+    } else if (event.data.cmd === "stop_digi") {
+      this.wasm.stop_digi(this.generatorPtr, event.data.voice);
+      this.digi_playing[event.data.voice] = false;
+    } else if (event.data.cmd === "set_digi_volume") {
+      this.wasm.set_digi_volume(this.generatorPtr, event.data.voice, event.data.volume);
+    } else if (event.data.cmd === "seek_imf") {
+      this.wasm.seek_imf(this.generatorPtr, event.data.positionMs);
+    } else if (event.data.cmd === "set_imf_tempo") {
+      this.wasm.set_imf_tempo(this.generatorPtr, event.data.tempo);
+    } else if (event.data.cmd === "set_imf_transpose") {
+      this.wasm.set_imf_transpose(this.generatorPtr, event.data.semitones);
+    } else if (event.data.cmd === "pause_imf") {
+      this.wasm.pause_imf(this.generatorPtr);
+    } else if (event.data.cmd === "resume_imf") {
+      this.wasm.resume_imf(this.generatorPtr);
+    } else if (event.data.cmd === "set_music_volume") {
+      this.wasm.set_music_volume(this.generatorPtr, event.data.volume);
+    } else if (event.data.cmd === "set_sfx_volume") {
+      this.wasm.set_sfx_volume(this.generatorPtr, event.data.volume);
+    } else if (event.data.cmd === "set_master_volume") {
+      this.wasm.set_master_volume(this.generatorPtr, event.data.volume);
+    } else if (event.data.cmd === "write_reg_at") {
+      this.writeRegAt(event.data.reg, event.data.value, event.data.time);
+    } else if (event.data.cmd === "write_reg") {
+      this.wasm.write_reg(this.generatorPtr, event.data.reg, event.data.value);
+    } else if (event.data.cmd === "stop_imf") {
+      this.wasm.stop_imf(this.generatorPtr);
+    }
+  }
+
+  writeRegAt(reg, value, time) {
+    // the context time in seconds is mapped to the sample clock of the generator
+    const offset = this.frame_offset ?? currentFrame;
+    const frame = Math.max(0, Math.round(time * sampleRate) - offset);
+    this.wasm.queue_write(this.generatorPtr, frame, reg, value);
+  }
+
+  // applies the queued register writes whose preceding messages were handled
+  drainRegQueue() {
+    if (!this.queue_header) {
+      return;
+    }
+    let head = Atomics.load(this.queue_header, QUEUE_HEAD);
+    const tail = Atomics.load(this.queue_header, QUEUE_TAIL);
+    while (head !== tail) {
+      const record = head & (QUEUE_RECORDS - 1);
+      const word = (record * QUEUE_RECORD_BYTES) / 4;
+      if (((this.queue_words[word + 3] - this.messages_received) | 0) > 0) {
+        break;
+      }
+      const op = this.queue_words[word];
+      const reg = this.queue_words[word + 1];
+      const value = this.queue_words[word + 2];
+      if (op === OP_WRITE_REG) {
+        this.wasm.write_reg(this.generatorPtr, reg, value);
+      } else if (op === OP_WRITE_REG_AT) {
+        this.writeRegAt(reg, value, this.queue_times[(record * QUEUE_RECORD_BYTES) / 8 + 2]);
+      }
+      head = (head + 1) | 0;
+    }
+    Atomics.store(this.queue_header, QUEUE_HEAD, head);
+  }
+
   process(inputs, outputs) {
+    // the clock rates were rejected, the outputs stay silent
+    if (!this.generatorPtr) {
+      return true;
+    }
+    if (this.frame_offset === null) {
+      this.frame_offset = currentFrame;
+    }
+    this.drainRegQueue();
     const ptr = this.wasm.generate_block(this.generatorPtr);
     const bytes = new Float32Array(this.wasm.memory.buffer, ptr, 256);
 
+    // the generator renders stereo, further channels get the right one
     const output = outputs[0];
-    for (let i = 0; i < 128; i++) {
-      output[0][i] = bytes[i * 2];
-      output[1][i] = bytes[i * 2 + 1];
+    for (let channel = 0; channel < output.length; channel++) {
+      const side = Math.min(channel, 1);
+      for (let i = 0; i < 128; i++) {
+        output[channel][i] = bytes[i * 2 + side];
+      }
+    }
+
+    const imf_playing = this.wasm.is_imf_playing(this.generatorPtr);
+    if (this.imf_playing && !imf_playing) {
+      this.imf_playing = false;
+      this.blocks_since_position = 0;
+      this.port.postMessage({ cmd: "imf_finished", seq: this.imf_seq });
+    } else if (imf_playing && ++this.blocks_since_position >= POSITION_REPORT_BLOCKS) {
+      this.blocks_since_position = 0;
+      this.port.postMessage({
+        cmd: "imf_position",
+        seq: this.imf_seq,
+        positionMs: this.wasm.imf_position_ms(this.generatorPtr),
+        durationMs: this.wasm.imf_duration_ms(this.generatorPtr),
+      });
     }
 
     const adl_playing = this.wasm.is_adl_playing(this.generatorPtr);
     if (this.adl_playing && !adl_playing) {
       this.adl_playing = false;
       //notify on "falling flank" to the main thread
-      this.port.postMessage("adl_finished");
+      this.port.postMessage({ cmd: "adl_finished", seq: this.adl_seq });
+    }
+
+    const pc_playing = this.wasm.is_pc_playing(this.generatorPtr);
+    if (this.pc_playing && !pc_playing) {
+      this.pc_playing = false;
+      this.port.postMessage({ cmd: "pc_finished", seq: this.pc_seq });
+    }
+
+    for (let voice = 0; voice < DIGI_VOICES; voice++) {
+      if (this.digi_playing[voice] && !this.wasm.is_digi_playing(this.generatorPtr, voice)) {
+        this.digi_playing[voice] = false;
+        this.port.postMessage({ cmd: "digi_finished", voice: voice, seq: this.digi_seq[voice] });
+      }
     }
 
     return true; // keep processor alive